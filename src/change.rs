@@ -0,0 +1,113 @@
+use std::cmp;
+
+use crate::editorrows;
+use crate::motion;
+use crate::operator;
+use crate::selection;
+
+/* What an operator in a change applied to. */
+#[derive(Copy, Clone)]
+pub enum Target {
+    /* a motion key, and whether it followed `g` */
+    Motion(char, bool),
+    /* a text object key, and whether it was `i` rather than `a` */
+    Object(char, bool),
+    /* a doubled operator, as in `dd` */
+    Lines,
+    /* a visual selection, repeated over as many rows and columns from
+     * the cursor as it first covered */
+    Selection {
+        kind: selection::SelectionKind,
+        rows: usize,
+        columns: usize,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum InsertKind {
+    Insert,
+    Append,
+    OpenBelow,
+    OpenAbove,
+}
+
+#[derive(Copy, Clone)]
+pub enum Command {
+    Operator(operator::Operator, Target),
+    Insert(InsertKind),
+    /* `p` when set, `P` otherwise */
+    Paste(bool),
+}
+
+/* The last change made to the buffer, kept so that `.` can make it again. */
+#[derive(Clone)]
+pub struct Change {
+    pub command: Command,
+    pub count: Option<usize>,
+    pub register: Option<char>,
+    /* what was typed when the change went on into insert mode */
+    pub inserted: String,
+}
+
+impl Change {
+    pub fn new(command: Command, count: Option<usize>, register: Option<char>) -> Self {
+        Self {
+            command,
+            count,
+            register,
+            inserted: String::new(),
+        }
+    }
+}
+
+impl Target {
+    /* The size of a visual selection, for repeating it elsewhere. */
+    pub fn from_selection(
+        kind: selection::SelectionKind,
+        range: &motion::MotionRange,
+        block: &selection::Block,
+    ) -> Self {
+        let rows = range.end.0 - range.start.0;
+        let columns = match kind {
+            selection::SelectionKind::Charwise if rows == 0 => range.end.1 - range.start.1,
+            selection::SelectionKind::Charwise => range.end.1,
+            selection::SelectionKind::Linewise => 0,
+            selection::SelectionKind::Blockwise => block.right - block.left,
+        };
+        Target::Selection { kind, rows, columns }
+    }
+}
+
+/* The range, or block, a repeated selection of `rows` and `columns`
+ * covers when it starts at `from`. */
+pub fn reselect(
+    kind: selection::SelectionKind,
+    rows: usize,
+    columns: usize,
+    from: (usize, usize),
+    editor_rows: &editorrows::EditorRows,
+) -> (motion::MotionRange, selection::Block) {
+    let last_row = editor_rows.number_of_rows().saturating_sub(1);
+    let (y, x) = (cmp::min(from.0, last_row), from.1);
+    let end_row = cmp::min(y + rows, last_row);
+    let end = match kind {
+        selection::SelectionKind::Charwise if rows == 0 => {
+            (y, cmp::min(x + columns, editor_rows.row_len(y)))
+        }
+        _ => (end_row, cmp::min(columns, editor_rows.row_len(end_row))),
+    };
+    let left = editor_rows.get_editor_row(y).get_render_x(x);
+    (
+        motion::MotionRange {
+            start: (y, x),
+            end,
+            linewise: kind == selection::SelectionKind::Linewise,
+        },
+        selection::Block {
+            top: y,
+            bottom: end_row,
+            left,
+            right: left + cmp::max(columns, 1),
+        },
+    )
+}
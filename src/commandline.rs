@@ -0,0 +1,106 @@
+use crate::ex;
+
+/* The `:` line being typed, along with the lines run before it. Up and
+ * Down step through earlier lines that start with what was typed, and Tab
+ * cycles through completions of the word at the end. */
+#[derive(Default)]
+pub struct CommandLine {
+    pub input: String,
+    pub history: Vec<String>,
+    /* the history entry being shown, and what was typed before browsing */
+    browsing: Option<(usize, String)>,
+    /* the candidates, the one shown, and where the completed word starts */
+    completion: Option<(Vec<String>, usize, usize)>,
+}
+
+impl CommandLine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, input: String) {
+        self.input = input;
+        self.browsing = None;
+        self.completion = None;
+    }
+
+    pub fn push(&mut self, ch: char) {
+        self.input.push(ch);
+        self.browsing = None;
+        self.completion = None;
+    }
+
+    /* Returns `false` when there was nothing left to take away. */
+    pub fn pop(&mut self) -> bool {
+        self.browsing = None;
+        self.completion = None;
+        self.input.pop().is_some()
+    }
+
+    /* Step to an older (`older`) or newer history entry starting with the
+     * text typed before browsing began. */
+    pub fn browse(&mut self, older: bool) {
+        let (index, prefix) = self
+            .browsing
+            .take()
+            .unwrap_or((self.history.len(), self.input.clone()));
+        let matches = |line: &&String| line.starts_with(&prefix);
+        let found = if older {
+            self.history[..index].iter().rposition(|line| matches(&line))
+        } else {
+            self.history
+                .iter()
+                .skip(index + 1)
+                .position(|line| matches(&line))
+                .map(|offset| index + 1 + offset)
+        };
+        match found {
+            Some(found) => {
+                self.input = self.history[found].clone();
+                self.browsing = Some((found, prefix));
+            }
+            /* past the newest entry is the line as it was typed */
+            None if !older => {
+                self.input = prefix;
+            }
+            None => self.browsing = Some((index, prefix)),
+        }
+        self.completion = None;
+    }
+
+    /* Replace the word being typed with the next (or previous) completion. */
+    pub fn complete(&mut self, forward: bool) {
+        let (candidates, index, start) = match self.completion.take() {
+            Some((candidates, index, start)) => {
+                let len = candidates.len();
+                let index = if forward { (index + 1) % len } else { (index + len - 1) % len };
+                (candidates, index, start)
+            }
+            None => {
+                let (start, candidates) = ex::complete(&self.input);
+                if candidates.is_empty() {
+                    return;
+                }
+                let index = if forward { 0 } else { candidates.len() - 1 };
+                (candidates, index, start)
+            }
+        };
+        self.input.truncate(start);
+        self.input.push_str(&candidates[index]);
+        self.completion = Some((candidates, index, start));
+        self.browsing = None;
+    }
+
+    /* Take the typed line, remembering it unless it is empty. A line run
+     * again moves to the end of the history. */
+    pub fn finish(&mut self) -> String {
+        let input = std::mem::take(&mut self.input);
+        if !input.trim().is_empty() {
+            self.history.retain(|line| *line != input);
+            self.history.push(input.clone());
+        }
+        self.browsing = None;
+        self.completion = None;
+        input
+    }
+}
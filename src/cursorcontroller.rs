@@ -2,6 +2,7 @@ use crossterm::event::*;
 use std::{cmp};
 use std::cmp::Ordering;
 use crate::editorrows;
+use crate::motion;
 use crate::row;
 
 #[derive(Copy, Clone)]
@@ -29,58 +30,164 @@ impl CursorController {
         }
     }
 
-    pub fn move_cursor(&mut self, direction: char, editor_rows: &editorrows::EditorRows) {
+    /* Where `motion` would put the cursor, or `None` if it cannot move. */
+    pub fn motion_target(
+        &self,
+        motion: motion::Motion,
+        editor_rows: &editorrows::EditorRows,
+    ) -> Option<(usize, usize)> {
         let number_of_rows = editor_rows.number_of_rows();
-        match direction {
-            'k' => {
-                self.cursor_y = self.cursor_y.saturating_sub(1);
+        let from = (self.cursor_y, self.cursor_x);
+        let row_len = |y: usize| if y < number_of_rows { editor_rows.row_len(y) } else { 0 };
+        match motion {
+            motion::Motion::Up => {
+                (self.cursor_y > 0).then(|| (self.cursor_y - 1, self.cursor_x))
             }
-
-
-            'h' => {
-                if self.cursor_x != 0 {
-                    self.cursor_x -= 1;
-                } else if self.cursor_y > 0 {
-                    self.cursor_y -= 1;
-                    self.cursor_x = editor_rows.get_row(self.cursor_y).len();
+            motion::Motion::Down => {
+                (self.cursor_y + 1 < number_of_rows).then(|| (self.cursor_y + 1, self.cursor_x))
+            }
+            motion::Motion::Left => {
+                (self.cursor_x > 0).then(|| (self.cursor_y, self.cursor_x - 1))
+            }
+            motion::Motion::Right => {
+                (self.cursor_x < row_len(self.cursor_y)).then(|| (self.cursor_y, self.cursor_x + 1))
+            }
+            motion::Motion::LineStart => Some((self.cursor_y, 0)),
+            motion::Motion::FirstNonBlank => {
+                (self.cursor_y < number_of_rows)
+                    .then(|| (self.cursor_y, editor_rows.first_non_blank(self.cursor_y)))
+            }
+            motion::Motion::LineEnd => Some((self.cursor_y, row_len(self.cursor_y))),
+            motion::Motion::WordForward(bigword) => motion::word_forward(editor_rows, from, bigword),
+            motion::Motion::WordBackward(bigword) => motion::word_backward(editor_rows, from, bigword),
+            motion::Motion::WordEnd(bigword) => motion::word_end(editor_rows, from, bigword),
+            motion::Motion::WordEndBackward(bigword) => {
+                motion::word_end_backward(editor_rows, from, bigword)
+            }
+            motion::Motion::FirstLine => self.line_target(0, editor_rows),
+            motion::Motion::LastLine => self.line_target(number_of_rows.checked_sub(1)?, editor_rows),
+            motion::Motion::ParagraphForward => motion::paragraph_forward(editor_rows, from),
+            motion::Motion::ParagraphBackward => motion::paragraph_backward(editor_rows, from),
+            motion::Motion::SentenceForward => motion::sentence_forward(editor_rows, from),
+            motion::Motion::SentenceBackward => motion::sentence_backward(editor_rows, from),
+            motion::Motion::ScreenTop => self.line_target(self.row_offset, editor_rows),
+            motion::Motion::ScreenMiddle => {
+                let last = cmp::min(self.row_offset + self.screen_rows, number_of_rows);
+                self.line_target(self.row_offset + last.saturating_sub(self.row_offset + 1) / 2, editor_rows)
+            }
+            motion::Motion::ScreenBottom => {
+                let last = cmp::min(self.row_offset + self.screen_rows, number_of_rows);
+                self.line_target(last.saturating_sub(1), editor_rows)
+            }
+            motion::Motion::MatchPair => {
+                if self.cursor_y < number_of_rows {
+                    motion::match_pair(editor_rows, from)
+                } else {
+                    None
                 }
             }
+        }
+    }
 
-            'j' => {
-                if self.cursor_y < number_of_rows {
-                    self.cursor_y += 1;
+    /* `motion` repeated `count` times. A count on `gg`/`G` names a row
+     * instead. Stops early once the motion cannot go any further. */
+    pub fn motion_target_count(
+        &self,
+        motion: motion::Motion,
+        count: Option<usize>,
+        editor_rows: &editorrows::EditorRows,
+    ) -> Option<(usize, usize)> {
+        if let (motion::Motion::FirstLine | motion::Motion::LastLine, Some(count)) = (motion, count) {
+            let row = cmp::min(count, editor_rows.number_of_rows());
+            return self.line_target(row.saturating_sub(1), editor_rows);
+        }
+        let mut cursor = *self;
+        let mut target = None;
+        for _ in 0..count.unwrap_or(1) {
+            match cursor.motion_target(motion, editor_rows) {
+                Some((y, x)) => {
+                    cursor.cursor_y = y;
+                    cursor.cursor_x = x;
+                    target = Some((y, x));
                 }
+                None => break,
             }
+        }
+        target
+    }
 
-            'l' => {
-                if self.cursor_y < number_of_rows {
-                    match self.cursor_x.cmp(&editor_rows.get_row(self.cursor_y).len()) {
-                        Ordering::Less => self.cursor_x += 1,
-                        Ordering::Equal => {
-                            self.cursor_y += 1;
-                            self.cursor_x = 0
-                        }
+    /* Linewise jumps land on the first non-blank of the row. */
+    fn line_target(&self, y: usize, editor_rows: &editorrows::EditorRows) -> Option<(usize, usize)> {
+        (y < editor_rows.number_of_rows()).then(|| (y, editor_rows.first_non_blank(y)))
+    }
 
-                        _ => {}
-                    }
-                }
+    /* The range an operator applied with `motion` covers. */
+    pub fn motion_range(
+        &self,
+        motion: motion::Motion,
+        count: Option<usize>,
+        editor_rows: &editorrows::EditorRows,
+    ) -> Option<motion::MotionRange> {
+        let target = self.motion_target_count(motion, count, editor_rows)?;
+        let from = (self.cursor_y, self.cursor_x);
+        let (start, mut end) = if target < from { (target, from) } else { (from, target) };
+        if motion.is_linewise() {
+            let last_row = editor_rows.number_of_rows().saturating_sub(1);
+            return Some(motion::MotionRange {
+                start: (cmp::min(start.0, last_row), 0),
+                end: (cmp::min(end.0, last_row), 0),
+                linewise: true,
+            });
+        }
+        if motion.is_inclusive() {
+            end.1 = cmp::min(end.1 + 1, editor_rows.row_len(end.0));
+        } else if end.1 == 0 && end.0 > start.0 {
+            /* an exclusive motion that ends at the start of a row stops at
+             * the end of the previous one instead (`dw` on a row's last word),
+             * and becomes linewise if it also started before any text (`d}`) */
+            if start.1 <= editor_rows.first_non_blank(start.0)
+                && !matches!(motion, motion::Motion::WordForward(_))
+            {
+                return Some(motion::MotionRange {
+                    start: (start.0, 0),
+                    end: (end.0 - 1, 0),
+                    linewise: true,
+                });
             }
+            end = (end.0 - 1, editor_rows.row_len(end.0 - 1));
+        }
+        Some(motion::MotionRange {
+            start,
+            end,
+            linewise: false,
+        })
+    }
 
-            'e' => {
-                if self.cursor_y < number_of_rows {
-                    self.cursor_x = editor_rows.get_row(self.cursor_y).len();
-                }
+    pub fn move_cursor(
+        &mut self,
+        motion: motion::Motion,
+        count: Option<usize>,
+        editor_rows: &editorrows::EditorRows,
+    ) -> bool {
+        match self.motion_target_count(motion, count, editor_rows) {
+            Some((y, x)) => {
+                self.cursor_y = y;
+                self.cursor_x = x;
+                self.clamp(editor_rows);
+                true
             }
-             _ => unimplemented!(),
-         }
+            None => false,
+        }
+    }
 
-        let row_len = if self.cursor_y < number_of_rows {
-            editor_rows.get_row(self.cursor_y).len()
+    pub fn clamp(&mut self, editor_rows: &editorrows::EditorRows) {
+        self.cursor_y = cmp::min(self.cursor_y, editor_rows.number_of_rows());
+        let row_len = if self.cursor_y < editor_rows.number_of_rows() {
+            editor_rows.row_len(self.cursor_y)
         } else {
             0
         };
         self.cursor_x = cmp::min(self.cursor_x, row_len);
-
     }
 
     pub fn move_cursor_arrows(&mut self, direction: KeyCode, editor_rows: &editorrows::EditorRows) {
@@ -94,49 +201,37 @@ impl CursorController {
                     self.cursor_x -= 1;
                 } else if self.cursor_y > 0 {
                     self.cursor_y -= 1;
-                    self.cursor_x = editor_rows.get_row(self.cursor_y).len();
+                    self.cursor_x = editor_rows.row_len(self.cursor_y);
                 }
             }
-            KeyCode::Down => {
-                if self.cursor_y < number_of_rows{
-                    self.cursor_y += 1;
-                }
+            KeyCode::Down if self.cursor_y < number_of_rows => {
+                self.cursor_y += 1;
             }
-            KeyCode::Right => {
-                if self.cursor_y < number_of_rows {
-                    match self.cursor_x.cmp(&editor_rows.get_row(self.cursor_y).len()) {
-                        Ordering::Less => self.cursor_x += 1,
-                        Ordering::Equal => {
-                            self.cursor_y += 1;
-                            self.cursor_x = 0
-                        }
-
-                        _ => {}
+            KeyCode::Right if self.cursor_y < number_of_rows => {
+                match self.cursor_x.cmp(&editor_rows.row_len(self.cursor_y)) {
+                    Ordering::Less => self.cursor_x += 1,
+                    Ordering::Equal => {
+                        self.cursor_y += 1;
+                        self.cursor_x = 0
                     }
+
+                    _ => {}
                 }
             }
 
-            KeyCode::End => {
-                if self.cursor_y < number_of_rows {
-                    self.cursor_x = editor_rows.get_row(self.cursor_y).len();
-                }   
+            KeyCode::End if self.cursor_y < number_of_rows => {
+                self.cursor_x = editor_rows.row_len(self.cursor_y);
             }
             KeyCode::Home => self.cursor_x = 0,
-            _ => unimplemented!(),
+            _ => {}
         }
-
-        let row_len = if self.cursor_y < number_of_rows {
-            editor_rows.get_row(self.cursor_y).len()
-        } else {
-            0
-        };
-        self.cursor_x = cmp::min(self.cursor_x, row_len);
+        self.clamp(editor_rows);
     }
 
     pub fn scroll(&mut self, editor_rows: &editorrows::EditorRows) {
         self.render_x = 0;
         if self.cursor_y < editor_rows.number_of_rows() {
-            self.render_x = self.get_render_x(editor_rows.get_editor_row(self.cursor_y))
+            self.render_x = self.get_render_x(&editor_rows.get_editor_row(self.cursor_y))
         }
         self.row_offset = cmp::min(self.row_offset, self.cursor_y);
         if self.cursor_y >= self.row_offset + self.screen_rows {
@@ -149,15 +244,7 @@ impl CursorController {
     }
 
     pub fn get_render_x(&self, row: &row::Row) -> usize {
-        row.row_content[..self.cursor_x]
-            .chars()
-            .fold(0, |render_x, c| {
-                if c == '\t' {
-                    render_x + (4 - 1) - (render_x % 4) + 1
-                } else {
-                    render_x + 1
-                }
-            })
+        row.get_render_x(self.cursor_x)
     }
 
 }
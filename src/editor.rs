@@ -1,8 +1,23 @@
 use crossterm::event::*;
 use crossterm::event;
 use std::cmp;
+use std::path::{Path, PathBuf};
 
+use crate::change;
+use crate::commandline;
+use crate::editorrows;
+use crate::ex;
+use crate::motion;
+use crate::operator;
 use crate::reader;
+use crate::register;
+use crate::search;
+use crate::searchdirection;
+use crate::selection;
+use crate::session;
+use crate::substitute;
+use crate::textobject;
+use crate::theme;
 use crate::output;
 use crate::prompt;
 
@@ -11,6 +26,11 @@ const QUIT_TIMES: u8 = 1;
 pub enum MODE {
    Normal,
    Insert,
+   OperatorPending,
+   Visual,
+   VisualLine,
+   VisualBlock,
+   Command,
 }
 
 pub struct Editor {
@@ -18,20 +38,198 @@ pub struct Editor {
     reader: reader::Reader,
     output: output::Output,
     quit_times: u8,
+    pending_g: bool,
+    count: Option<usize>,
+    pending_operator: Option<(operator::Operator, Option<usize>)>,
+    /* `i` or `a` typed after an operator, waiting for a text object */
+    pending_object: Option<bool>,
+    insert_count: usize,
+    inserted: String,
+    /* rows and display column that text typed after a block `I`, `A` or
+     * `c` is copied to when insert mode ends */
+    block_insert: Option<(Vec<usize>, usize)>,
+    registers: register::Registers,
+    /* `q` or `@` waiting for a register name, with the count for `@` */
+    pending_macro: Option<(char, Option<usize>)>,
+    last_macro: Option<char>,
+    /* register named with `"x` for the next command, and whether a `"`
+     * is waiting for the name */
+    register: Option<char>,
+    pending_register: bool,
+    /* what `.` repeats, and a change that is still taking insert-mode
+     * text before it can become that */
+    last_change: Option<change::Change>,
+    pending_change: Option<change::Change>,
+    command_line: commandline::CommandLine,
+    /* rows the last visual selection covered, for `'<` and `'>` */
+    visual_marks: Option<(usize, usize)>,
+    /* pattern and replacement of the last `:s`, for a bare `:s` to reuse */
+    last_substitute: Option<(String, String)>,
+    /* `/` or `?` waiting for the prompt to read a pattern */
+    pending_search: Option<searchdirection::SearchDirection>,
+    /* where registers are kept between sessions */
+    session_file: Option<PathBuf>,
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Editor {
     pub fn new() -> Self {
-        Self { 
-            reader: reader::Reader,
-            output: output::Output::new(),
+        Self::with_output(output::Output::new(), session::session_file())
+    }
+
+    /* An editor drawing to `output`, keeping its registers in
+     * `session_file` if there is one. */
+    fn with_output(output: output::Output, session_file: Option<PathBuf>) -> Self {
+        Self {
+            reader: reader::Reader::new(),
+            output,
             quit_times: QUIT_TIMES,
             mode: MODE::Normal,
+            pending_g: false,
+            count: None,
+            pending_operator: None,
+            pending_object: None,
+            insert_count: 1,
+            inserted: String::new(),
+            block_insert: None,
+            registers: session_file.as_deref().map_or_else(register::Registers::new, session::load),
+            session_file,
+            pending_macro: None,
+            last_macro: None,
+            register: None,
+            pending_register: false,
+            last_change: None,
+            pending_change: None,
+            command_line: commandline::CommandLine::new(),
+            visual_marks: None,
+            last_substitute: None,
+            pending_search: None,
+        }
+    }
+
+    fn enter_insert(&mut self, count: Option<usize>) {
+        self.mode = MODE::Insert;
+        self.insert_count = count.unwrap_or(1);
+        self.inserted.clear();
+        self.output.editor_rows.history.begin_group();
+        self.output.status_message.set_message("INSERT".into());
+    }
+
+    fn enter_normal(&mut self) {
+        self.mode = MODE::Normal;
+        self.pending_operator = None;
+        self.pending_object = None;
+        self.count = None;
+        self.register = None;
+        self.pending_register = false;
+        self.pending_macro = None;
+        if let Some(selection) = self.output.selection.take() {
+            let cursor_controller = &self.output.cursor_controller;
+            let cursor = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+            let block = selection.block(cursor, &self.output.editor_rows);
+            self.visual_marks = Some((block.top, block.bottom));
+        }
+    }
+
+    /* Start a visual selection of `kind`, or switch an active one over to
+     * it while keeping the anchor. */
+    fn enter_visual(&mut self, kind: selection::SelectionKind) {
+        let cursor_controller = &self.output.cursor_controller;
+        let cursor = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+        let anchor = self.output.selection.map_or(cursor, |selection| selection.anchor);
+        self.output.selection = Some(selection::Selection::new(anchor, kind));
+        let (mode, message) = match kind {
+            selection::SelectionKind::Charwise => (MODE::Visual, "VISUAL"),
+            selection::SelectionKind::Linewise => (MODE::VisualLine, "VISUAL LINE"),
+            selection::SelectionKind::Blockwise => (MODE::VisualBlock, "VISUAL BLOCK"),
+        };
+        self.mode = mode;
+        self.output.status_message.set_message(message.into());
+    }
+
+    /* `v`, `V` and `Ctrl-V` leave visual mode when it is already that kind. */
+    fn toggle_visual(&mut self, kind: selection::SelectionKind) {
+        if self.output.selection.is_some_and(|selection| selection.kind == kind) {
+            self.enter_normal();
+            self.output.status_message.set_message("NORMAL".into());
+        } else {
+            self.enter_visual(kind);
+        }
+    }
+
+    /* Count digits, `"x` register names and the `g` prefix, shared by
+     * normal and visual keys. */
+    fn take_prefix(&mut self, ch: char, pending_g: bool) -> bool {
+        if std::mem::take(&mut self.pending_register) {
+            if register::Registers::is_valid(ch) {
+                self.register = Some(ch);
+            }
+            return true;
+        }
+        if ch == '"' && self.pending_operator.is_none() && self.pending_object.is_none() {
+            self.pending_register = true;
+            return true;
+        }
+        if ch.is_ascii_digit() && (ch != '0' || self.count.is_some()) {
+            let digit = ch.to_digit(10).unwrap() as usize;
+            self.count = Some(self.count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+            return true;
+        }
+        if ch == 'g' && !pending_g && self.pending_object.is_none() {
+            self.pending_g = true;
+            return true;
+        }
+        false
+    }
+
+    /* What has been typed of the current normal-mode command so far. */
+    fn update_pending_command(&mut self) {
+        let mut pending = String::new();
+        if let Some(name) = self.register {
+            pending.push('"');
+            pending.push(name);
+        }
+        if self.pending_register {
+            pending.push('"');
+        }
+        if let Some((operator, count)) = self.pending_operator {
+            if let Some(count) = count {
+                pending.push_str(&count.to_string());
+            }
+            pending.push(operator.to_char());
+        }
+        if let Some(inner) = self.pending_object {
+            pending.push(if inner { 'i' } else { 'a' });
+        }
+        if let Some(count) = self.count {
+            pending.push_str(&count.to_string());
         }
+        if self.pending_g {
+            pending.push('g');
+        }
+        if let Some((command, _)) = self.pending_macro {
+            pending.push(command);
+        }
+        self.output.recording = self.reader.recording.as_ref().map(|(name, _)| *name);
+        self.output.pending_command = pending;
     }
 
     pub fn process_keypress(&mut self) -> crossterm::Result<bool> {
-        match self.reader.read_key()? {
+        let output = &mut self.output;
+        let key = self.reader.read_key(&mut |columns, rows| output.resize(columns, rows))?;
+        /* a search count only goes with the message of the search */
+        self.output.search_count = None;
+        if matches!(self.mode, MODE::Command) {
+            let running = self.process_command(key)?;
+            self.update_pending_command();
+            return Ok(running);
+        }
+        match key {
             KeyEvent {
                 code: KeyCode::Char('w'),
                 modifiers: event::KeyModifiers::CONTROL,
@@ -44,31 +242,14 @@ impl Editor {
                 self.quit_times -= 1;
                 return Ok(true);
                 }
-                return Ok(false);
-            }
-
-            KeyEvent {
-                code: KeyCode::Char(val @ ('h' | 'j' | 'k' | 'l')),
-                modifiers: KeyModifiers::NONE,
-            } => { 
-                    if matches!(self.mode, MODE::Normal) {
-                        self.output.move_cursor(val);
-                    }
-                    else {
-                        self.output.insert_char(val);
-                    }
+                return Ok(self.quit());
             }
 
             KeyEvent {
-                code: KeyCode::Char(val @ 'e'),
-                modifiers: KeyModifiers::CONTROL,
-            } => self.output.move_cursor(val),
-
-            KeyEvent {
-                code: direction @ 
-                (KeyCode::Up 
-                | KeyCode::Down 
-                | KeyCode::Left 
+                code: direction @
+                (KeyCode::Up
+                | KeyCode::Down
+                | KeyCode::Left
                 | KeyCode::Right
                 | KeyCode::Home
                 | KeyCode::End),
@@ -80,7 +261,7 @@ impl Editor {
                 modifiers: KeyModifiers::NONE,
             } => {
                 if matches!(val, KeyCode::PageUp) {
-                    self.output.cursor_controller.cursor_y = 
+                    self.output.cursor_controller.cursor_y =
                         self.output.cursor_controller.row_offset
                 } else {
                     self.output.cursor_controller.cursor_y = cmp::min(
@@ -91,116 +272,1405 @@ impl Editor {
 
                 (0..self.output.win_size.1).for_each(|_| {
                     self.output.move_cursor_arrows(if matches!(val, KeyCode::PageUp) {
-                        KeyCode::Up    
+                        KeyCode::Up
                 } else {
                     KeyCode::Down
                 });
             })
         }
-            
+
             KeyEvent {
                 code: KeyCode::Char('s'),
                 modifiers: KeyModifiers::CONTROL,
             } =>  {
-                if matches!(self.output.editor_rows.filename, None) {
-                    let prompt = prompt!(&mut self.output, "Save as : {} (ESC to cancel)")
+                if self.output.editor_rows.filename.is_none() {
+                    let prompt = prompt!(&mut self.output, &mut self.reader, "Save as : {} (ESC to cancel)")
                         .map(|it| it.into());
-                    if let None = prompt {
+                    if prompt.is_none() {
                         self.output
                             .status_message
                             .set_message("Save Aborted".into());
                         return Ok(true);
                     }
-                    self.output.editor_rows.filename = prompt
+                    self.output.editor_rows.filename = prompt;
+                    self.output.detect_file_type();
                 }
                 self.output.editor_rows.save().map(|len| {
                     self.output
                         .status_message
                         .set_message(format!("{} bytes written to disk", len));
-                    self.output.dirty = 0
+                    self.output.dirty = 0;
+                    self.output.editor_rows.history.mark_saved();
                 })?;
+                self.write_undo_file();
             }
 
-            KeyEvent { 
-                code: KeyCode::Char('f'), 
-                modifiers: KeyModifiers::CONTROL, 
+            KeyEvent {
+                code: KeyCode::Char('f'),
+                modifiers: KeyModifiers::CONTROL,
             } => {
-                self.output.find()?;
+                self.output
+                    .find(&mut self.reader, searchdirection::SearchDirection::Forward)?;
             }
 
-            KeyEvent { 
-                code: key @ (KeyCode::Backspace | KeyCode::Delete), 
+            KeyEvent {
+                code: key @ (KeyCode::Backspace | KeyCode::Delete),
                 modifiers: KeyModifiers::NONE,
-            } => {
+            } if self.output.selection.is_none() => {
                 if matches!(key, KeyCode::Delete) {
                     self.output.move_cursor_arrows(KeyCode::Right)
+                } else if matches!(self.mode, MODE::Insert) {
+                    self.inserted.pop();
                 }
                 self.output.delete_char();
-            }   
+            }
 
+            _ => match self.mode {
+                MODE::Insert => self.process_insert(key),
+                MODE::Normal | MODE::OperatorPending => self.process_normal(key),
+                MODE::Visual | MODE::VisualLine | MODE::VisualBlock => self.process_visual(key),
+                MODE::Command => {}
+            },
+        }
+        if let Some(direction) = self.pending_search.take() {
+            if !self.output.find(&mut self.reader, direction)? {
+                self.abort_macro();
+            }
+        }
+        self.update_pending_command();
+        Ok(true)
+    }
+
+    fn process_insert(&mut self, key: KeyEvent) {
+        match key {
             KeyEvent {
                 code: KeyCode::Enter,
                 modifiers: KeyModifiers::NONE,
             } => {
-                    if matches!(self.mode, MODE::Insert) {
-                        self.output.inser_newline();
+                    self.output.inser_newline();
+                    self.inserted.push('\n');
+                }
+
+            KeyEvent {
+                code: code @ (KeyCode::Char(..) | KeyCode::Tab),
+                modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+            } => {
+                    let text = match code {
+                        KeyCode::Tab => self.output.tab_text(),
+                        KeyCode::Char(ch) => ch.to_string(),
+                        _ => unreachable!(),
+                    };
+                    for ch in text.chars() {
+                        self.output.insert_char(ch);
+                        self.inserted.push(ch);
                     }
                 }
 
-            KeyEvent { 
-                code: KeyCode::Char('i'), 
-                modifiers: KeyModifiers::NONE, 
-            } => {  
-                    if matches!(self.mode, MODE::Normal) {
-                        self.mode = MODE::Insert;
-                        self.output.status_message.set_message(format!("INSERT"));
+            KeyEvent {
+                code: KeyCode::Esc,
+                modifiers: KeyModifiers::NONE,
+            } => self.finish_insert(),
+
+            _ => {}
+        }
+    }
+
+    /* Normal and operator-pending keys: `[operator] motion`, a doubled
+     * operator for whole rows, or one of the plain normal-mode commands. */
+    fn process_normal(&mut self, key: KeyEvent) {
+        let pending_g = std::mem::take(&mut self.pending_g);
+        let ch = match key {
+            KeyEvent {
+                code: KeyCode::Char(ch),
+                modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+            } => ch,
+
+            KeyEvent {
+                code: KeyCode::Char('e'),
+                modifiers: KeyModifiers::CONTROL,
+            } => {
+                self.output.move_cursor(motion::Motion::LineEnd, None);
+                return;
+            }
+
+            KeyEvent {
+                code: KeyCode::Char('r'),
+                modifiers: KeyModifiers::CONTROL,
+            } => {
+                self.output.history_step(
+                    editorrows::EditorRows::redo,
+                    "Already at newest change",
+                );
+                return;
+            }
+
+            KeyEvent {
+                code: KeyCode::Char('v'),
+                modifiers: KeyModifiers::CONTROL,
+            } if self.pending_operator.is_none() => {
+                self.enter_visual(selection::SelectionKind::Blockwise);
+                return;
+            }
+
+            KeyEvent {
+                code: KeyCode::Esc, ..
+            } => {
+                self.enter_normal();
+                return;
+            }
+
+            _ => return,
+        };
+
+        if let Some((command, count)) = self.pending_macro.take() {
+            if command == 'q' {
+                self.start_recording(ch);
+            } else {
+                self.run_macro(ch, count);
+            }
+            return;
+        }
+        if self.take_prefix(ch, pending_g) {
+            return;
+        }
+        if matches!(ch, 'i' | 'a') && self.pending_operator.is_some() && self.pending_object.is_none() {
+            self.pending_object = Some(ch == 'i');
+            return;
+        }
+        let count = self.count.take();
+        let register = self.register.take();
+
+        if let Some((operator, operator_count)) = self.pending_operator {
+            let pending_object = self.pending_object;
+            self.enter_normal();
+            /* `2d3w` deletes six words */
+            let count = match (operator_count, count) {
+                (None, None) => None,
+                (a, b) => Some(a.unwrap_or(1) * b.unwrap_or(1)),
+            };
+            let target = match pending_object {
+                Some(inner) => change::Target::Object(ch, inner),
+                None if ch == operator.to_char() => change::Target::Lines,
+                None => change::Target::Motion(ch, pending_g),
+            };
+            self.run_operator(operator, target, count, register);
+            return;
+        }
+
+        if let Some(motion) = motion::Motion::from_char(ch, pending_g) {
+            if !self.output.move_cursor(motion, count) {
+                self.abort_macro();
+            }
+            return;
+        }
+        if let Some(operator) = operator::Operator::from_char(ch) {
+            self.pending_operator = Some((operator, count));
+            self.register = register;
+            self.mode = MODE::OperatorPending;
+            return;
+        }
+
+        match (pending_g, ch) {
+            (true, '-') => self.output.history_step(
+                editorrows::EditorRows::earlier,
+                "Already at oldest change",
+            ),
+            (true, '+') => self.output.history_step(
+                editorrows::EditorRows::later,
+                "Already at newest change",
+            ),
+            (_, 'i') => self.start_insert(change::InsertKind::Insert, count),
+            (_, 'a') => self.start_insert(change::InsertKind::Append, count),
+            (_, 'o') => self.start_insert(change::InsertKind::OpenBelow, count),
+            (_, 'O') => self.start_insert(change::InsertKind::OpenAbove, count),
+            (_, 'x') => self.run_operator(
+                operator::Operator::Delete,
+                change::Target::Motion('l', false),
+                count,
+                register,
+            ),
+            (_, '.') => self.repeat_change(count),
+            (_, '/' | '?' | 'n' | 'N' | '*' | '#') => self.search(ch, count),
+            (_, 'p') => self.paste(register, true, count),
+            (_, 'P') => self.paste(register, false, count),
+            (_, 'q') if self.reader.recording.is_some() => self.stop_recording(),
+            (_, 'q' | '@') => self.pending_macro = Some((ch, count)),
+            (_, 'u') => self.output.history_step(
+                editorrows::EditorRows::undo,
+                "Already at oldest change",
+            ),
+            (_, 'v') => self.enter_visual(selection::SelectionKind::Charwise),
+            (_, 'V') => self.enter_visual(selection::SelectionKind::Linewise),
+            /* `3:` starts the line with a range over three rows */
+            (_, ':') => self.open_command_line(match count {
+                Some(1) => String::from("."),
+                Some(count) => format!(".,.+{}", count - 1),
+                None => String::new(),
+            }),
+            _ => {}
+        }
+    }
+
+    /* Visual keys: motions move the cursor end of the selection, text
+     * objects reselect around the cursor and operators act on the
+     * selection and go back to normal mode. */
+    fn process_visual(&mut self, key: KeyEvent) {
+        let pending_g = std::mem::take(&mut self.pending_g);
+        let ch = match key {
+            KeyEvent {
+                code: KeyCode::Char(ch),
+                modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+            } => ch,
+
+            KeyEvent {
+                code: KeyCode::Char('v'),
+                modifiers: KeyModifiers::CONTROL,
+            } => {
+                self.toggle_visual(selection::SelectionKind::Blockwise);
+                return;
+            }
+
+            KeyEvent {
+                code: KeyCode::Delete,
+                modifiers: KeyModifiers::NONE,
+            } => 'x',
+
+            KeyEvent {
+                code: KeyCode::Esc, ..
+            } => {
+                self.enter_normal();
+                self.output.status_message.set_message("NORMAL".into());
+                return;
+            }
+
+            _ => return,
+        };
+
+        if self.take_prefix(ch, pending_g) {
+            return;
+        }
+        if matches!(ch, 'i' | 'a') && self.pending_object.is_none() {
+            self.pending_object = Some(ch == 'i');
+            return;
+        }
+        let count = self.count.take();
+        let register = self.register.take();
+
+        if let Some(inner) = self.pending_object.take() {
+            self.select_object(ch, inner, count);
+            return;
+        }
+        if let Some(motion) = motion::Motion::from_char(ch, pending_g) {
+            if !self.output.move_cursor(motion, count) {
+                self.abort_macro();
+            }
+            return;
+        }
+        let block = self.output.selection.is_some_and(|selection| {
+            selection.kind == selection::SelectionKind::Blockwise
+        });
+        match ch {
+            'v' => self.toggle_visual(selection::SelectionKind::Charwise),
+            'V' => self.toggle_visual(selection::SelectionKind::Linewise),
+            'o' => {
+                let cursor_controller = &mut self.output.cursor_controller;
+                if let Some(selection) = &mut self.output.selection {
+                    let cursor = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+                    (cursor_controller.cursor_y, cursor_controller.cursor_x) = selection.anchor;
+                    selection.anchor = cursor;
+                }
+            }
+            'I' | 'A' if block => self.insert_block(ch == 'A'),
+            ':' => {
+                self.enter_normal();
+                self.open_command_line(String::from("'<,'>"));
+            }
+            '/' | '?' | 'n' | 'N' | '*' | '#' => self.search(ch, count),
+            'x' => self.apply_visual(operator::Operator::Delete, count, register),
+            's' => self.apply_visual(operator::Operator::Change, count, register),
+            _ => {
+                if let Some(operator) = operator::Operator::from_char(ch) {
+                    self.apply_visual(operator, count, register);
+                }
+            }
+        }
+    }
+
+    /* `v{i,a}{object}` replaces the selection with the text object, going
+     * linewise for objects that cover whole rows. */
+    fn select_object(&mut self, ch: char, inner: bool, count: Option<usize>) {
+        let cursor_controller = &self.output.cursor_controller;
+        let range = match textobject::TextObject::from_char(ch).and_then(|object| {
+            object.range(
+                &self.output.editor_rows,
+                (cursor_controller.cursor_y, cursor_controller.cursor_x),
+                inner,
+                count,
+            )
+        }) {
+            Some(range) => range,
+            None => return self.abort_macro(),
+        };
+        let (anchor, cursor) = if range.linewise {
+            self.enter_visual(selection::SelectionKind::Linewise);
+            ((range.start.0, 0), (range.end.0, 0))
+        } else {
+            let end = self.output.editor_rows.char_index(range.end.0, range.end.1);
+            (range.start, self.output.editor_rows.position(end - 1))
+        };
+        if let Some(selection) = &mut self.output.selection {
+            selection.anchor = anchor;
+        }
+        (self.output.cursor_controller.cursor_y, self.output.cursor_controller.cursor_x) = cursor;
+    }
+
+    fn apply_visual(
+        &mut self,
+        operator: operator::Operator,
+        count: Option<usize>,
+        register: Option<char>,
+    ) {
+        let selection = match self.output.selection {
+            Some(selection) => selection,
+            None => return,
+        };
+        let cursor_controller = &self.output.cursor_controller;
+        let cursor = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+        let range = selection.range(cursor, &self.output.editor_rows);
+        let block = selection.block(cursor, &self.output.editor_rows);
+        self.enter_normal();
+        self.apply_selection(operator, selection.kind, range, block, count, register);
+        let target = change::Target::from_selection(selection.kind, &range, &block);
+        self.record_change(change::Change::new(
+            change::Command::Operator(operator, target),
+            count,
+            register,
+        ));
+    }
+
+    /* Apply `operator` to what a visual selection of `kind` covers: `range`
+     * when it is charwise or linewise, `block` when it is a block. */
+    fn apply_selection(
+        &mut self,
+        operator: operator::Operator,
+        kind: selection::SelectionKind,
+        range: motion::MotionRange,
+        block: selection::Block,
+        count: Option<usize>,
+        register: Option<char>,
+    ) {
+        if matches!(operator, operator::Operator::ShiftRight | operator::Operator::ShiftLeft) {
+            /* `3>` shifts the selected rows three times */
+            for _ in 0..count.unwrap_or(1) {
+                self.apply_operator(operator, range, register);
+            }
+        } else if kind != selection::SelectionKind::Blockwise {
+            self.apply_operator(operator, range, register);
+        } else {
+            if operator == operator::Operator::Change {
+                /* `c` on a block types into every row the block had text on */
+                let rows = (block.top + 1..=block.bottom)
+                    .filter(|&y| block.span(&self.output.editor_rows.get_editor_row(y)).is_some())
+                    .collect();
+                self.enter_insert(None);
+                self.block_insert = Some((rows, block.left));
+            }
+            if let Some(texts) = self.output.apply_block(operator, block) {
+                let stored = register::Register::new(texts.join("\n"), register::RegisterKind::Blockwise);
+                if operator == operator::Operator::Yank {
+                    if texts.len() > 2 {
+                        self.output
+                            .status_message
+                            .set_message(format!("block of {} lines yanked", texts.len()));
                     }
-                    else {
-                        self.output.insert_char('i');
+                    self.registers.yank(register, stored);
+                } else {
+                    self.registers.delete(register, stored);
+                }
+            }
+        }
+    }
+
+    /* Block `I` and `A`: type on the block's first row and copy the text to
+     * the rows below when insert mode ends. `A` pads short rows out to the
+     * block, `I` leaves out rows that end before it. */
+    fn insert_block(&mut self, append: bool) {
+        let selection = match self.output.selection {
+            Some(selection) => selection,
+            None => return,
+        };
+        let cursor_controller = &self.output.cursor_controller;
+        let cursor = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+        let editor_rows = &self.output.editor_rows;
+        if editor_rows.number_of_rows() == 0 {
+            return;
+        }
+        let block = selection.block(cursor, editor_rows);
+        let column = if append { block.right } else { block.left };
+        let rows: Vec<usize> = (block.top + 1..=block.bottom)
+            .filter(|&y| append || block.span(&editor_rows.get_editor_row(y)).is_some())
+            .collect();
+
+        self.enter_normal();
+        self.enter_insert(None);
+        if append {
+            self.output.insert_at_column(block.top, column, "");
+        }
+        self.output.cursor_controller.cursor_y = block.top;
+        self.output.cursor_controller.cursor_x = self
+            .output
+            .editor_rows
+            .get_editor_row(block.top)
+            .get_row_content_x(column);
+        self.block_insert = Some((rows, column));
+    }
+
+    fn operator_range(
+        &self,
+        operator: operator::Operator,
+        ch: char,
+        count: Option<usize>,
+        pending_g: bool,
+    ) -> Option<motion::MotionRange> {
+        let motion = motion::Motion::from_char(ch, pending_g)?;
+        let cursor_controller = &self.output.cursor_controller;
+        let from = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+        /* `cw` on a non-blank only changes to the end of the word, like `ce` */
+        if let (operator::Operator::Change, motion::Motion::WordForward(bigword)) = (operator, motion) {
+            if let Some(mut end) = motion::current_word_end(&self.output.editor_rows, from, bigword) {
+                for _ in 1..count.unwrap_or(1) {
+                    match motion::word_end(&self.output.editor_rows, end, bigword) {
+                        Some(next) => end = next,
+                        None => break,
                     }
                 }
+                return Some(motion::MotionRange {
+                    start: from,
+                    end: (end.0, end.1 + 1),
+                    linewise: false,
+                });
+            }
+        }
+        cursor_controller.motion_range(motion, count, &self.output.editor_rows)
+    }
 
-            KeyEvent { 
-                code: KeyCode::Char('a'), 
-                modifiers: KeyModifiers::NONE, 
-            } => {
-                    if matches!(self.mode, MODE::Normal) {
-                        self.mode = MODE::Insert;
-                        self.output.status_message.set_message(format!("INSERT"));
+    fn apply_operator(
+        &mut self,
+        operator: operator::Operator,
+        range: motion::MotionRange,
+        register: Option<char>,
+    ) {
+        if operator == operator::Operator::Change {
+            self.enter_insert(None);
+        }
+        let text = match self.output.apply_operator(operator, range) {
+            Some(text) => text,
+            None => return,
+        };
+        let lines = text.matches('\n').count();
+        let kind = if range.linewise {
+            register::RegisterKind::Linewise
+        } else {
+            register::RegisterKind::Charwise
+        };
+        if operator == operator::Operator::Yank {
+            if lines > 2 {
+                self.output
+                    .status_message
+                    .set_message(format!("{} lines yanked", lines));
+            }
+            self.registers.yank(register, register::Register::new(text, kind));
+        } else {
+            self.registers.delete(register, register::Register::new(text, kind));
+        }
+    }
+
+    fn paste(&mut self, name: Option<char>, after: bool, count: Option<usize>) {
+        match self.registers.get(name) {
+            Some(register) => {
+                self.output.paste(register, after, count.unwrap_or(1));
+                self.record_change(change::Change::new(change::Command::Paste(after), count, name));
+            }
+            None if name == Some('_') => {}
+            None => {
+                self.output.status_message.set_message(format!(
+                    "E353: Nothing in register {}",
+                    name.unwrap_or('"')
+                ));
+                self.abort_macro();
+            }
+        }
+    }
+
+    /* Apply `operator` to `target` from the cursor. */
+    fn run_operator(
+        &mut self,
+        operator: operator::Operator,
+        target: change::Target,
+        count: Option<usize>,
+        register: Option<char>,
+    ) {
+        if let change::Target::Selection { kind, rows, columns } = target {
+            let cursor_controller = &self.output.cursor_controller;
+            let cursor = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+            let (range, block) =
+                change::reselect(kind, rows, columns, cursor, &self.output.editor_rows);
+            self.apply_selection(operator, kind, range, block, count, register);
+        } else {
+            match self.target_range(operator, target, count) {
+                Some(range) => self.apply_operator(operator, range, register),
+                None => return self.abort_macro(),
+            }
+        }
+        self.record_change(change::Change::new(
+            change::Command::Operator(operator, target),
+            count,
+            register,
+        ));
+    }
+
+    /* The range a motion, text object or doubled operator covers. */
+    fn target_range(
+        &self,
+        operator: operator::Operator,
+        target: change::Target,
+        count: Option<usize>,
+    ) -> Option<motion::MotionRange> {
+        let cursor_controller = &self.output.cursor_controller;
+        let cursor = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+        match target {
+            change::Target::Motion(ch, pending_g) => self.operator_range(operator, ch, count, pending_g),
+            change::Target::Object(ch, inner) => textobject::TextObject::from_char(ch)
+                .and_then(|object| object.range(&self.output.editor_rows, cursor, inner, count)),
+            change::Target::Lines => {
+                let last_row = self.output.editor_rows.number_of_rows().saturating_sub(1);
+                Some(motion::MotionRange {
+                    start: (cursor.0, 0),
+                    end: (cmp::min(cursor.0 + count.unwrap_or(1) - 1, last_row), 0),
+                    linewise: true,
+                })
+            }
+            change::Target::Selection { .. } => None,
+        }
+    }
+
+    /* `/` and `?` search for a typed pattern, `n` and `N` for the last one
+     * again and `*` and `#` for the word under the cursor. */
+    fn search(&mut self, ch: char, count: Option<usize>) {
+        let found = match ch {
+            '/' => {
+                self.pending_search = Some(searchdirection::SearchDirection::Forward);
+                true
+            }
+            '?' => {
+                self.pending_search = Some(searchdirection::SearchDirection::Backward);
+                true
+            }
+            'n' | 'N' => self.output.search_next(ch == 'N', count),
+            _ => {
+                let cursor_controller = &self.output.cursor_controller;
+                let cursor = (cursor_controller.cursor_y, cursor_controller.cursor_x);
+                match search::word_under(&self.output.editor_rows, cursor) {
+                    Some(word) => {
+                        let direction = if ch == '*' {
+                            searchdirection::SearchDirection::Forward
+                        } else {
+                            searchdirection::SearchDirection::Backward
+                        };
+                        let pattern = format!("\\<{}\\>", word);
+                        self.output.search_index.pattern = Some(pattern);
+                        self.output.search_index.direction = direction;
+                        self.output.search_next(false, count)
                     }
-                    else {
-                        self.output.insert_char('a');
+                    None => {
+                        self.output
+                            .status_message
+                            .set_message("E348: No string under cursor".into());
+                        false
                     }
                 }
+            }
+        };
+        if !found {
+            self.abort_macro();
+        }
+    }
+
+    fn start_insert(&mut self, kind: change::InsertKind, count: Option<usize>) {
+        self.enter_insert(count);
+        match kind {
+            change::InsertKind::Insert => {}
+            change::InsertKind::Append => {
+                self.output.move_cursor(motion::Motion::Right, None);
+            }
+            change::InsertKind::OpenBelow => self.output.open_row(true),
+            change::InsertKind::OpenAbove => self.output.open_row(false),
+        }
+        self.record_change(change::Change::new(change::Command::Insert(kind), count, None));
+    }
+
+    /* Leave insert mode: repeat the typed text for a count, copy it down a
+     * block, and close the undo step. */
+    fn finish_insert(&mut self) {
+        let inserted = std::mem::take(&mut self.inserted);
+        let open_rows = matches!(
+            self.pending_change,
+            Some(change::Change {
+                command: change::Command::Insert(change::InsertKind::OpenBelow | change::InsertKind::OpenAbove),
+                ..
+            })
+        );
+        /* `3ihello<Esc>` types the text twice more, `3ohello<Esc>` on two
+         * more rows */
+        for _ in 1..self.insert_count {
+            if open_rows {
+                self.output.open_row(true);
+            }
+            self.output.insert_text(&inserted);
+        }
+        if let Some((rows, column)) = self.block_insert.take() {
+            if !inserted.contains('\n') {
+                for y in rows {
+                    self.output.insert_at_column(y, column, &inserted);
+                }
+            }
+        }
+        self.output.editor_rows.history.end_group();
+        if let Some(mut change) = self.pending_change.take() {
+            change.inserted = inserted;
+            self.last_change = Some(change);
+        }
+        self.enter_normal();
+        self.output.status_message.set_message("NORMAL".into());
+    }
+
+    /* Keep `change` for `.`. Yanks are not changes, and a change that went
+     * on into insert mode waits there for the text typed. */
+    fn record_change(&mut self, change: change::Change) {
+        if let change::Command::Operator(operator::Operator::Yank, _) = change.command {
+            return;
+        }
+        if matches!(self.mode, MODE::Insert) {
+            self.pending_change = Some(change);
+        } else {
+            self.last_change = Some(change);
+        }
+    }
+
+    /* `.` makes the last change again at the cursor, with `count` in place
+     * of the one it was first given. */
+    fn repeat_change(&mut self, count: Option<usize>) {
+        let mut change = match self.last_change.clone() {
+            Some(change) => change,
+            None => return self.abort_macro(),
+        };
+        if count.is_some() {
+            change.count = count;
+        }
+        /* `"1p...` puts back older and older deletes */
+        if let (change::Command::Paste(_), Some(name @ '1'..='8')) = (change.command, change.register) {
+            change.register = char::from_digit(name.to_digit(10).unwrap() + 1, 10);
+        }
+        match change.command {
+            change::Command::Operator(operator, target) => {
+                self.run_operator(operator, target, change.count, change.register)
+            }
+            change::Command::Insert(kind) => self.start_insert(kind, change.count),
+            change::Command::Paste(after) => self.paste(change.register, after, change.count),
+        }
+        if matches!(self.mode, MODE::Insert) {
+            self.output.insert_text(&change.inserted);
+            self.inserted = change.inserted;
+            self.finish_insert();
+        }
+    }
+
+    fn start_recording(&mut self, name: char) {
+        if register::Registers::is_valid(name) && !matches!(name, '_' | '-') {
+            self.reader.recording = Some((name, Vec::new()));
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some((name, mut keys)) = self.reader.recording.take() {
+            /* the `q` that stopped the recording */
+            keys.pop();
+            let text = keys.into_iter().filter_map(reader::key_to_char).collect();
+            self.registers.record(name, text);
+        }
+    }
+
+    /* `@a` feeds register `a` back through the reader as if it were typed,
+     * `@@` repeats the last one. */
+    fn run_macro(&mut self, name: char, count: Option<usize>) {
+        let name = match name {
+            '@' => match self.last_macro {
+                Some(name) => name,
+                None => {
+                    self.output
+                        .status_message
+                        .set_message("E748: No previously used register".into());
+                    return self.abort_macro();
+                }
+            },
+            name => name,
+        };
+        let keys: Vec<KeyEvent> = match self.registers.get(Some(name)) {
+            Some(register) => register.text.chars().map(reader::char_to_key).collect(),
+            None => return self.abort_macro(),
+        };
+        self.last_macro = Some(name);
+        for _ in 0..count.unwrap_or(1) {
+            self.reader.replay(&keys);
+        }
+    }
+
+    /* Like Vim, a macro stops at the first command that fails. */
+    fn abort_macro(&mut self) {
+        self.reader.pending.clear();
+    }
+
+    /* Start typing a `:` line, beginning with `input`. */
+    fn open_command_line(&mut self, input: String) {
+        self.mode = MODE::Command;
+        self.output.command_line = Some(input.clone());
+        self.command_line.open(input);
+    }
 
+    fn close_command_line(&mut self) {
+        self.mode = MODE::Normal;
+        self.output.command_line = None;
+    }
+
+    /* Keys typed on the `:` line. Returns `false` once a command quits. */
+    fn process_command(&mut self, key: KeyEvent) -> crossterm::Result<bool> {
+        match key {
             KeyEvent {
-                code: code @ (KeyCode::Char(..) | KeyCode::Tab),
-                modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+                code: KeyCode::Esc, ..
+            } => self.close_command_line(),
+
+            KeyEvent {
+                code: KeyCode::Enter,
+                modifiers: KeyModifiers::NONE,
             } => {
-                    if matches!(self.mode, MODE::Insert) {
-                        self.output.insert_char(match code {
-                            KeyCode::Tab => '\t',
-                            KeyCode::Char(ch) => ch,
-                            _ => unreachable!(),
-                        });
-                    }
-                }
-                
-            KeyEvent { 
-                code: KeyCode::Esc, 
-                modifiers: KeyModifiers::NONE, 
-            } => { 
-                    self.mode = MODE::Normal;
-                    self.output.status_message.set_message(format!("NORMAL"));
+                let line = self.command_line.finish();
+                self.close_command_line();
+                return self.execute(&line);
+            }
+
+            KeyEvent {
+                code: KeyCode::Backspace,
+                modifiers: KeyModifiers::NONE,
+            } => {
+                /* backspacing over the `:` leaves the line */
+                let was_empty = !self.command_line.pop();
+                if was_empty {
+                    self.close_command_line();
+                    return Ok(true);
                 }
+            }
+
+            KeyEvent {
+                code: direction @ (KeyCode::Up | KeyCode::Down),
+                modifiers: KeyModifiers::NONE,
+            } => self.command_line.browse(direction == KeyCode::Up),
+
+            KeyEvent {
+                code: KeyCode::Tab,
+                modifiers: KeyModifiers::NONE,
+            } => self.command_line.complete(true),
+
+            KeyEvent {
+                code: KeyCode::BackTab, ..
+            } => self.command_line.complete(false),
+
+            KeyEvent {
+                code: KeyCode::Char(ch),
+                modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+            } => self.command_line.push(ch),
 
             _ => {}
         }
+        self.output.command_line = Some(self.command_line.input.clone());
         Ok(true)
     }
 
+    /* Run a `:` line, showing what went wrong in the message bar. Returns
+     * `false` when the editor should quit. */
+    fn execute(&mut self, line: &str) -> crossterm::Result<bool> {
+        match ex::parse(line).and_then(|command| self.run_ex(command)) {
+            Ok(running) => Ok(running),
+            Err(message) => {
+                self.output.status_message.set_message(message);
+                self.abort_macro();
+                Ok(true)
+            }
+        }
+    }
+
+    fn run_ex(&mut self, command: ex::Command) -> Result<bool, String> {
+        let number_of_rows = self.output.editor_rows.number_of_rows();
+        let context = ex::Context {
+            editor_rows: &self.output.editor_rows,
+            cursor_y: self.output.cursor_controller.cursor_y,
+            visual: self.visual_marks,
+            options: &self.output.options,
+        };
+        let current = cmp::min(context.cursor_y + 1, number_of_rows);
+        let whole = (cmp::min(1, number_of_rows), number_of_rows);
+        match command.name.as_str() {
+            "" => {
+                /* `:12` goes to row 12 */
+                if !command.range.is_empty() {
+                    let (_, line) = command.lines(&context, (current, current))?;
+                    self.output
+                        .move_cursor(motion::Motion::LastLine, Some(cmp::max(line, 1)));
+                }
+            }
+            "write" => {
+                let lines = command.lines(&context, whole)?;
+                self.write(&command.args, lines, lines != whole, command.bang)?;
+            }
+            "wq" | "xit" | "exit" => {
+                let lines = command.lines(&context, whole)?;
+                /* `:x` only writes when there is something to write */
+                if command.name == "wq" || self.output.dirty > 0 || !command.args.is_empty() {
+                    self.write(&command.args, lines, lines != whole, command.bang)?;
+                }
+                return Ok(self.quit());
+            }
+            "quit" => {
+                if self.output.dirty > 0 && !command.bang {
+                    return Err(String::from("E37: No write since last change (add ! to override)"));
+                }
+                return Ok(self.quit());
+            }
+            "edit" => self.edit(&command.args, command.bang)?,
+            "saveas" => {
+                if command.args.is_empty() {
+                    return Err(String::from("E471: Argument required"));
+                }
+                let path = PathBuf::from(&command.args);
+                if path.exists() && !command.bang {
+                    return Err(String::from("E13: File exists (add ! to override)"));
+                }
+                self.output.editor_rows.filename = Some(path);
+                self.output.detect_file_type();
+                self.write("", whole, false, false)?;
+            }
+            "read" => {
+                let (_, line) = command.lines(&context, (current, current))?;
+                self.read(&command.args, line)?;
+            }
+            "substitute" => {
+                let lines = command.lines(&context, (current, current))?;
+                self.substitute(&command.args, lines)?;
+            }
+            "nohlsearch" => self.output.search_index.highlight = None,
+            "colorscheme" => {
+                /* a bare `:colorscheme` says which one is in use */
+                if command.args.is_empty() {
+                    self.output.status_message.set_message(self.output.theme.name.clone());
+                } else {
+                    self.output.theme = theme::Theme::load(&command.args, self.output.theme.depth)?;
+                }
+            }
+            "set" => {
+                if let Some(shown) = self.output.options.set(&command.args)? {
+                    self.output.status_message.set_message(shown);
+                }
+            }
+            "registers" | "display" => {
+                let names: String = command.args.chars().filter(|ch| !ch.is_whitespace()).collect();
+                self.output
+                    .show_lines(&mut self.reader, &self.registers.listing(&names))
+                    .map_err(|error| error.to_string())?;
+            }
+            /* a name in `ex::COMMANDS` that is not handled here */
+            name => return Err(format!("E492: Not an editor command: {}", name)),
+        }
+        Ok(true)
+    }
+
+    /* `:w`: the buffer goes to its own file, which a buffer without one
+     * takes its name from `file`. Writing to another file, or only some
+     * of the rows, leaves the buffer modified. */
+    fn write(&mut self, file: &str, lines: (usize, usize), partial: bool, bang: bool) -> Result<(), String> {
+        let editor_rows = &mut self.output.editor_rows;
+        let named = editor_rows.filename.is_none() && !file.is_empty();
+        if named {
+            editor_rows.filename = Some(PathBuf::from(file));
+        }
+        let path = match (file, &editor_rows.filename) {
+            ("", None) => return Err(String::from("E32: No file name")),
+            ("", Some(name)) => name.clone(),
+            (file, _) => PathBuf::from(file),
+        };
+        let own_file = editor_rows.filename.as_ref() == Some(&path);
+        if own_file && partial && !bang {
+            return Err(String::from("E140: Use ! to write partial buffer"));
+        }
+        if !own_file && path.exists() && !bang {
+            return Err(String::from("E13: File exists (add ! to override)"));
+        }
+        let written = if own_file && !partial {
+            editor_rows
+                .save()
+                .map(|len| (editor_rows.number_of_rows(), len))
+        } else {
+            let rows = (lines.1 > 0).then(|| (lines.0.saturating_sub(1), lines.1 - 1));
+            editor_rows.write_rows(&path, rows)
+        };
+        let (rows, bytes) = written.map_err(|error| {
+            format!("E212: Can't open file for writing: {}: {}", path.display(), error)
+        })?;
+        if own_file && !partial {
+            self.output.dirty = 0;
+            self.output.editor_rows.history.mark_saved();
+        }
+        if named {
+            self.output.detect_file_type();
+        }
+        self.output
+            .status_message
+            .set_message(format!("\"{}\" {}L, {}B written", path.display(), rows, bytes));
+        if own_file && !partial {
+            self.write_undo_file();
+        }
+        Ok(())
+    }
+
+    /* With `undofile` set, the buffer's own file just written is followed
+     * by its undo history. The file itself is safe by then, so a failure
+     * here is only reported. */
+    fn write_undo_file(&mut self) {
+        if !self.output.options.undo_file {
+            return;
+        }
+        let editor_rows = &self.output.editor_rows;
+        if let (Err(error), Some(name)) = (editor_rows.save_history(), &editor_rows.filename) {
+            let message = format!(
+                "E828: Cannot open undo file for writing: {}: {}",
+                editorrows::EditorRows::undo_file(name).display(),
+                error
+            );
+            self.output.status_message.set_message(message);
+        }
+    }
+
+    /* `:e file` opens `file` in place of the buffer, `:e!` throws away the
+     * changes to the current one. */
+    fn edit(&mut self, file: &str, bang: bool) -> Result<(), String> {
+        if self.output.dirty > 0 && !bang {
+            return Err(String::from("E37: No write since last change (add ! to override)"));
+        }
+        let path = match (file, &self.output.editor_rows.filename) {
+            ("", None) => return Err(String::from("E32: No file name")),
+            ("", Some(name)) => name.clone(),
+            (file, _) => PathBuf::from(file),
+        };
+        let editor_rows = editorrows::EditorRows::from_file(path.clone())
+            .map_err(|error| format!("E484: Can't open file {}: {}", path.display(), error))?;
+        let message = if path.exists() {
+            format!(
+                "\"{}\" {}L, {}B",
+                path.display(),
+                editor_rows.number_of_rows(),
+                editor_rows.buffer.len_bytes()
+            )
+        } else {
+            format!("\"{}\" [New]", path.display())
+        };
+        self.output.editor_rows = editor_rows;
+        self.output.update_syntax();
+        self.output.cursor_controller.cursor_y = 0;
+        self.output.cursor_controller.cursor_x = 0;
+        self.output.dirty = 0;
+        self.visual_marks = None;
+        self.output.status_message.set_message(message);
+        Ok(())
+    }
+
+    /* `:r file` puts the rows of `file` below row `line`, which may be 0 to
+     * put them above the first. */
+    fn read(&mut self, file: &str, line: usize) -> Result<(), String> {
+        let path = match (file, &self.output.editor_rows.filename) {
+            ("", None) => return Err(String::from("E32: No file name")),
+            ("", Some(name)) => name.clone(),
+            (file, _) => PathBuf::from(file),
+        };
+        let (text, _) = editorrows::EditorRows::read_file(Path::new(&path))
+            .map_err(|error| format!("E484: Can't open file {}: {}", path.display(), error))?;
+        if text.len_chars() == 0 {
+            return Ok(());
+        }
+        let editor_rows = &mut self.output.editor_rows;
+        let index = editor_rows.buffer.line_to_char(line);
+        editor_rows.insert_text(index, &String::from(text));
+        self.output.dirty += 1;
+        self.output.cursor_controller.cursor_y = line;
+        self.output.cursor_controller.cursor_x = self.output.editor_rows.first_non_blank(line);
+        Ok(())
+    }
+
+    /* `:s`: replace matches on rows `lines` as one undo step, asking about
+     * each one first with the `c` flag. */
+    fn substitute(&mut self, args: &str, lines: (usize, usize)) -> Result<(), String> {
+        let last = self
+            .last_substitute
+            .as_ref()
+            .map(|(pattern, replacement)| (pattern.as_str(), replacement.as_str()));
+        let substitute = substitute::Substitute::parse(args, last, &self.output.options)?;
+        self.last_substitute = Some((substitute.pattern.clone(), substitute.replacement.clone()));
+        let not_found = format!("E486: Pattern not found: {}", substitute.pattern);
+        let number_of_rows = self.output.editor_rows.number_of_rows();
+        if number_of_rows == 0 {
+            return Err(not_found);
+        }
+        /* a count counts rows from the last one of the range */
+        let (first, last) = match substitute.count {
+            Some(count) => (lines.1, lines.1 + count - 1),
+            None => lines,
+        };
+        let mut y = first.saturating_sub(1);
+        let mut last = cmp::min(last, number_of_rows).saturating_sub(1);
+
+        let mut found = false;
+        let mut substitutions = 0;
+        let mut changed_rows = 0;
+        let mut last_changed = None;
+        let mut ask = substitute.confirm;
+        let mut finished = false;
+        self.output.editor_rows.history.begin_group();
+        while y <= last && !finished {
+            let mut row = self.output.editor_rows.get_row(y);
+            let mut from = 0;
+            /* an empty match right where the last replacement ended does
+             * not count, so putting `-` in place of every `b*` turns `abc`
+             * into `-a-c-` */
+            let mut replaced_to = None;
+            let mut row_changed = false;
+            while let Some(captures) = substitute.regex.captures_at(&row, from) {
+                let matched = captures.get(0).unwrap();
+                let (start, end) = (matched.start(), matched.end());
+                if start == end && replaced_to == Some(start) {
+                    match row[start..].chars().next() {
+                        Some(ch) => {
+                            from = start + ch.len_utf8();
+                            replaced_to = None;
+                            continue;
+                        }
+                        None => break,
+                    }
+                }
+                found = true;
+                let replacement = substitute.expand(&captures);
+                let row_start = self.output.editor_rows.buffer.line_to_char(y);
+                let start_char = row_start + row[..start].chars().count();
+                let end_char = start_char + row[start..end].chars().count();
+
+                let mut replace = true;
+                if ask {
+                    match self.confirm_substitution(start_char, end_char, &replacement)? {
+                        'y' => {}
+                        'l' => finished = true,
+                        'a' => ask = false,
+                        'n' => replace = false,
+                        _ => {
+                            finished = true;
+                            break;
+                        }
+                    }
+                }
+                let mut next = end;
+                if replace {
+                    let editor_rows = &mut self.output.editor_rows;
+                    if start_char < end_char {
+                        editor_rows.remove_text(start_char..end_char);
+                    }
+                    if !replacement.is_empty() {
+                        editor_rows.insert_text(start_char, &replacement);
+                    }
+                    substitutions += 1;
+                    row_changed = true;
+                    /* a `\r` in the replacement carries on past the rows it made */
+                    let breaks = replacement.matches('\n').count();
+                    y += breaks;
+                    last += breaks;
+                    next = match replacement.rfind('\n') {
+                        Some(index) => replacement.len() - index - 1,
+                        None => start + replacement.len(),
+                    };
+                    row = editor_rows.get_row(y);
+                    replaced_to = (start < end).then_some(next);
+                }
+                if start == end {
+                    match row[next..].chars().next() {
+                        Some(ch) => next += ch.len_utf8(),
+                        None => break,
+                    }
+                }
+                from = next;
+                if !substitute.global || finished {
+                    break;
+                }
+            }
+            if row_changed {
+                changed_rows += 1;
+                last_changed = Some(y);
+            }
+            y += 1;
+        }
+        self.output.editor_rows.history.end_group();
+        self.output.matched = None;
+
+        if let Some(y) = last_changed {
+            self.output.dirty += 1;
+            self.output.cursor_controller.cursor_y = y;
+            self.output.cursor_controller.cursor_x = self.output.editor_rows.first_non_blank(y);
+        }
+        if !found {
+            return Err(not_found);
+        }
+        if substitutions > 2 {
+            self.output.status_message.set_message(format!(
+                "{} substitutions on {} line{}",
+                substitutions,
+                changed_rows,
+                if changed_rows == 1 { "" } else { "s" }
+            ));
+        } else if ask || substitute.confirm {
+            self.output.status_message.set_message(String::new());
+        }
+        Ok(())
+    }
+
+    /* Show the match between buffer chars `start` and `end` and ask what
+     * to do with it: `y`, `n`, `a` for all the rest, `l` for this one and
+     * no more, or `q` to stop. */
+    fn confirm_substitution(&mut self, start: usize, end: usize, replacement: &str) -> Result<char, String> {
+        let start = self.output.editor_rows.position(start);
+        let end = self.output.editor_rows.position(end);
+        (self.output.cursor_controller.cursor_y, self.output.cursor_controller.cursor_x) = start;
+        self.output.matched = Some((start, end));
+        let replacement = replacement.replace('\n', "^M");
+        loop {
+            self.output
+                .status_message
+                .set_message(format!("replace with {} (y/n/a/q/l)?", replacement));
+            self.output.refresh_screen().map_err(|error| error.to_string())?;
+            let output = &mut self.output;
+            let key = self
+                .reader
+                .read_key(&mut |columns, rows| output.resize(columns, rows))
+                .map_err(|error| error.to_string())?;
+            match key.code {
+                KeyCode::Char(answer @ ('y' | 'n' | 'a' | 'q' | 'l')) => return Ok(answer),
+                KeyCode::Esc => return Ok('q'),
+                _ => {}
+            }
+        }
+    }
+
+    /* Leaving keeps the registers for the next session. */
+    fn quit(&mut self) -> bool {
+        if let Some(file) = &self.session_file {
+            let _ = session::save(file, &self.registers);
+        }
+        false
+    }
+
     pub fn run(&mut self) -> crossterm::Result<bool> {
-        self.output.refresh_screen()?;
+        /* a running macro only redraws once it is done */
+        if !self.reader.is_replaying() {
+            self.output.refresh_screen()?;
+        }
         self.process_keypress()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editorcontents;
+
+    /* An editor on `text`, with nothing kept between sessions and a
+     * terminal that only keeps what is written to it. */
+    fn editor_on(text: &str) -> Editor {
+        let editor_rows = editorrows::EditorRows::from_rope(ropey::Rope::from_str(text));
+        let theme = theme::Theme::built_in("default", theme::ColorDepth::TrueColor);
+        let mut output = output::Output::with_size(60, 10, editor_rows, theme);
+        output.editor_contents = editorcontents::EditorContents::keeping();
+        Editor::with_output(output, None)
+    }
+
+    /* An editor on `text` that has run the keys of `register_a` as
+     * `keys`, giving up after `limit` of them. */
+    fn run_macro_keys(text: &str, register_a: &str, keys: &str, limit: usize) -> (Editor, usize) {
+        let mut editor = editor_on(text);
+        editor.registers.record('a', register_a.to_string());
+        editor.reader.replay(&keys.chars().map(reader::char_to_key).collect::<Vec<_>>());
+        let mut handled = 0;
+        while editor.reader.is_replaying() && handled < limit {
+            editor.process_keypress().unwrap();
+            handled += 1;
+        }
+        (editor, handled)
+    }
+
+    /* An editor on `text` that has been given `keys`. */
+    fn after(text: &str, keys: &str) -> Editor {
+        let mut editor = editor_on(text);
+        editor.reader.replay(&keys.chars().map(reader::char_to_key).collect::<Vec<_>>());
+        while editor.reader.is_replaying() {
+            editor.process_keypress().unwrap();
+        }
+        editor
+    }
+
+    /* The text `keys` leave of `text`. */
+    fn typed(text: &str, keys: &str) -> String {
+        after(text, keys).output.editor_rows.buffer.to_string()
+    }
+
+    #[test]
+    fn j_past_the_last_row_stops_a_counted_macro() {
+        let (editor, _) = run_macro_keys("a\nb\n", "$a!\x1bj", "5@a", 100);
+        assert_eq!(editor.output.editor_rows.buffer.to_string(), "a!\nb!\n");
+        assert!(!editor.reader.is_replaying());
+    }
+
+    #[test]
+    fn j_past_the_last_row_stops_a_recursive_macro() {
+        let (editor, handled) = run_macro_keys("a\nb\n", "$a!\x1bj@a", "gg@a", 100);
+        assert!(handled < 100);
+        assert_eq!(editor.output.editor_rows.buffer.to_string(), "a!\nb!\n");
+    }
+
+    #[test]
+    fn every_ex_command_runs() {
+        for (name, _, _) in ex::COMMANDS {
+            let mut editor = editor_on("a\nb\n");
+            /* for the listings that wait for a key */
+            editor.reader.replay(&[reader::char_to_key('\x1b')]);
+            let _ = editor.run_ex(ex::parse(name).unwrap());
+        }
+    }
+
+    #[test]
+    fn dot_repeats_an_operator_with_its_motion_or_object() {
+        assert_eq!(typed("a b c\n", "dw."), "c\n");
+        assert_eq!(typed("foo bar baz\n", "diw.."), " baz\n");
+        assert_eq!(typed("abcdef\n", "vld."), "ef\n");
+    }
+
+    #[test]
+    fn dot_repeats_the_text_typed_after_a_change() {
+        assert_eq!(typed("foo bar\n", "ciwX\x1bw."), "X X\n");
+        assert_eq!(typed("ab\ncd\n", "$a!\x1bj."), "ab!\ncd!\n");
+        assert_eq!(typed("\n", "3ix\x1b."), "xxxxxx\n");
+    }
+
+    #[test]
+    fn a_count_on_dot_replaces_the_first_one() {
+        assert_eq!(typed("1\n2\n3\n4\n5\n", "dd2."), "4\n5\n");
+    }
+
+    #[test]
+    fn yanks_are_not_repeated() {
+        assert_eq!(typed("a b c\n", "dwyw."), "c\n");
+    }
+
+    #[test]
+    fn dot_after_a_numbered_put_puts_older_deletes() {
+        assert_eq!(typed("a\nb\nc\nx\n", "dddddd\"1p.."), "x\nc\nb\na\n");
+    }
+
+    #[test]
+    fn operators_act_on_motions() {
+        assert_eq!(typed("foo bar\n", "dw"), "bar\n");
+        assert_eq!(typed("foo bar\n", "de"), " bar\n");
+        assert_eq!(typed("foo bar\n", "wd$"), "foo \n");
+        assert_eq!(typed("foo bar\n", "wd0"), "bar\n");
+        assert_eq!(typed("a\nb\nc\n", "dj"), "c\n");
+        assert_eq!(typed("a\nb\nc\n", "jdG"), "a\n");
+        assert_eq!(typed("a\nb\n\nc\n", "d}"), "\nc\n");
+    }
+
+    #[test]
+    fn cw_on_a_word_changes_to_its_end() {
+        assert_eq!(typed("foo bar\n", "cwX\x1b"), "X bar\n");
+        assert_eq!(typed("foo bar\n", "ccX\x1b"), "X\n");
+    }
+
+    #[test]
+    fn doubled_operators_act_on_rows() {
+        assert_eq!(typed("a\nb\n", "jdd"), "a\n");
+        assert_eq!(typed("a\nb\n", "yyp"), "a\na\nb\n");
+        assert_eq!(typed("a\n", ">>"), "\ta\n");
+        assert_eq!(typed("a\n", ">><<"), "a\n");
+    }
+
+    #[test]
+    fn operators_act_on_text_objects() {
+        assert_eq!(typed("foo bar\n", "yiwP"), "foofoo bar\n");
+        assert_eq!(typed("f(a, b)\n", "lldi("), "f()\n");
+        assert_eq!(typed("say \"hi\"\n", "ci\"yo\x1b"), "say \"yo\"\n");
+    }
+
+    #[test]
+    fn counts_repeat_motions_and_stop_at_the_last_row() {
+        assert_eq!(typed("a\nb\nc\nd\n", "2jx"), "a\nb\n\nd\n");
+        assert_eq!(typed("a\nb\nc\n", "9jx"), "a\nb\n\n");
+        assert_eq!(typed("a b c d\n", "2wx"), "a b  d\n");
+        assert_eq!(typed("a\nb\nc\n", "2Gx"), "a\n\nc\n");
+    }
+
+    #[test]
+    fn counts_on_an_operator_and_its_motion_multiply() {
+        assert_eq!(typed("abcdef\n", "3x"), "def\n");
+        assert_eq!(typed("abc\n", "10x"), "\n");
+        assert_eq!(typed("a b c d e f g h\n", "2d3w"), "g h\n");
+        assert_eq!(typed("a\nb\nc\nd\n", "2dd"), "c\nd\n");
+        assert_eq!(typed("a\nb\nc\n", "2yyGp"), "a\nb\nc\na\nb\n");
+    }
+
+    #[test]
+    fn counts_repeat_puts_and_inserts() {
+        assert_eq!(typed("ab\n", "yl3p"), "aaaab\n");
+        assert_eq!(typed("a\n", "2ox\x1b"), "a\nx\nx\n");
+        assert_eq!(typed("\n", "2ixy\x1b"), "xyxy\n");
+    }
+
+    #[test]
+    fn charwise_selections_include_both_ends() {
+        assert_eq!(typed("abc\ndef\n", "vjd"), "ef\n");
+        assert_eq!(typed("abc\n", "lvhd"), "c\n");
+        assert_eq!(typed("abc\ndef\n", "lvjold"), "abf\n");
+        assert_eq!(typed("ab\ncd\n", "v$d"), "cd\n");
+        assert_eq!(typed("foo bar\n", "viwd"), " bar\n");
+    }
+
+    #[test]
+    fn linewise_selections_take_whole_rows() {
+        assert_eq!(typed("a\nb\nc\n", "Vjd"), "c\n");
+        assert_eq!(typed("ab\ncd\n", "lvVd"), "cd\n");
+        assert_eq!(typed("a\nb\n", "VyjP"), "a\na\nb\n");
+    }
+
+    #[test]
+    fn block_selections_act_on_each_row() {
+        assert_eq!(typed("abc\ndef\n", "\x16jld"), "c\nf\n");
+        assert_eq!(typed("abc\ndef\n", "\x16jIX\x1b"), "Xabc\nXdef\n");
+        assert_eq!(typed("abc\ndef\n", "\x16jlAY\x1b"), "abYc\ndeYf\n");
+    }
+
+    #[test]
+    fn a_search_counts_its_matches_until_nohlsearch() {
+        let mut editor = after("b\nab\nb\n", "/b\r");
+        assert_eq!(editor.output.search_count.as_deref(), Some("[2/3]"));
+        assert!(editor.output.search_index.highlight.is_some());
+        editor.run_ex(ex::parse("noh").unwrap()).unwrap();
+        assert!(editor.output.search_index.highlight.is_none());
+    }
+}
@@ -4,6 +4,15 @@ use std::io;
 
 pub struct EditorContents {
     content: String,
+    /* everything written out, when it is kept instead of going to the
+     * terminal */
+    pub kept: Option<String>,
+}
+
+impl Default for EditorContents {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EditorContents {
@@ -11,6 +20,15 @@ impl EditorContents {
     pub fn new() -> Self {
         Self {
             content: String::new(),
+            kept: None,
+        }
+    }
+
+    /* Contents that are never sent to the terminal, for tests. */
+    pub fn keeping() -> Self {
+        Self {
+            content: String::new(),
+            kept: Some(String::new()),
         }
     }
 
@@ -36,6 +54,11 @@ impl io::Write for EditorContents {
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        if let Some(kept) = &mut self.kept {
+            kept.push_str(&self.content);
+            self.content.clear();
+            return Ok(());
+        }
         let out = write!(stdout(), "{}", self.content);
         stdout().flush()?;
         self.content.clear();
@@ -1,60 +1,247 @@
 use std::{env, fs};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use std::io;
-use std::io::ErrorKind;
-use std::io::Write;
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Range;
 
+use ropey::iter::Chunks;
+use ropey::{Rope, RopeSlice};
+use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete, UnicodeSegmentation};
+
+use crate::filetype;
+use crate::history;
 use crate::row;
-use crate::editorrows;
+use crate::syntax;
+#[cfg(feature = "tree-sitter")]
+use crate::treesitter;
+
+pub const TAB_STOP: usize = 4;
+
+/* The graphemes of a slice of the rope, found chunk by chunk so that the
+ * text never has to be copied out of it. */
+struct Graphemes<'a> {
+    text: RopeSlice<'a>,
+    chunks: Chunks<'a>,
+    chunk: &'a str,
+    /* byte offset of `chunk` into `text` */
+    chunk_start: usize,
+    cursor: GraphemeCursor,
+}
+
+impl<'a> Graphemes<'a> {
+    fn new(text: RopeSlice<'a>) -> Self {
+        let mut chunks = text.chunks();
+        let chunk = chunks.next().unwrap_or("");
+        Self {
+            text,
+            chunks,
+            chunk,
+            chunk_start: 0,
+            cursor: GraphemeCursor::new(0, text.len_bytes(), true),
+        }
+    }
+}
 
-const TAB_STOP: usize = 4;
+impl<'a> Iterator for Graphemes<'a> {
+    type Item = RopeSlice<'a>;
 
+    fn next(&mut self) -> Option<RopeSlice<'a>> {
+        let start = self.cursor.cur_cursor();
+        loop {
+            match self.cursor.next_boundary(self.chunk, self.chunk_start) {
+                Ok(Some(end)) => return Some(self.text.byte_slice(start..end)),
+                Ok(None) => return None,
+                Err(GraphemeIncomplete::NextChunk) => {
+                    self.chunk_start += self.chunk.len();
+                    self.chunk = self.chunks.next().unwrap_or("");
+                }
+                Err(GraphemeIncomplete::PreContext(end)) => {
+                    let (chunk, chunk_start, _, _) = self.text.chunk_at_byte(end - 1);
+                    self.cursor.provide_context(chunk, chunk_start);
+                }
+                /* going forward nothing else is asked for */
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/* The whole file lives in a rope where every row is terminated by '\n', so
+ * row `at` always starts at `buffer.line_to_char(at)` and the buffer holds
+ * `len_lines() - 1` rows. Rows are only materialized (and rendered) when
+ * they are asked for. */
 pub struct EditorRows {
-    pub row_contents : Vec<row::Row>,
-    pub filename     : Option<PathBuf>,
+    pub buffer   : Rope,
+    pub filename : Option<PathBuf>,
+    pub file_type: filetype::FileType,
+    pub history  : history::History,
+    /* what each row leaves open for highlighting the next */
+    pub states   : syntax::States,
+    /* the file had no line break after its last row, and is written back
+     * without one, like Vim's 'noeol' */
+    pub missing_newline: bool,
+    /* the parse tree rows are coloured from, for file types with a grammar */
+    #[cfg(feature = "tree-sitter")]
+    pub tree     : Option<treesitter::SyntaxTree>,
+}
+
+impl Default for EditorRows {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EditorRows {
+    /* The file named on the command line, or an empty buffer. */
     pub fn new() -> Self {
         let mut arg = env::args();
         match arg.nth(1) {
-            None => Self {
-                row_contents: Vec::new(),
-                filename: None,
-            },
-            Some(file) => Self::from_file(file.into()),
+            None => Self::from_rope(Rope::new()),
+            Some(file) => Self::from_file(file.into()).expect("Unable to read file"),
         }
     }
 
-    pub fn from_file(file: PathBuf) -> Self {
-        let file_contents = fs::read_to_string(&file).expect("Unable to read file");
+    /* A buffer holding `buffer`, with no file behind it. */
+    pub fn from_rope(buffer: Rope) -> Self {
         Self {
+            buffer,
+            filename: None,
+            file_type: filetype::FileType::default(),
+            history: history::History::new(),
+            states: syntax::States::new(),
+            missing_newline: false,
+            #[cfg(feature = "tree-sitter")]
+            tree: None,
+        }
+    }
+
+    /* A file that does not exist yet opens as an empty buffer that will
+     * be created when it is written. */
+    pub fn from_file(file: PathBuf) -> io::Result<Self> {
+        let (buffer, missing_newline) = match Self::read_file(&file) {
+            Ok(read) => read,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (Rope::new(), false),
+            Err(error) => return Err(error),
+        };
+        let file_type = filetype::FileType::detect(Some(&file), &buffer);
+        Ok(Self {
+            history: Self::load_history(&file, &buffer),
+            #[cfg(feature = "tree-sitter")]
+            tree: treesitter::SyntaxTree::for_file_type(file_type.name.as_deref()),
+            file_type,
             filename: Some(file),
-            row_contents: file_contents.lines()
-                .map(|it| {
-                    let mut r = row::Row::new(it.into(), String::new());
-                    Self::render_row(&mut r);
-                    r
-                })
-                .collect(),
+            buffer,
+            states: syntax::States::new(),
+            missing_newline,
+        })
+    }
+
+    /* The text of `file` as rows, each ending in a line break, and
+     * whether the last one had to be given its line break. */
+    pub fn read_file(file: &Path) -> io::Result<(Rope, bool)> {
+        let mut text = Rope::from_reader(BufReader::new(fs::File::open(file)?))?;
+        let end = text.len_chars();
+        let missing_newline = end > 0 && text.char(end - 1) != '\n';
+        if missing_newline {
+            text.insert_char(end, '\n');
         }
+        Ok((text, missing_newline))
     }
-    
-    pub fn get_render(&self, at: usize) -> &String {
-        &self.row_contents[at].render
+
+    /* Undo history lives next to the file as `.name.un~`, like Vim's. It
+     * is only written with `undofile` set, but one that is there is always
+     * read back. */
+    pub fn undo_file(file: &Path) -> PathBuf {
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        file.with_file_name(format!(".{}.un~", name))
+    }
+
+    fn load_history(file: &Path, buffer: &Rope) -> history::History {
+        fs::read(Self::undo_file(file))
+            .ok()
+            .and_then(|data| history::History::read_from(&data, buffer))
+            .unwrap_or_default()
+    }
+
+    pub fn save_history(&self) -> io::Result<()> {
+        let file = self
+            .filename
+            .as_ref()
+            .ok_or_else(|| io::Error::other("no file name specified"))?;
+        let mut writer = BufWriter::new(fs::File::create(Self::undo_file(file))?);
+        self.history
+            .write_to(history::content_hash(&self.buffer), &mut writer)?;
+        writer.flush()
     }
 
     pub fn number_of_rows(&self) -> usize {
-        self.row_contents.len()
+        self.buffer.len_lines() - 1
+    }
+
+    pub fn get_row(&self, at: usize) -> String {
+        let mut content: String = self.buffer.line(at).into();
+        while content.ends_with('\n') || content.ends_with('\r') {
+            content.pop();
+        }
+        content
+    }
+
+    /* Row `at` without its line break, as it is in the rope. */
+    fn row_slice(&self, at: usize) -> RopeSlice<'_> {
+        let line = self.buffer.line(at);
+        let mut end = line.len_chars();
+        while end > 0 && matches!(line.char(end - 1), '\n' | '\r') {
+            end -= 1;
+        }
+        line.slice(..end)
+    }
+
+    pub fn row_len(&self, at: usize) -> usize {
+        Graphemes::new(self.row_slice(at)).count()
+    }
+
+    pub fn first_non_blank(&self, at: usize) -> usize {
+        Graphemes::new(self.row_slice(at))
+            .take_while(|g| g.chars().all(char::is_whitespace))
+            .count()
     }
 
-    pub fn get_row(&self, at:usize) -> &str {
-        &self.row_contents[at].row_content
+    pub fn get_editor_row(&self, at: usize) -> row::Row {
+        let mut row = row::Row::new(self.get_row(at), String::new());
+        Self::render_row(&mut row);
+        row
     }
 
-    pub fn get_editor_row(&self, at: usize) -> &row::Row {
-        &self.row_contents[at]
+    /* Row `at`, rendered and highlighted by `syntax`, after colouring as
+     * many of the rows above it as it takes to know what they leave open. */
+    pub fn highlighted_row(&mut self, syntax: &dyn syntax::SyntaxHighlight, at: usize) -> row::Row {
+        #[cfg(feature = "tree-sitter")]
+        if self.tree.is_some() {
+            let mut row = self.get_editor_row(at);
+            if let Some(tree) = &mut self.tree {
+                tree.highlight(&self.buffer, at, &mut row);
+            }
+            return row;
+        }
+        let mut row = self.get_editor_row(at);
+        if !syntax.carries_state() {
+            syntax.update_syntax(&mut row, syntax::State::Normal);
+            return row;
+        }
+        let (start, mut state, exact) = self.states.start(at);
+        for y in start..at {
+            let mut above = self.get_editor_row(y);
+            syntax.update_syntax(&mut above, state);
+            state = above.state;
+            self.states.store(y, state, exact);
+        }
+        syntax.update_syntax(&mut row, state);
+        self.states.store(at, row.state, exact);
+        row
     }
 
     pub fn render_row(row: &mut row::Row) {
@@ -65,54 +252,171 @@ impl EditorRows {
             .fold(0, |acc, next| acc + if next == '\t' { TAB_STOP } else { 1 });
 
         row.render = String::with_capacity(capacity);
-        row.row_content.chars().for_each(|c| {
-            index += 1;
-            if c == '\t' {
+        row.row_content.graphemes(true).for_each(|g| {
+            if g == "\t" {
                 row.render.push(' ');
+                index += 1;
                 while index % TAB_STOP != 0 {
                     row.render.push(' ');
                     index += 1
                 }
             } else {
-                row.render.push(c);
+                row.render.push_str(g);
+                index += row::Row::grapheme_width(g);
             }
         });
     }
 
-    pub fn get_editor_row_mut(&mut self, at: usize) -> &mut row::Row {
-        &mut self.row_contents[at]
+    /* Char offset into the buffer of grapheme `x` on row `at`. */
+    pub fn char_index(&self, at: usize, x: usize) -> usize {
+        let start = self.buffer.line_to_char(at);
+        if x == 0 {
+            return start;
+        }
+        start + Graphemes::new(self.row_slice(at)).take(x).map(|g| g.len_chars()).sum::<usize>()
+    }
+
+    /* Grapheme position of a buffer char offset, the inverse of `char_index`. */
+    pub fn position(&self, index: usize) -> (usize, usize) {
+        let at = self.buffer.char_to_line(index);
+        let row = row::Row::new(self.get_row(at), String::new());
+        (at, row.char_to_grapheme(index - self.buffer.line_to_char(at)))
+    }
+
+    /* All buffer edits funnel through these two so that every change
+     * ends up in the undo history. */
+    pub fn insert_text(&mut self, index: usize, text: &str) {
+        let at = self.buffer.char_to_line(index);
+        self.states.edited(at);
+        self.buffer.insert(index, text);
+        #[cfg(feature = "tree-sitter")]
+        if let Some(tree) = &mut self.tree {
+            tree.inserted(&self.buffer, index, text);
+        }
+        self.history.record(history::Edit::Insert {
+            at: index,
+            text: text.into(),
+        });
+    }
+
+    pub fn remove_text(&mut self, range: Range<usize>) {
+        let text = self.buffer.slice(range.clone()).to_string();
+        let at = self.buffer.char_to_line(range.start);
+        self.states.edited(at);
+        #[cfg(feature = "tree-sitter")]
+        if let Some(tree) = &mut self.tree {
+            tree.removing(&self.buffer, range.start, range.end);
+        }
+        self.buffer.remove(range.clone());
+        self.history.record(history::Edit::Remove {
+            at: range.start,
+            text,
+        });
+    }
+
+    pub fn insert_char(&mut self, at: usize, x: usize, ch: char) {
+        let index = self.char_index(at, x);
+        self.insert_text(index, ch.encode_utf8(&mut [0; 4]));
+    }
+
+    pub fn delete_char(&mut self, at: usize, x: usize) {
+        let start = self.char_index(at, x);
+        let end = self.char_index(at, x + 1);
+        self.remove_text(start..end);
+    }
+
+    pub fn insert_row(&mut self, at: usize, mut contents: String) {
+        contents.push('\n');
+        let index = self.buffer.line_to_char(at);
+        self.insert_text(index, &contents);
+    }
+
+    pub fn split_row(&mut self, at: usize, x: usize) {
+        self.insert_char(at, x, '\n');
+    }
+
+    /* The history changed the buffer from row `at` on, behind the back
+     * of the highlighting. */
+    fn changed_from(&mut self, at: usize) {
+        self.states.edited(at);
+        #[cfg(feature = "tree-sitter")]
+        if let Some(tree) = &mut self.tree {
+            tree.forget();
+        }
+    }
+
+    pub fn undo(&mut self) -> Option<(usize, usize)> {
+        self.history
+            .undo(&mut self.buffer)
+            .map(|index| {
+                self.changed_from(self.buffer.char_to_line(index));
+                self.position(index)
+            })
     }
 
-    pub fn insert_row(&mut self, at: usize, contents: String) {
-        let mut new_row = row::Row::new(contents, String::new());
-        editorrows::EditorRows::render_row(&mut new_row);
-        self.row_contents.insert(at, new_row);
+    pub fn redo(&mut self) -> Option<(usize, usize)> {
+        self.history
+            .redo(&mut self.buffer)
+            .map(|index| {
+                self.changed_from(self.buffer.char_to_line(index));
+                self.position(index)
+            })
+    }
+
+    pub fn earlier(&mut self) -> Option<(usize, usize)> {
+        /* these can undo and redo all over the buffer */
+        self.changed_from(0);
+        self.history
+            .earlier(&mut self.buffer)
+            .map(|index| self.position(index))
+    }
+
+    pub fn later(&mut self) -> Option<(usize, usize)> {
+        /* these can undo and redo all over the buffer */
+        self.changed_from(0);
+        self.history
+            .later(&mut self.buffer)
+            .map(|index| self.position(index))
     }
 
     pub fn save(&self) -> io::Result<usize> {
         match &self.filename {
-            None => Err(io::Error::new(ErrorKind::Other, "no file name specified")),
-            Some(name) => {
-                let mut file = fs::OpenOptions::new().write(true).create(true).open(name)?;
-                let contents: String = self
-                    .row_contents
-                    .iter()
-                    .map(|it| it.row_content.as_str())
-                    .collect::<Vec<&str>>()
-                    .join("\n");
-
-                file.set_len(contents.len() as u64)?;
-                file.write_all(contents.as_bytes())?;
-                Ok(contents.as_bytes().len())
-            }
+            None => Err(io::Error::other("no file name specified")),
+            Some(name) => self.write_rows(name, None).map(|(_, len)| len),
         }
     }
 
+    /* Write rows `start..=end`, or all of them, to `file`. Returns how
+     * many rows and bytes were written. The last row goes without a line
+     * break if the file it was read from had none. */
+    pub fn write_rows(&self, file: &Path, rows: Option<(usize, usize)>) -> io::Result<(usize, usize)> {
+        let (start, end) = rows.unwrap_or((0, self.number_of_rows().saturating_sub(1)));
+        let text = if self.number_of_rows() == 0 {
+            self.buffer.slice(..)
+        } else {
+            let last = end + 1 == self.number_of_rows() && self.missing_newline;
+            self.buffer.slice(
+                self.buffer.line_to_char(start)..self.buffer.line_to_char(end + 1) - usize::from(last),
+            )
+        };
+        let rows = if self.number_of_rows() == 0 { 0 } else { end + 1 - start };
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file)?;
+        let mut writer = BufWriter::new(file);
+        for chunk in text.chunks() {
+            writer.write_all(chunk.as_bytes())?;
+        }
+        writer.flush()?;
+        Ok((rows, text.len_bytes()))
+    }
+
     pub fn join_adjacent_rows(&mut self, at: usize) {
-        let current_row = self.row_contents.remove(at);
-        let previous_row = self.get_editor_row_mut(at - 1);
-        previous_row.row_content.push_str(&current_row.row_content);
-        Self::render_row(previous_row);
+        let start = self.char_index(at - 1, self.row_len(at - 1));
+        let end = self.buffer.line_to_char(at);
+        self.remove_text(start..end);
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,416 @@
+use std::cmp;
+use std::fs;
+use std::path::Path;
+
+use crate::editorrows;
+use crate::options;
+use crate::search;
+
+/* Ex commands by full name, with the shortest abbreviation each accepts
+ * and whether its argument is a file name. */
+pub const COMMANDS: [(&str, usize, bool); 14] = [
+    ("colorscheme", 4, false),
+    ("display", 2, false),
+    ("edit", 1, true),
+    ("exit", 3, true),
+    ("nohlsearch", 3, false),
+    ("quit", 1, false),
+    ("read", 1, true),
+    ("registers", 3, false),
+    ("saveas", 3, true),
+    ("set", 2, false),
+    ("substitute", 1, false),
+    ("write", 1, true),
+    ("wq", 2, true),
+    ("xit", 1, true),
+];
+
+#[derive(Clone, PartialEq)]
+enum Base {
+    Current,
+    Last,
+    /* a 1-based line number; 0 is allowed for `:0r` */
+    Line(usize),
+    Mark(char),
+    /* a pattern and whether it searches forward (`/`) or backward (`?`) */
+    Search(String, bool),
+}
+
+#[derive(Clone)]
+pub struct Address {
+    base: Base,
+    offset: isize,
+}
+
+/* A parsed command line: `[range]name[!] [args]`. */
+pub struct Command {
+    pub range: Vec<Address>,
+    pub name: String,
+    pub bang: bool,
+    pub args: String,
+}
+
+/* What a resolved range refers to besides plain line numbers. */
+pub struct Context<'a> {
+    pub editor_rows: &'a editorrows::EditorRows,
+    pub cursor_y: usize,
+    /* rows of the last visual selection, for `'<` and `'>` */
+    pub visual: Option<(usize, usize)>,
+    /* how `/pattern/` addresses treat case */
+    pub options: &'a options::Options,
+}
+
+/* The full name of command `name`, which may be abbreviated. */
+pub fn lookup(name: &str) -> Option<&'static str> {
+    COMMANDS
+        .iter()
+        .find(|(full, shortest, _)| name.len() >= *shortest && full.starts_with(name))
+        .map(|(full, _, _)| *full)
+}
+
+fn takes_file(name: &str) -> bool {
+    lookup(name).is_some_and(|full| COMMANDS.iter().any(|(other, _, file)| *other == full && *file))
+}
+
+/* Parse a pattern delimited by `delimiter`, which may be escaped inside
+ * it. Returns the pattern and the rest of the line. */
+pub fn split_pattern(text: &str, delimiter: char) -> (String, &str) {
+    let mut pattern = String::new();
+    let mut chars = text.char_indices();
+    while let Some((index, ch)) = chars.next() {
+        if ch == delimiter {
+            return (pattern, &text[index + ch.len_utf8()..]);
+        }
+        if ch == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => pattern.push(next),
+                Some((_, next)) => {
+                    pattern.push('\\');
+                    pattern.push(next);
+                }
+                None => pattern.push('\\'),
+            }
+        } else {
+            pattern.push(ch);
+        }
+    }
+    (pattern, "")
+}
+
+fn parse_address(text: &str) -> Result<(Option<Address>, &str), String> {
+    let mut rest = text.trim_start();
+    let base = match rest.chars().next() {
+        Some('.') => {
+            rest = &rest[1..];
+            Some(Base::Current)
+        }
+        Some('$') => {
+            rest = &rest[1..];
+            Some(Base::Last)
+        }
+        Some('\'') => {
+            let mark = rest[1..].chars().next().ok_or("E20: Mark not set")?;
+            rest = &rest[1 + mark.len_utf8()..];
+            Some(Base::Mark(mark))
+        }
+        Some(delimiter @ ('/' | '?')) => {
+            let (pattern, after) = split_pattern(&rest[1..], delimiter);
+            rest = after;
+            Some(Base::Search(pattern, delimiter == '/'))
+        }
+        Some(ch) if ch.is_ascii_digit() => {
+            let end = rest.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(rest.len());
+            let line = rest[..end].parse().map_err(|_| String::from("E16: Invalid range"))?;
+            rest = &rest[end..];
+            Some(Base::Line(line))
+        }
+        _ => None,
+    };
+
+    /* `+`, `-` and `+3` offsets; a bare offset counts from the cursor */
+    let mut offset = 0isize;
+    let mut has_offset = false;
+    while let Some(sign @ ('+' | '-')) = rest.chars().next() {
+        rest = &rest[1..];
+        let end = rest.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(rest.len());
+        let amount: isize = if end == 0 { 1 } else { rest[..end].parse().unwrap_or(1) };
+        rest = &rest[end..];
+        offset += if sign == '+' { amount } else { -amount };
+        has_offset = true;
+    }
+    let address = match base {
+        Some(base) => Some(Address { base, offset }),
+        None if has_offset => Some(Address { base: Base::Current, offset }),
+        None => None,
+    };
+    Ok((address, rest))
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let mut rest = line.trim_start_matches(|ch: char| ch == ':' || ch.is_whitespace());
+    let mut range = Vec::new();
+    if let Some(after) = rest.strip_prefix('%') {
+        range.push(Address { base: Base::Line(1), offset: 0 });
+        range.push(Address { base: Base::Last, offset: 0 });
+        rest = after;
+    } else {
+        loop {
+            let (address, after) = parse_address(rest)?;
+            let separator = after.trim_start().chars().next();
+            match (address, separator) {
+                (Some(address), Some(',' | ';')) => {
+                    range.push(address);
+                    rest = &after.trim_start()[1..];
+                }
+                (None, Some(',' | ';')) => {
+                    range.push(Address { base: Base::Current, offset: 0 });
+                    rest = &after.trim_start()[1..];
+                }
+                (Some(address), _) => {
+                    range.push(address);
+                    rest = after;
+                    break;
+                }
+                (None, _) => {
+                    rest = after;
+                    break;
+                }
+            }
+        }
+    }
+    if range.len() > 2 {
+        range.drain(..range.len() - 2);
+    }
+
+    let rest = rest.trim_start();
+    let end = rest
+        .find(|ch: char| !ch.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let name = &rest[..end];
+    let rest = &rest[end..];
+    let (bang, args) = match rest.strip_prefix('!') {
+        Some(args) => (true, args),
+        None => (false, rest),
+    };
+    let name = if name.is_empty() {
+        String::new()
+    } else {
+        lookup(name)
+            .ok_or_else(|| format!("E492: Not an editor command: {}", line.trim()))?
+            .to_string()
+    };
+    /* spaces at the end of a replacement are part of it */
+    let args = if name == "substitute" { args.trim_start() } else { args.trim() };
+    Ok(Command {
+        range,
+        name,
+        bang,
+        args: args.to_string(),
+    })
+}
+
+impl Address {
+    /* The 1-based line this address names. */
+    fn resolve(&self, context: &Context, from: usize) -> Result<usize, String> {
+        let number_of_rows = context.editor_rows.number_of_rows();
+        let line = match &self.base {
+            Base::Current => from,
+            Base::Last => number_of_rows,
+            Base::Line(line) => *line,
+            Base::Mark(mark) => match (mark, context.visual) {
+                ('<', Some((start, _))) => start + 1,
+                ('>', Some((_, end))) => end + 1,
+                _ => return Err(String::from("E20: Mark not set")),
+            },
+            Base::Search(pattern, forward) => {
+                let searcher = search::Searcher::new(pattern, context.options.ignore_case_for(pattern))?;
+                search_line(context.editor_rows, &searcher, from, *forward)
+                    .ok_or_else(|| format!("E486: Pattern not found: {}", pattern))?
+            }
+        };
+        let line = line as isize + self.offset;
+        if line < 0 || line as usize > number_of_rows {
+            return Err(String::from("E16: Invalid range"));
+        }
+        Ok(line as usize)
+    }
+}
+
+/* The first line after (or before) line `from` that a match of
+ * `searcher` starts on, wrapping around the buffer. Lines are 1-based. */
+fn search_line(
+    editor_rows: &editorrows::EditorRows,
+    searcher: &search::Searcher,
+    from: usize,
+    forward: bool,
+) -> Option<usize> {
+    let number_of_rows = editor_rows.number_of_rows();
+    (1..=number_of_rows)
+        .map(|step| {
+            if forward {
+                (from - 1 + step) % number_of_rows
+            } else {
+                (from - 1 + number_of_rows * 2 - step) % number_of_rows
+            }
+        })
+        .find(|&y| !searcher.row_matches(editor_rows, y).is_empty())
+        .map(|y| y + 1)
+}
+
+impl Command {
+    /* The 1-based, inclusive lines the command applies to, or `default`
+     * when it was given no range. */
+    pub fn lines(&self, context: &Context, default: (usize, usize)) -> Result<(usize, usize), String> {
+        let current = cmp::min(context.cursor_y + 1, cmp::max(context.editor_rows.number_of_rows(), 1));
+        let (start, end) = match self.range.as_slice() {
+            [] => return Ok(default),
+            [only] => {
+                let line = only.resolve(context, current)?;
+                (line, line)
+            }
+            [first, second] => {
+                let start = first.resolve(context, current)?;
+                (start, second.resolve(context, current)?)
+            }
+            _ => unreachable!(),
+        };
+        Ok((cmp::min(start, end), cmp::max(start, end)))
+    }
+}
+
+/* Completions for the word being typed at the end of `input`: command
+ * names at first, then file names for commands that take one. Returns
+ * the byte offset the word starts at along with the candidates. */
+pub fn complete(input: &str) -> (usize, Vec<String>) {
+    let command_start = input.len()
+        - input
+            .trim_start_matches(|ch: char| ch == ':' || ch.is_whitespace())
+            .trim_start_matches(|ch: char| !ch.is_ascii_alphabetic())
+            .len();
+    let rest = &input[command_start..];
+    let name_end = rest.find(|ch: char| !ch.is_ascii_alphabetic()).unwrap_or(rest.len());
+    if name_end == rest.len() {
+        let candidates = COMMANDS
+            .iter()
+            .filter(|(full, _, _)| full.starts_with(rest))
+            .map(|(full, _, _)| full.to_string())
+            .collect();
+        return (command_start, candidates);
+    }
+    if !takes_file(&rest[..name_end]) {
+        return (input.len(), Vec::new());
+    }
+    let word_start = input.rfind(' ').map_or(input.len(), |index| index + 1);
+    (word_start, complete_path(&input[word_start..]))
+}
+
+fn complete_path(prefix: &str) -> Vec<String> {
+    let (directory, name) = match prefix.rfind('/') {
+        Some(index) => (&prefix[..index + 1], &prefix[index + 1..]),
+        None => ("", prefix),
+    };
+    let read_from = if directory.is_empty() { Path::new(".") } else { Path::new(directory) };
+    let mut candidates: Vec<String> = fs::read_dir(read_from)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let file_name = entry.file_name().into_string().ok()?;
+                    /* hidden files only when asked for */
+                    if !file_name.starts_with(name) || (file_name.starts_with('.') && !name.starts_with('.')) {
+                        return None;
+                    }
+                    let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+                    Some(format!("{}{}{}", directory, file_name, if is_dir { "/" } else { "" }))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    candidates.sort();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* The lines `line` gives on `text`, with the cursor on the first. */
+    fn lines(text: &str, line: &str, options: &options::Options) -> Result<(usize, usize), String> {
+        let editor_rows = editorrows::EditorRows::from_rope(ropey::Rope::from_str(text));
+        let context = Context {
+            editor_rows: &editor_rows,
+            cursor_y: 0,
+            visual: None,
+            options,
+        };
+        parse(line)?.lines(&context, (0, 0))
+    }
+
+    const TEXT: &str = "fn main\nfoo\nfoooo\nFOO\n";
+
+    #[test]
+    fn search_addresses_are_patterns() {
+        let options = options::Options::new();
+        assert_eq!(lines(TEXT, r"/fo\{3}/", &options), Ok((3, 3)));
+        assert_eq!(lines(TEXT, r"/^fn/,$", &options), Ok((1, 4)));
+        assert_eq!(lines(TEXT, r"/o\+$/", &options), Ok((2, 2)));
+        assert_eq!(lines(TEXT, r"?^f\w*o$?", &options), Ok((3, 3)));
+        assert!(lines(TEXT, "/main(/", &options).is_err());
+    }
+
+    #[test]
+    fn search_addresses_follow_ignorecase_and_smartcase() {
+        let mut options = options::Options::new();
+        assert_eq!(lines(TEXT, "/FOO/", &options), Ok((4, 4)));
+        assert!(lines(TEXT, "/Main/", &options).is_err());
+        options.ignore_case = true;
+        assert_eq!(lines(TEXT, "/FOO/", &options), Ok((2, 2)));
+        assert_eq!(lines(TEXT, "/Main/", &options), Ok((1, 1)));
+        options.smart_case = true;
+        assert_eq!(lines(TEXT, "/FOO/", &options), Ok((4, 4)));
+        assert_eq!(lines(TEXT, r"/\cFOO/", &options), Ok((2, 2)));
+    }
+
+    #[test]
+    fn names_may_be_abbreviated_down_to_their_shortest_form() {
+        assert_eq!(lookup("s"), Some("substitute"));
+        assert_eq!(lookup("subst"), Some("substitute"));
+        assert_eq!(lookup("w"), Some("write"));
+        assert_eq!(lookup("wq"), Some("wq"));
+        assert_eq!(lookup("reg"), Some("registers"));
+        assert_eq!(lookup("re"), Some("read"));
+        assert_eq!(lookup("colo"), Some("colorscheme"));
+        assert_eq!(lookup("col"), None);
+        assert_eq!(lookup("writes"), None);
+        assert!(parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn commands_split_into_range_name_bang_and_arguments() {
+        let command = parse(":  3,$w! out.txt ").unwrap();
+        assert_eq!(command.name, "write");
+        assert!(command.bang);
+        assert_eq!(command.args, "out.txt");
+        assert_eq!(command.range.len(), 2);
+        /* spaces at the end of a replacement are kept */
+        assert_eq!(parse("s/a/b /").unwrap().args, "/a/b /");
+    }
+
+    #[test]
+    fn ranges_resolve_to_lines() {
+        let options = options::Options::new();
+        let text = "1\n2\n3\n4\n5\n";
+        assert_eq!(lines(text, "", &options), Ok((0, 0)));
+        assert_eq!(lines(text, "%", &options), Ok((1, 5)));
+        assert_eq!(lines(text, "2,4", &options), Ok((2, 4)));
+        assert_eq!(lines(text, "4,2", &options), Ok((2, 4)));
+        assert_eq!(lines(text, ".,$", &options), Ok((1, 5)));
+        assert_eq!(lines(text, ".+2", &options), Ok((3, 3)));
+        assert_eq!(lines(text, "$-1", &options), Ok((4, 4)));
+        assert_eq!(lines(text, "++", &options), Ok((3, 3)));
+        assert_eq!(lines(text, ",3", &options), Ok((1, 3)));
+        assert_eq!(lines(text, "1,2,4", &options), Ok((2, 4)));
+        assert_eq!(lines(text, "9", &options), Err(String::from("E16: Invalid range")));
+        assert_eq!(lines(text, "'<", &options), Err(String::from("E20: Mark not set")));
+    }
+}
@@ -0,0 +1,290 @@
+use std::path::Path;
+
+use ropey::Rope;
+
+use crate::editorrows::TAB_STOP;
+use crate::languages;
+
+/* Rows at either end of a file that may hold a modeline. */
+const MODELINE_ROWS: usize = 5;
+
+/* What kind of file a buffer holds, and how it is indented. */
+#[derive(Clone, PartialEq)]
+pub struct FileType {
+    /* the Vim name of the type, like `rust` or `sh` */
+    pub name: Option<String>,
+    /* how far `>` and `<` shift a row */
+    pub shift_width: usize,
+    /* whether indenting uses spaces rather than tabs */
+    pub expand_tab: bool,
+}
+
+impl Default for FileType {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl FileType {
+    /* A file of type `name`, indented the way its community does. */
+    pub fn new(name: Option<String>) -> Self {
+        let (shift_width, expand_tab) = match name.as_deref() {
+            None | Some("go" | "make") => (TAB_STOP, false),
+            Some("json" | "toml" | "markdown" | "yaml" | "sh") => (2, true),
+            Some(_) => (4, true),
+        };
+        Self {
+            name,
+            shift_width,
+            expand_tab,
+        }
+    }
+
+    /* Work out the type of `file` holding `buffer`: a modeline says it
+     * outright, otherwise the file name, the `#!` line or what the text
+     * looks like decide. A modeline can set the indentation too. */
+    pub fn detect(file: Option<&Path>, buffer: &Rope) -> Self {
+        let modeline = modeline(buffer);
+        let name = modeline
+            .name
+            .clone()
+            .or_else(|| file.and_then(by_name))
+            .or_else(|| shebang(buffer))
+            .or_else(|| sniff(buffer));
+        let mut file_type = Self::new(name);
+        if let Some(shift_width) = modeline.shift_width {
+            file_type.shift_width = shift_width;
+        }
+        if let Some(expand_tab) = modeline.expand_tab {
+            file_type.expand_tab = expand_tab;
+        }
+        file_type
+    }
+
+    /* The text one `>` puts in front of a row. */
+    pub fn indent(&self) -> String {
+        if self.expand_tab {
+            " ".repeat(self.shift_width)
+        } else {
+            String::from("\t")
+        }
+    }
+}
+
+fn row(buffer: &Rope, at: usize) -> String {
+    buffer.line(at).to_string().trim_end_matches(['\n', '\r']).to_string()
+}
+
+fn by_name(file: &Path) -> Option<String> {
+    let name = file.file_name()?.to_str()?;
+    let by_file_name = match name {
+        "Cargo.lock" | "Pipfile" => Some("toml"),
+        ".bashrc" | ".bash_profile" | ".profile" | ".zshrc" | "PKGBUILD" => Some("sh"),
+        "Makefile" | "makefile" | "GNUmakefile" => Some("make"),
+        _ => None,
+    };
+    if let Some(name) = by_file_name {
+        return Some(name.to_string());
+    }
+    let extension = file.extension()?.to_str()?;
+    languages::all()
+        .into_iter()
+        .find(|syntax| syntax.extensions().contains(&extension))
+        .map(|syntax| syntax.file_type().to_string())
+}
+
+/* `#!/usr/bin/env python3` and `#!/bin/bash` name the interpreter, and
+ * the interpreter the type. */
+fn shebang(buffer: &Rope) -> Option<String> {
+    let first = row(buffer, 0);
+    let command = first.strip_prefix("#!")?;
+    let mut words = command.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+    /* `python3.12` is python */
+    let program = program.trim_end_matches(|ch: char| ch.is_ascii_digit() || ch == '.');
+    let name = match program {
+        "sh" | "bash" | "zsh" | "dash" | "ksh" | "ash" => "sh",
+        "node" | "nodejs" => "javascript",
+        "make" => "make",
+        other => other,
+    };
+    Some(name.to_string())
+}
+
+/* A guess from the text itself, for files with no name to go by. */
+fn sniff(buffer: &Rope) -> Option<String> {
+    let text: String = buffer.chars().take(4096).collect();
+    let start = text.trim_start();
+    let name = if start.starts_with("<?xml") {
+        "xml"
+    } else if (start.starts_with('{') || start.starts_with('['))
+        && buffer.slice(buffer.len_chars().saturating_sub(64)..).to_string().trim_end().ends_with(['}', ']'])
+        && start[1..].trim_start().starts_with(['"', '{', '[', '}', ']'])
+    {
+        "json"
+    } else if start.starts_with("#include") || start.starts_with("#ifndef") {
+        "c"
+    } else if start.starts_with("package ") {
+        "go"
+    } else {
+        return None;
+    };
+    Some(name.to_string())
+}
+
+#[derive(Default)]
+struct Modeline {
+    name: Option<String>,
+    shift_width: Option<usize>,
+    expand_tab: Option<bool>,
+}
+
+/* Vim's `vim: set ft=python sw=4 et:` in the first or last few rows, or
+ * Emacs's `-*- mode: python -*-` at the top. */
+fn modeline(buffer: &Rope) -> Modeline {
+    let mut modeline = Modeline::default();
+    let number_of_rows = buffer.len_lines().saturating_sub(1);
+    let rows = (0..number_of_rows.min(MODELINE_ROWS))
+        .chain(number_of_rows.saturating_sub(MODELINE_ROWS).max(MODELINE_ROWS)..number_of_rows);
+    for at in rows {
+        let text = row(buffer, at);
+        if at < 2 {
+            if let Some(mode) = emacs_mode(&text) {
+                modeline.name = Some(mode);
+            }
+        }
+        if let Some(options) = vim_options(&text) {
+            for option in options {
+                match option.split_once('=') {
+                    Some(("ft" | "filetype", name)) if !name.is_empty() => modeline.name = Some(name.to_string()),
+                    Some(("sw" | "shiftwidth", width)) => {
+                        if let Ok(width) = width.parse::<usize>() {
+                            modeline.shift_width = Some(width.max(1));
+                        }
+                    }
+                    None if option == "et" || option == "expandtab" => modeline.expand_tab = Some(true),
+                    None if option == "noet" || option == "noexpandtab" => modeline.expand_tab = Some(false),
+                    _ => {}
+                }
+            }
+        }
+    }
+    modeline
+}
+
+/* The options of a Vim modeline on `text`, in either of its forms:
+ * `vim: ft=c sw=2` or `vim: set ft=c sw=2:`. */
+fn vim_options(text: &str) -> Option<Vec<String>> {
+    let start = ["vim:", "vi:", "ex:"].iter().find_map(|marker| {
+        text.match_indices(marker)
+            .find(|(index, _)| {
+                /* `vi:` and `ex:` only after a blank, so `lexi: x` is not one */
+                let before = text[..*index].chars().next_back();
+                before.is_none_or(char::is_whitespace) && (*marker == "vim:" || before.is_some())
+            })
+            .map(|(index, _)| index + marker.len())
+    })?;
+    let rest = text[start..].trim_start();
+    let options = match rest.strip_prefix("set ").or_else(|| rest.strip_prefix("se ")) {
+        /* the `set` form ends at the next `:` */
+        Some(set) => set.split(':').next().unwrap_or("").split_whitespace().map(String::from).collect(),
+        None => rest.split([' ', '\t', ':']).filter(|option| !option.is_empty()).map(String::from).collect(),
+    };
+    Some(options)
+}
+
+fn emacs_mode(text: &str) -> Option<String> {
+    let start = text.find("-*-")? + 3;
+    let end = start + text[start..].find("-*-")?;
+    let inside = text[start..end].trim();
+    let mode = if inside.contains(':') {
+        inside
+            .split(';')
+            .filter_map(|variable| variable.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("mode"))?
+            .1
+            .trim()
+    } else {
+        inside
+    };
+    let mode = mode.to_ascii_lowercase();
+    let name = match mode.as_str() {
+        "shell-script" | "sh" | "bash" => "sh",
+        "gfm" | "markdown" => "markdown",
+        "c++" => "cpp",
+        "js" => "javascript",
+        other => other,
+    };
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detected(file: Option<&str>, text: &str) -> FileType {
+        FileType::detect(file.map(Path::new), &Rope::from_str(text))
+    }
+
+    fn name(file: Option<&str>, text: &str) -> Option<String> {
+        detected(file, text).name
+    }
+
+    #[test]
+    fn file_names_and_extensions_name_the_type() {
+        assert_eq!(name(Some("src/main.rs"), "").as_deref(), Some("rust"));
+        assert_eq!(name(Some("/tmp/Makefile"), "").as_deref(), Some("make"));
+        assert_eq!(name(Some(".bashrc"), "").as_deref(), Some("sh"));
+        assert_eq!(name(Some("notes.unknown"), "plain\n"), None);
+    }
+
+    #[test]
+    fn the_shebang_names_the_interpreter() {
+        assert_eq!(name(Some("run"), "#!/bin/bash\n").as_deref(), Some("sh"));
+        assert_eq!(name(None, "#!/usr/bin/env python3.12\n").as_deref(), Some("python"));
+        assert_eq!(name(None, "#!/usr/bin/env -S FOO=1 node\n").as_deref(), Some("javascript"));
+    }
+
+    #[test]
+    fn text_without_a_name_is_sniffed() {
+        assert_eq!(name(None, "<?xml version=\"1.0\"?>\n").as_deref(), Some("xml"));
+        assert_eq!(name(None, "{\n  \"a\": 1\n}\n").as_deref(), Some("json"));
+        assert_eq!(name(None, "#include <stdio.h>\n").as_deref(), Some("c"));
+        assert_eq!(name(None, "{ not json\n"), None);
+    }
+
+    #[test]
+    fn a_modeline_overrides_the_name_and_sets_the_indent() {
+        let file_type = detected(Some("x.rs"), "a\n// vim: set ft=python sw=2 noet:\n");
+        assert_eq!(file_type.name.as_deref(), Some("python"));
+        assert_eq!(file_type.shift_width, 2);
+        assert_eq!(file_type.indent(), "\t");
+        assert_eq!(name(None, "x = 1 # vi:ft=ruby\n").as_deref(), Some("ruby"));
+        assert_eq!(name(None, "# -*- mode: shell-script; coding: utf-8 -*-\n").as_deref(), Some("sh"));
+    }
+
+    #[test]
+    fn markers_inside_words_are_not_modelines() {
+        assert_eq!(name(None, "lexi: ft=c\n"), None);
+        assert_eq!(name(None, "vi:ft=c\n"), None);
+    }
+
+    #[test]
+    fn modelines_are_looked_for_only_near_the_ends() {
+        let mut text = String::from("vim: ft=c\n");
+        text.push_str(&"x\n".repeat(20));
+        assert_eq!(name(None, &text).as_deref(), Some("c"));
+        let text = format!("{}vim: ft=c\n{}", "x\n".repeat(10), "x\n".repeat(10));
+        assert_eq!(name(None, &text), None);
+    }
+
+    #[test]
+    fn each_type_indents_its_own_way() {
+        assert_eq!(FileType::new(Some("go".into())).indent(), "\t");
+        assert_eq!(FileType::new(Some("yaml".into())).indent(), "  ");
+        assert_eq!(FileType::new(Some("rust".into())).indent(), "    ");
+    }
+}
@@ -0,0 +1,529 @@
+use std::io;
+
+use ropey::Rope;
+
+/* A single reversible edit against the buffer. Positions are char offsets. */
+#[derive(Clone)]
+pub enum Edit {
+    Insert { at: usize, text: String },
+    Remove { at: usize, text: String },
+}
+
+impl Edit {
+    fn at(&self) -> usize {
+        match self {
+            Edit::Insert { at, .. } | Edit::Remove { at, .. } => *at,
+        }
+    }
+
+    fn apply(&self, buffer: &mut Rope) {
+        match self {
+            Edit::Insert { at, text } => buffer.insert(*at, text),
+            Edit::Remove { at, text } => buffer.remove(*at..*at + text.chars().count()),
+        }
+    }
+
+    /* Whether `apply`, or `revert` when `undo`, stays inside `buffer`. */
+    fn fits(&self, buffer: &Rope, undo: bool) -> bool {
+        let len = buffer.len_chars();
+        match (self, undo) {
+            (Edit::Insert { at, .. }, false) | (Edit::Remove { at, .. }, true) => *at <= len,
+            (Edit::Insert { at, text }, true) | (Edit::Remove { at, text }, false) => {
+                at.checked_add(text.chars().count()).is_some_and(|end| end <= len)
+            }
+        }
+    }
+
+    fn revert(&self, buffer: &mut Rope) {
+        match self {
+            Edit::Insert { at, text } => buffer.remove(*at..*at + text.chars().count()),
+            Edit::Remove { at, text } => buffer.insert(*at, text),
+        }
+    }
+
+    /* Fold `next` into `self` when it continues the same run of typing or
+     * deleting, so an insert session does not store one edit per key. */
+    fn merge(&mut self, next: &Edit) -> bool {
+        match (self, next) {
+            (Edit::Insert { at, text }, Edit::Insert { at: next_at, text: next_text })
+                if *at + text.chars().count() == *next_at =>
+            {
+                text.push_str(next_text);
+                true
+            }
+            (Edit::Remove { at, text }, Edit::Remove { at: next_at, text: next_text })
+                if *next_at + next_text.chars().count() == *at =>
+            {
+                text.insert_str(0, next_text);
+                *at = *next_at;
+                true
+            }
+            (Edit::Remove { at, text }, Edit::Remove { at: next_at, text: next_text })
+                if *next_at == *at =>
+            {
+                text.push_str(next_text);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/* One node of the undo tree: the edits leading to it from its parent. */
+pub struct Node {
+    pub parent   : usize,
+    pub children : Vec<usize>,
+    pub redo     : Option<usize>,
+    pub edits    : Vec<Edit>,
+}
+
+/* Undo tree. Node 0 is the unmodified buffer and node indices grow with
+ * time, so `g-`/`g+` step through states by index while `u`/`Ctrl-R` walk
+ * up and down the branch that was visited last. */
+pub struct History {
+    pub nodes   : Vec<Node>,
+    pub current : usize,
+    /* the node the file on disk holds, if it is one */
+    pub saved   : Option<usize>,
+    pending     : Vec<Edit>,
+    depth       : usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node {
+                parent: 0,
+                children: Vec::new(),
+                redo: None,
+                edits: Vec::new(),
+            }],
+            current: 0,
+            saved: Some(0),
+            pending: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    pub fn begin_group(&mut self) {
+        self.depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth == 0 {
+            self.commit();
+        }
+    }
+
+    pub fn record(&mut self, edit: Edit) {
+        let merged = self
+            .pending
+            .last_mut()
+            .is_some_and(|last| last.merge(&edit));
+        if !merged {
+            self.pending.push(edit);
+        }
+        if self.depth == 0 {
+            self.commit();
+        }
+    }
+
+    fn commit(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            parent: self.current,
+            children: Vec::new(),
+            redo: None,
+            edits: std::mem::take(&mut self.pending),
+        });
+        let parent = &mut self.nodes[self.current];
+        parent.children.push(index);
+        parent.redo = Some(index);
+        self.current = index;
+    }
+
+    /* The buffer has just been written to its file. Edits of an insert
+     * that is still going on are not a node yet, so then none is known
+     * to match the file. */
+    pub fn mark_saved(&mut self) {
+        self.saved = self.pending.is_empty().then_some(self.current);
+    }
+
+    /* Whether the buffer holds what was last written to its file. */
+    pub fn is_saved(&self) -> bool {
+        self.pending.is_empty() && self.saved == Some(self.current)
+    }
+
+    /* Each of these returns the char offset the cursor should move to, or
+     * `None` when there is nothing left to undo or redo. */
+    pub fn undo(&mut self, buffer: &mut Rope) -> Option<usize> {
+        self.commit();
+        if self.current == 0 {
+            return None;
+        }
+        let node = &self.nodes[self.current];
+        node.edits.iter().rev().for_each(|edit| edit.revert(buffer));
+        let at = node.edits.iter().map(Edit::at).min();
+        let parent = node.parent;
+        self.nodes[parent].redo = Some(self.current);
+        self.current = parent;
+        at
+    }
+
+    pub fn redo(&mut self, buffer: &mut Rope) -> Option<usize> {
+        self.commit();
+        let child = self.nodes[self.current].redo?;
+        let node = &self.nodes[child];
+        node.edits.iter().for_each(|edit| edit.apply(buffer));
+        self.current = child;
+        node.edits.iter().map(Edit::at).min()
+    }
+
+    pub fn earlier(&mut self, buffer: &mut Rope) -> Option<usize> {
+        self.commit();
+        if self.current == 0 {
+            return None;
+        }
+        self.goto(self.current - 1, buffer)
+    }
+
+    pub fn later(&mut self, buffer: &mut Rope) -> Option<usize> {
+        self.commit();
+        if self.current + 1 >= self.nodes.len() {
+            return None;
+        }
+        self.goto(self.current + 1, buffer)
+    }
+
+    fn ancestors(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path
+    }
+
+    /* Move to any state in the tree by undoing up to the common ancestor
+     * and redoing down the target branch. */
+    fn goto(&mut self, target: usize, buffer: &mut Rope) -> Option<usize> {
+        let up = self.ancestors(self.current);
+        let down = self.ancestors(target);
+        let common = *up.iter().find(|node| down.contains(node))?;
+        let mut at = None;
+        while self.current != common {
+            at = self.undo(buffer);
+        }
+        let mut branch: Vec<usize> = down.into_iter().take_while(|&node| node != common).collect();
+        branch.reverse();
+        for node in branch {
+            self.nodes[self.current].redo = Some(node);
+            at = self.redo(buffer);
+        }
+        at
+    }
+}
+
+const UNDO_FILE_MAGIC: &str = "rezvan-undo 1";
+
+/* FNV-1a over the buffer, used to tell whether an undo file still belongs
+ * to the text it was written for. */
+pub fn content_hash(buffer: &Rope) -> u64 {
+    buffer
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+pub fn take_line<'a>(rest: &mut &'a [u8]) -> Option<&'a str> {
+    let end = rest.iter().position(|&b| b == b'\n')?;
+    let line = std::str::from_utf8(&rest[..end]).ok()?;
+    *rest = &rest[end + 1..];
+    Some(line)
+}
+
+/* Undo file layout: a magic line, the content hash and the current node,
+ * then one `node <parent> <redo> <edits>` line per node followed by its
+ * edits as `<I|R> <at> <byte len>` lines, each followed by the raw text. */
+impl History {
+    pub fn write_to(&self, hash: u64, out: &mut impl io::Write) -> io::Result<()> {
+        /* an open insert group is already in the buffer, so store it too */
+        let pending = (!self.pending.is_empty()).then(|| Node {
+            parent: self.current,
+            children: Vec::new(),
+            redo: None,
+            edits: self.pending.clone(),
+        });
+        let current = if pending.is_some() { self.nodes.len() } else { self.current };
+
+        writeln!(out, "{}", UNDO_FILE_MAGIC)?;
+        writeln!(out, "hash {:016x}", hash)?;
+        writeln!(out, "current {}", current)?;
+        for node in self.nodes.iter().chain(pending.iter()) {
+            let redo = node.redo.map_or(String::from("-"), |redo| redo.to_string());
+            writeln!(out, "node {} {} {}", node.parent, redo, node.edits.len())?;
+            for edit in &node.edits {
+                let (kind, at, text) = match edit {
+                    Edit::Insert { at, text } => ('I', at, text),
+                    Edit::Remove { at, text } => ('R', at, text),
+                };
+                writeln!(out, "{} {} {}", kind, at, text.len())?;
+                out.write_all(text.as_bytes())?;
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /* `None` when the file is malformed or was written for other contents
+     * than `buffer`'s. */
+    pub fn read_from(data: &[u8], buffer: &Rope) -> Option<Self> {
+        let hash = content_hash(buffer);
+        let mut rest = data;
+
+        if take_line(&mut rest)? != UNDO_FILE_MAGIC {
+            return None;
+        }
+        if u64::from_str_radix(take_line(&mut rest)?.strip_prefix("hash ")?, 16).ok()? != hash {
+            return None;
+        }
+        let current: usize = take_line(&mut rest)?.strip_prefix("current ")?.parse().ok()?;
+
+        let mut nodes: Vec<Node> = Vec::new();
+        while let Some(header) = take_line(&mut rest) {
+            let mut fields = header.strip_prefix("node ")?.split(' ');
+            let parent: usize = fields.next()?.parse().ok()?;
+            let redo = match fields.next()? {
+                "-" => None,
+                redo => Some(redo.parse().ok()?),
+            };
+            let count: usize = fields.next()?.parse().ok()?;
+            let mut edits = Vec::with_capacity(count);
+            for _ in 0..count {
+                let mut fields = take_line(&mut rest)?.split(' ');
+                let kind = fields.next()?;
+                let at: usize = fields.next()?.parse().ok()?;
+                let len: usize = fields.next()?.parse().ok()?;
+                let text = String::from_utf8(rest.get(..len)?.to_vec()).ok()?;
+                rest = rest.get(len + 1..)?;
+                edits.push(match kind {
+                    "I" => Edit::Insert { at, text },
+                    "R" => Edit::Remove { at, text },
+                    _ => return None,
+                });
+            }
+            nodes.push(Node {
+                parent,
+                children: Vec::new(),
+                redo,
+                edits,
+            });
+        }
+
+        if nodes.is_empty() || current >= nodes.len() {
+            return None;
+        }
+        for index in 1..nodes.len() {
+            let parent = nodes[index].parent;
+            if parent >= index {
+                return None;
+            }
+            nodes[parent].children.push(index);
+        }
+        /* `u` then `Ctrl-R` has to come back down the branch it went up */
+        if nodes.iter().any(|node| node.redo.is_some_and(|redo| !node.children.contains(&redo))) {
+            return None;
+        }
+        /* every edit has to fit the text it is undone or redone on, going
+         * up from `buffer` to the root and from there down every branch */
+        let mut root = buffer.clone();
+        let mut node = current;
+        while node != 0 {
+            for edit in nodes[node].edits.iter().rev() {
+                if !edit.fits(&root, true) {
+                    return None;
+                }
+                edit.revert(&mut root);
+            }
+            node = nodes[node].parent;
+        }
+        let mut branches = vec![(0, root)];
+        while let Some((node, text)) = branches.pop() {
+            for &child in &nodes[node].children {
+                let mut text = text.clone();
+                for edit in &nodes[child].edits {
+                    if !edit.fits(&text, false) {
+                        return None;
+                    }
+                    edit.apply(&mut text);
+                }
+                branches.push((child, text));
+            }
+        }
+        Some(Self {
+            nodes,
+            current,
+            saved: Some(current),
+            pending: Vec::new(),
+            depth: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* "one" typed, then " two" on top of it, as an undo file. */
+    fn undo_file() -> (Rope, String) {
+        let mut buffer = Rope::new();
+        let mut history = History::new();
+        for (at, text) in [(0, "one"), (3, " two")] {
+            buffer.insert(at, text);
+            history.record(Edit::Insert { at, text: text.to_string() });
+            history.commit();
+        }
+        let mut data = Vec::new();
+        history.write_to(content_hash(&buffer), &mut data).unwrap();
+        (buffer, String::from_utf8(data).unwrap())
+    }
+
+    fn insert(history: &mut History, buffer: &mut Rope, at: usize, text: &str) {
+        buffer.insert(at, text);
+        history.record(Edit::Insert { at, text: text.to_string() });
+    }
+
+    /* "a", then "ab", then back to "a" and "ac" on a second branch. */
+    fn branched() -> (History, Rope) {
+        let mut history = History::new();
+        let mut buffer = Rope::new();
+        insert(&mut history, &mut buffer, 0, "a");
+        insert(&mut history, &mut buffer, 1, "b");
+        history.undo(&mut buffer);
+        insert(&mut history, &mut buffer, 1, "c");
+        (history, buffer)
+    }
+
+    #[test]
+    fn undo_and_redo_follow_the_branch_visited_last() {
+        let (mut history, mut buffer) = branched();
+        assert_eq!(history.undo(&mut buffer), Some(1));
+        assert_eq!(buffer, "a");
+        history.undo(&mut buffer);
+        assert_eq!(history.undo(&mut buffer), None);
+        assert_eq!(buffer, "");
+        history.redo(&mut buffer);
+        assert_eq!(history.redo(&mut buffer), Some(1));
+        assert_eq!(buffer, "ac");
+        assert_eq!(history.redo(&mut buffer), None);
+    }
+
+    #[test]
+    fn earlier_and_later_step_through_every_state() {
+        let (mut history, mut buffer) = branched();
+        history.earlier(&mut buffer);
+        assert_eq!(buffer, "ab");
+        history.earlier(&mut buffer);
+        assert_eq!(buffer, "a");
+        history.later(&mut buffer);
+        history.later(&mut buffer);
+        assert_eq!(buffer, "ac");
+        assert_eq!(history.later(&mut buffer), None);
+    }
+
+    #[test]
+    fn a_group_is_undone_at_once() {
+        let mut history = History::new();
+        let mut buffer = Rope::new();
+        history.begin_group();
+        insert(&mut history, &mut buffer, 0, "x");
+        insert(&mut history, &mut buffer, 1, "y");
+        buffer.remove(0..1);
+        history.record(Edit::Remove { at: 0, text: "x".into() });
+        history.end_group();
+        assert_eq!(history.nodes.len(), 2);
+        history.undo(&mut buffer);
+        assert_eq!(buffer, "");
+    }
+
+    #[test]
+    fn saving_marks_the_state_on_disk() {
+        let (mut history, mut buffer) = branched();
+        assert!(!history.is_saved());
+        history.mark_saved();
+        history.undo(&mut buffer);
+        assert!(!history.is_saved());
+        history.redo(&mut buffer);
+        assert!(history.is_saved());
+    }
+
+    #[test]
+    fn an_undo_file_keeps_every_branch() {
+        let (history, buffer) = branched();
+        let mut data = Vec::new();
+        history.write_to(content_hash(&buffer), &mut data).unwrap();
+        let mut history = History::read_from(&data, &buffer).unwrap();
+        let mut buffer = buffer;
+        assert!(history.is_saved());
+        history.earlier(&mut buffer);
+        assert_eq!(buffer, "ab");
+        history.undo(&mut buffer);
+        history.redo(&mut buffer);
+        assert_eq!(buffer, "ab");
+    }
+
+    #[test]
+    fn an_intact_undo_file_is_read() {
+        let (buffer, data) = undo_file();
+        let history = History::read_from(data.as_bytes(), &buffer).unwrap();
+        assert_eq!(history.current, 2);
+    }
+
+    #[test]
+    fn an_undo_file_for_other_text_is_rejected() {
+        let (_, data) = undo_file();
+        assert!(History::read_from(data.as_bytes(), &Rope::from_str("other")).is_none());
+    }
+
+    #[test]
+    fn a_truncated_undo_file_is_rejected() {
+        let (buffer, data) = undo_file();
+        let cut = data.rfind("I 3").unwrap() + 4;
+        assert!(History::read_from(&data.as_bytes()[..cut], &buffer).is_none());
+    }
+
+    #[test]
+    fn a_redo_outside_the_tree_is_rejected() {
+        let (buffer, data) = undo_file();
+        let data = data.replacen("node 0 1 0", "node 0 7 0", 1);
+        assert!(History::read_from(data.as_bytes(), &buffer).is_none());
+    }
+
+    #[test]
+    fn a_redo_that_is_not_a_child_is_rejected() {
+        let (buffer, data) = undo_file();
+        let data = data.replacen("node 0 1 0", "node 0 2 0", 1);
+        assert!(History::read_from(data.as_bytes(), &buffer).is_none());
+    }
+
+    #[test]
+    fn an_edit_outside_the_buffer_is_rejected() {
+        let (buffer, data) = undo_file();
+        let data = data.replacen("I 3 4", "I 30 4", 1);
+        assert!(History::read_from(data.as_bytes(), &buffer).is_none());
+        let (buffer, data) = undo_file();
+        let data = data.replacen("I 3 4", "I 18446744073709551615 4", 1);
+        assert!(History::read_from(data.as_bytes(), &buffer).is_none());
+    }
+}
@@ -0,0 +1,382 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::row;
+use crate::syntax::{HighlightType, State, SyntaxHighlight};
+use crate::syntax_struct;
+use crate::syntaxfile;
+
+syntax_struct! {
+    struct RustHighlight {
+        file_type: "rust",
+        extensions: ["rs"],
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        quotes: [],
+        multiline_quotes: ['"'],
+        long_quotes: [],
+        raw_strings: true,
+        char_literals: true,
+        keywords: [
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+            "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+            "trait", "true", "type", "unsafe", "use", "where", "while",
+        ],
+        types: [
+            "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128",
+            "usize", "f32", "f64", "bool", "char", "str", "String", "Vec", "Option", "Result",
+            "Box", "Some", "None", "Ok", "Err",
+        ],
+    }
+}
+
+syntax_struct! {
+    struct CHighlight {
+        file_type: "c",
+        extensions: ["c", "h"],
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        quotes: ['"'],
+        multiline_quotes: [],
+        long_quotes: [],
+        raw_strings: false,
+        char_literals: true,
+        keywords: [
+            "break", "case", "const", "continue", "default", "do", "else", "enum", "extern",
+            "for", "goto", "if", "inline", "register", "return", "sizeof", "static", "struct",
+            "switch", "typedef", "union", "volatile", "while", "NULL", "#include", "#define",
+            "#ifdef", "#ifndef", "#if", "#else", "#endif", "#pragma",
+        ],
+        types: [
+            "char", "double", "float", "int", "long", "short", "signed", "unsigned", "void",
+            "bool", "size_t", "ssize_t", "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t",
+            "uint16_t", "uint32_t", "uint64_t",
+        ],
+    }
+}
+
+syntax_struct! {
+    struct PythonHighlight {
+        file_type: "python",
+        extensions: ["py", "pyw"],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: ['"', '\''],
+        multiline_quotes: [],
+        long_quotes: ["\"\"\"", "'''"],
+        raw_strings: false,
+        char_literals: false,
+        keywords: [
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "False", "finally", "for", "from", "global", "if",
+            "import", "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise",
+            "return", "True", "try", "while", "with", "yield",
+        ],
+        types: [
+            "int", "float", "complex", "str", "bytes", "bool", "list", "tuple", "dict", "set",
+            "frozenset", "object", "self",
+        ],
+    }
+}
+
+syntax_struct! {
+    struct GoHighlight {
+        file_type: "go",
+        extensions: ["go"],
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        quotes: ['"'],
+        multiline_quotes: ['`'],
+        long_quotes: [],
+        raw_strings: false,
+        char_literals: true,
+        keywords: [
+            "break", "case", "chan", "const", "continue", "default", "defer", "else",
+            "fallthrough", "for", "func", "go", "goto", "if", "import", "interface", "map",
+            "package", "range", "return", "select", "struct", "switch", "type", "var", "true",
+            "false", "nil", "iota",
+        ],
+        types: [
+            "bool", "byte", "complex64", "complex128", "error", "float32", "float64", "int",
+            "int8", "int16", "int32", "int64", "rune", "string", "uint", "uint8", "uint16",
+            "uint32", "uint64", "uintptr", "any",
+        ],
+    }
+}
+
+syntax_struct! {
+    struct TomlHighlight {
+        file_type: "toml",
+        extensions: ["toml"],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: ['"', '\''],
+        multiline_quotes: [],
+        long_quotes: ["\"\"\"", "'''"],
+        raw_strings: false,
+        char_literals: false,
+        keywords: ["true", "false", "inf", "nan"],
+        types: [],
+    }
+}
+
+syntax_struct! {
+    struct JsonHighlight {
+        file_type: "json",
+        extensions: ["json"],
+        line_comment: None,
+        block_comment: None,
+        quotes: ['"'],
+        multiline_quotes: [],
+        long_quotes: [],
+        raw_strings: false,
+        char_literals: false,
+        keywords: ["true", "false", "null"],
+        types: [],
+    }
+}
+
+syntax_struct! {
+    struct ShellHighlight {
+        file_type: "sh",
+        extensions: ["sh", "bash", "zsh"],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: [],
+        multiline_quotes: ['"', '\''],
+        long_quotes: [],
+        raw_strings: false,
+        char_literals: false,
+        keywords: [
+            "if", "then", "else", "elif", "fi", "for", "while", "until", "do", "done", "case",
+            "esac", "in", "function", "select", "return", "break", "continue", "local",
+            "export", "readonly", "declare", "set", "unset", "shift", "exit",
+        ],
+        types: [
+            "echo", "printf", "read", "cd", "test", "eval", "exec", "source", "trap", "true",
+            "false",
+        ],
+    }
+}
+
+/* Markdown has no keywords to speak of: headings, list markers and block
+ * quotes are told by how a row starts, code and emphasis by their marks. */
+pub struct MarkdownHighlight;
+
+impl SyntaxHighlight for MarkdownHighlight {
+    fn file_type(&self) -> &'static str {
+        "markdown"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["md", "markdown"]
+    }
+
+    /* fenced code blocks */
+    fn carries_state(&self) -> bool {
+        true
+    }
+
+    fn update_syntax(&self, row: &mut row::Row, start: State) {
+        let graphemes: Vec<&str> = row.render.graphemes(true).collect();
+        let highlight = &mut row.highlight;
+        highlight.clear();
+        row.state = State::Normal;
+        let trimmed = row.render.trim_start();
+        let indent = graphemes.len() - trimmed.graphemes(true).count();
+
+        /* a fenced code block runs until a fence like the one opening it */
+        let fence = ["```", "~~~"].into_iter().find(|fence| trimmed.starts_with(fence));
+        match (start, fence) {
+            (State::LongString(open), Some(fence)) if open == fence => {}
+            (State::LongString(open), _) => row.state = State::LongString(open),
+            (_, Some(fence)) => row.state = State::LongString(fence),
+            _ => {}
+        }
+        if matches!(start, State::LongString(_)) || fence.is_some() || row.render.starts_with("    ") {
+            highlight.resize(graphemes.len(), HighlightType::String);
+            return;
+        }
+        if trimmed.starts_with('#') {
+            highlight.resize(graphemes.len(), HighlightType::Keyword);
+            return;
+        }
+        highlight.resize(indent, HighlightType::Normal);
+        /* `-`, `*`, `+`, `1.` and `>` that begin a list item or quote */
+        let marker = match trimmed.split_once(' ') {
+            Some(("-" | "*" | "+" | ">", _)) => 1,
+            Some((number, _)) if number.ends_with('.') && number[..number.len() - 1].chars().all(|ch| ch.is_ascii_digit()) => {
+                number.len()
+            }
+            _ => 0,
+        };
+        highlight.resize(indent + marker, HighlightType::Operator);
+
+        let mut i = indent + marker;
+        while i < graphemes.len() {
+            let g = graphemes[i];
+            /* `code` and emphasis run to the matching mark on the row */
+            let (mark, highlight_type) = match g {
+                "`" => ("`", HighlightType::String),
+                "*" | "_" if graphemes.get(i + 1) == Some(&g) => (g, HighlightType::Keyword),
+                "*" | "_" => (g, HighlightType::Type),
+                _ => ("", HighlightType::Normal),
+            };
+            let double = highlight_type == HighlightType::Keyword;
+            let width = if double { 2 } else { 1 };
+            let end = (!mark.is_empty())
+                .then(|| {
+                    (i + width..graphemes.len())
+                        .find(|&end| graphemes[end] == mark && (!double || graphemes.get(end + 1) == Some(&mark)))
+                })
+                .flatten();
+            match end {
+                Some(end) => {
+                    highlight.resize(end + width, highlight_type);
+                    i = end + width;
+                }
+                None => {
+                    highlight.push(HighlightType::Normal);
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+/* Rows with no colour, for files of no known type. */
+pub struct PlainText;
+
+impl SyntaxHighlight for PlainText {
+    fn file_type(&self) -> &'static str {
+        ""
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn carries_state(&self) -> bool {
+        false
+    }
+
+    fn update_syntax(&self, row: &mut row::Row, _start: State) {
+        row.state = State::Normal;
+        row.highlight.clear();
+        row.highlight.resize(row.render.graphemes(true).count(), HighlightType::Normal);
+    }
+}
+
+/* Every language known, those defined in the syntax directory first so
+ * that they can stand in for the built-in ones. */
+pub fn all() -> Vec<Box<dyn SyntaxHighlight>> {
+    let defined = syntaxfile::definitions()
+        .iter()
+        .map(|definition| Box::new(definition.clone()) as Box<dyn SyntaxHighlight>);
+    let built_in: Vec<Box<dyn SyntaxHighlight>> = vec![
+        Box::new(RustHighlight),
+        Box::new(CHighlight),
+        Box::new(PythonHighlight),
+        Box::new(GoHighlight),
+        Box::new(TomlHighlight),
+        Box::new(JsonHighlight),
+        Box::new(MarkdownHighlight),
+        Box::new(ShellHighlight),
+    ];
+    defined.chain(built_in).collect()
+}
+
+/* The highlighter for files of type `name`, or plain text. */
+pub fn for_file_type(name: Option<&str>) -> Box<dyn SyntaxHighlight> {
+    name.and_then(|name| all().into_iter().find(|syntax| syntax.file_type() == name))
+        .unwrap_or_else(|| Box::new(PlainText))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* The colours `syntax` gives `text` starting in `start`, a letter to a
+     * grapheme, and what it leaves open. */
+    fn coloured(syntax: &dyn SyntaxHighlight, text: &str, start: State) -> (String, State) {
+        let mut row = row::Row::new(text.into(), text.into());
+        syntax.update_syntax(&mut row, start);
+        let letters = row
+            .highlight
+            .iter()
+            .map(|highlight| match highlight {
+                HighlightType::Normal => '.',
+                HighlightType::Number => '0',
+                HighlightType::Keyword => 'k',
+                HighlightType::Type => 't',
+                HighlightType::String => 's',
+                HighlightType::Char => 'c',
+                HighlightType::Comment => '/',
+                HighlightType::MultilineComment => '*',
+                HighlightType::Operator => '+',
+                _ => '?',
+            })
+            .collect();
+        (letters, row.state)
+    }
+
+    fn colours(syntax: &dyn SyntaxHighlight, text: &str) -> String {
+        coloured(syntax, text, State::Normal).0
+    }
+
+    #[test]
+    fn words_are_coloured_only_whole() {
+        assert_eq!(colours(&RustHighlight, "let x: u8 = 0x1f;"), "kkk..+.tt.+.0000.");
+        assert_eq!(colours(&RustHighlight, "letter x1 u8x"), ".............");
+        assert_eq!(colours(&PythonHighlight, "x = 1.5 + a.b"), "..+.000.+....");
+    }
+
+    #[test]
+    fn comments_run_to_the_end_of_the_row_or_their_close() {
+        assert_eq!(colours(&CHighlight, "a; // b"), "...////");
+        assert_eq!(colours(&CHighlight, "/* a */ b"), "*******..");
+        assert_eq!(coloured(&CHighlight, "a /* b", State::Normal), (String::from("..****"), State::Comment));
+        assert_eq!(coloured(&CHighlight, "b */ c", State::Comment), (String::from("****.."), State::Normal));
+    }
+
+    #[test]
+    fn strings_skip_escaped_quotes_and_end_with_their_row_unless_multiline() {
+        assert_eq!(colours(&CHighlight, r#"f("a\"b") 'x' '\n'"#), "..ssssss..ccc.cccc");
+        assert_eq!(coloured(&CHighlight, "\"open", State::Normal).1, State::Normal);
+        assert_eq!(coloured(&GoHighlight, "`open", State::Normal).1, State::String('`'));
+        assert_eq!(coloured(&ShellHighlight, "s'", State::String('\'')).0, "ss");
+    }
+
+    #[test]
+    fn rust_raw_strings_and_lifetimes() {
+        assert_eq!(colours(&RustHighlight, r##"r#"a"b"# x"##), "ssssssss..");
+        assert_eq!(coloured(&RustHighlight, r###"r##"a"#"###, State::Normal).1, State::RawString(2));
+        assert_eq!(colours(&RustHighlight, "&'a str"), "+...ttt");
+    }
+
+    #[test]
+    fn python_long_strings_carry_on() {
+        let (letters, state) = coloured(&PythonHighlight, "x = \"\"\"doc", State::Normal);
+        assert_eq!((letters.as_str(), state), ("..+.ssssss", State::LongString("\"\"\"")));
+        let (letters, state) = coloured(&PythonHighlight, "end\"\"\" # c", State::LongString("\"\"\""));
+        assert_eq!((letters.as_str(), state), ("ssssss.///", State::Normal));
+    }
+
+    #[test]
+    fn markdown_marks_headings_lists_code_and_emphasis() {
+        assert_eq!(colours(&MarkdownHighlight, "# Title"), "kkkkkkk");
+        assert_eq!(colours(&MarkdownHighlight, "- a `b` **c** _d_"), "+...sss.kkkkk.ttt");
+        assert_eq!(colours(&MarkdownHighlight, "12. x"), "+++..");
+        let (letters, state) = coloured(&MarkdownHighlight, "```rust", State::Normal);
+        assert_eq!((letters.as_str(), state), ("sssssss", State::LongString("```")));
+        assert_eq!(coloured(&MarkdownHighlight, "~~~", State::LongString("```")).1, State::LongString("```"));
+        assert_eq!(coloured(&MarkdownHighlight, "```", State::LongString("```")).1, State::Normal);
+    }
+
+    #[test]
+    fn files_of_no_known_type_are_plain() {
+        assert_eq!(colours(&PlainText, "let x = 1;"), "..........");
+        assert_eq!(for_file_type(Some("rust")).file_type(), "rust");
+        assert_eq!(for_file_type(Some("nonsense")).file_type(), "");
+    }
+}
@@ -12,6 +12,27 @@ pub mod status;
 pub mod searchindex;
 pub mod searchdirection;
 pub mod syntax;
+pub mod history;
+pub mod motion;
+pub mod operator;
+pub mod textobject;
+pub mod selection;
+pub mod register;
+pub mod session;
+pub mod change;
+pub mod ex;
+pub mod commandline;
+pub mod pattern;
+pub mod substitute;
+pub mod options;
+pub mod search;
+pub mod languages;
+pub mod filetype;
+pub mod syntaxfile;
+pub mod theme;
+pub mod screen;
+#[cfg(feature = "tree-sitter")]
+pub mod treesitter;
 
 fn main() -> crossterm::Result<()> {
     let _clean_up = cleanup::CleanUp;
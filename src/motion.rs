@@ -0,0 +1,591 @@
+use std::cmp;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::editorrows;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Motion {
+    Left,
+    Down,
+    Up,
+    Right,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    /* the flag selects WORDs (`W`, `B`, `E`, `gE`) over words */
+    WordForward(bool),
+    WordBackward(bool),
+    WordEnd(bool),
+    WordEndBackward(bool),
+    FirstLine,
+    LastLine,
+    ParagraphForward,
+    ParagraphBackward,
+    SentenceForward,
+    SentenceBackward,
+    ScreenTop,
+    ScreenMiddle,
+    ScreenBottom,
+    MatchPair,
+}
+
+impl Motion {
+    /* `pending_g` is set when the key follows a `g` prefix. */
+    pub fn from_char(ch: char, pending_g: bool) -> Option<Self> {
+        if pending_g {
+            return Some(match ch {
+                'g' => Motion::FirstLine,
+                'e' => Motion::WordEndBackward(false),
+                'E' => Motion::WordEndBackward(true),
+                _ => return None,
+            });
+        }
+        Some(match ch {
+            'h' => Motion::Left,
+            'j' => Motion::Down,
+            'k' => Motion::Up,
+            'l' => Motion::Right,
+            '0' => Motion::LineStart,
+            '^' => Motion::FirstNonBlank,
+            '$' => Motion::LineEnd,
+            'w' => Motion::WordForward(false),
+            'W' => Motion::WordForward(true),
+            'b' => Motion::WordBackward(false),
+            'B' => Motion::WordBackward(true),
+            'e' => Motion::WordEnd(false),
+            'E' => Motion::WordEnd(true),
+            'G' => Motion::LastLine,
+            '}' => Motion::ParagraphForward,
+            '{' => Motion::ParagraphBackward,
+            ')' => Motion::SentenceForward,
+            '(' => Motion::SentenceBackward,
+            'H' => Motion::ScreenTop,
+            'M' => Motion::ScreenMiddle,
+            'L' => Motion::ScreenBottom,
+            '%' => Motion::MatchPair,
+            _ => return None,
+        })
+    }
+
+    pub fn is_linewise(&self) -> bool {
+        matches!(
+            self,
+            Motion::Up
+                | Motion::Down
+                | Motion::FirstLine
+                | Motion::LastLine
+                | Motion::ScreenTop
+                | Motion::ScreenMiddle
+                | Motion::ScreenBottom
+        )
+    }
+
+    /* Inclusive motions take the grapheme under the target into the range. */
+    pub fn is_inclusive(&self) -> bool {
+        matches!(
+            self,
+            Motion::WordEnd(_) | Motion::WordEndBackward(_) | Motion::MatchPair
+        )
+    }
+}
+
+/* The span an operator works on. `end` is exclusive for charwise ranges;
+ * linewise ranges cover whole rows `start.0..=end.0`. */
+#[derive(Copy, Clone)]
+pub struct MotionRange {
+    pub start    : (usize, usize),
+    pub end      : (usize, usize),
+    pub linewise : bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum CharClass {
+    Blank,
+    Punctuation,
+    Word,
+}
+
+/* Vim's word classes: blanks, keyword characters and everything else.
+ * For WORDs anything that is not blank is part of the same class. */
+pub fn char_class(grapheme: &str, bigword: bool) -> CharClass {
+    let ch = grapheme.chars().next().unwrap_or(' ');
+    if ch.is_whitespace() {
+        CharClass::Blank
+    } else if bigword || ch.is_alphanumeric() || ch == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+/* Walks the buffer one grapheme at a time, stepping over row boundaries.
+ * An empty row is a single position without a class. */
+pub struct Scanner<'a> {
+    editor_rows : &'a editorrows::EditorRows,
+    bigword     : bool,
+    classes     : Vec<CharClass>,
+    pub y       : usize,
+    pub x       : usize,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(editor_rows: &'a editorrows::EditorRows, (y, x): (usize, usize), bigword: bool) -> Self {
+        let mut scanner = Self {
+            editor_rows,
+            bigword,
+            classes: Vec::new(),
+            y,
+            x,
+        };
+        scanner.load_row();
+        scanner
+    }
+
+    fn load_row(&mut self) {
+        self.classes = if self.y < self.editor_rows.number_of_rows() {
+            self.editor_rows
+                .get_row(self.y)
+                .graphemes(true)
+                .map(|g| char_class(g, self.bigword))
+                .collect()
+        } else {
+            Vec::new()
+        };
+    }
+
+    pub fn pos(&self) -> (usize, usize) {
+        (self.y, self.x)
+    }
+
+    pub fn row_len(&self) -> usize {
+        self.classes.len()
+    }
+
+    pub fn class(&self) -> Option<CharClass> {
+        self.classes.get(self.x).copied()
+    }
+
+    pub fn forward(&mut self) -> bool {
+        if self.x + 1 < self.classes.len() {
+            self.x += 1;
+            return true;
+        }
+        if self.y + 1 >= self.editor_rows.number_of_rows() {
+            return false;
+        }
+        self.y += 1;
+        self.x = 0;
+        self.load_row();
+        true
+    }
+
+    pub fn backward(&mut self) -> bool {
+        if self.x > 0 {
+            self.x = cmp::min(self.x - 1, self.classes.len().saturating_sub(1));
+            return true;
+        }
+        if self.y == 0 {
+            return false;
+        }
+        self.y -= 1;
+        self.load_row();
+        self.x = self.classes.len().saturating_sub(1);
+        true
+    }
+}
+
+/* `w`: start of the next word, an empty row also counts as a word. Running
+ * off the end of the buffer lands just past the last grapheme. */
+pub fn word_forward(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    bigword: bool,
+) -> Option<(usize, usize)> {
+    let mut scanner = Scanner::new(editor_rows, from, bigword);
+    let end = |scanner: &Scanner| (scanner.y, scanner.row_len());
+    match scanner.class() {
+        Some(class) if class != CharClass::Blank => loop {
+            if !scanner.forward() {
+                return (end(&scanner) != from).then(|| end(&scanner));
+            }
+            if scanner.y != from.0 || scanner.class() != Some(class) {
+                break;
+            }
+        },
+        _ => {
+            if !scanner.forward() {
+                return (end(&scanner) != from).then(|| end(&scanner));
+            }
+        }
+    }
+    loop {
+        match scanner.class() {
+            Some(CharClass::Blank) => {}
+            None if scanner.y != from.0 => return Some(scanner.pos()),
+            None => {}
+            Some(_) => return Some(scanner.pos()),
+        }
+        if !scanner.forward() {
+            return Some(end(&scanner));
+        }
+    }
+}
+
+/* `b`: start of the current or previous word. */
+pub fn word_backward(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    bigword: bool,
+) -> Option<(usize, usize)> {
+    let mut scanner = Scanner::new(editor_rows, from, bigword);
+    if !scanner.backward() {
+        return None;
+    }
+    while scanner.class() == Some(CharClass::Blank) {
+        if !scanner.backward() {
+            return Some(scanner.pos());
+        }
+    }
+    let class = match scanner.class() {
+        None => return Some(scanner.pos()),
+        Some(class) => class,
+    };
+    loop {
+        let previous = scanner.pos();
+        if !scanner.backward() || scanner.y != previous.0 || scanner.class() != Some(class) {
+            return Some(previous);
+        }
+    }
+}
+
+/* `e`: last grapheme of the current or next word. */
+pub fn word_end(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    bigword: bool,
+) -> Option<(usize, usize)> {
+    let mut scanner = Scanner::new(editor_rows, from, bigword);
+    if !scanner.forward() {
+        return None;
+    }
+    while matches!(scanner.class(), None | Some(CharClass::Blank)) {
+        if !scanner.forward() {
+            return None;
+        }
+    }
+    let class = scanner.class();
+    loop {
+        let previous = scanner.pos();
+        if !scanner.forward() || scanner.y != previous.0 || scanner.class() != class {
+            return Some(previous);
+        }
+    }
+}
+
+/* Last grapheme of the word under `from`, `None` when on a blank. */
+pub fn current_word_end(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    bigword: bool,
+) -> Option<(usize, usize)> {
+    let mut scanner = Scanner::new(editor_rows, from, bigword);
+    let class = scanner.class().filter(|&class| class != CharClass::Blank)?;
+    loop {
+        let previous = scanner.pos();
+        if !scanner.forward() || scanner.y != previous.0 || scanner.class() != Some(class) {
+            return Some(previous);
+        }
+    }
+}
+
+/* `ge`: last grapheme of the previous word, an empty row counts as a word. */
+pub fn word_end_backward(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    bigword: bool,
+) -> Option<(usize, usize)> {
+    let mut scanner = Scanner::new(editor_rows, from, bigword);
+    let class = scanner.class().filter(|&class| class != CharClass::Blank);
+    if !scanner.backward() {
+        return None;
+    }
+    if class.is_some() {
+        while scanner.y == from.0 && scanner.class() == class {
+            if !scanner.backward() {
+                return Some(scanner.pos());
+            }
+        }
+    }
+    while scanner.class() == Some(CharClass::Blank) {
+        if !scanner.backward() {
+            return Some(scanner.pos());
+        }
+    }
+    Some(scanner.pos())
+}
+
+pub fn is_blank_row(editor_rows: &editorrows::EditorRows, y: usize) -> bool {
+    editor_rows.row_len(y) == 0
+}
+
+/* `}`: the next empty row after the current paragraph, or the end of the
+ * last row. */
+pub fn paragraph_forward(editor_rows: &editorrows::EditorRows, from: (usize, usize)) -> Option<(usize, usize)> {
+    let number_of_rows = editor_rows.number_of_rows();
+    let last_row = number_of_rows.checked_sub(1)?;
+    let mut y = from.0;
+    while y < number_of_rows && is_blank_row(editor_rows, y) {
+        y += 1;
+    }
+    while y < number_of_rows && !is_blank_row(editor_rows, y) {
+        y += 1;
+    }
+    let target = if y < number_of_rows {
+        (y, 0)
+    } else {
+        (last_row, editor_rows.row_len(last_row))
+    };
+    (target != from).then_some(target)
+}
+
+/* `{`: the empty row before the current paragraph, or the buffer start. */
+pub fn paragraph_backward(editor_rows: &editorrows::EditorRows, from: (usize, usize)) -> Option<(usize, usize)> {
+    let mut y = cmp::min(from.0, editor_rows.number_of_rows().checked_sub(1)?);
+    while y > 0 && is_blank_row(editor_rows, y) {
+        y -= 1;
+    }
+    while y > 0 && !is_blank_row(editor_rows, y) {
+        y -= 1;
+    }
+    let target = (y, 0);
+    (target != from).then_some(target)
+}
+
+/* Calls `f` with every sentence start from row `first_row` on, until it
+ * returns false. A sentence ends at `.`, `!` or `?`, optionally followed by
+ * closing `)`, `]`, `"` or `'`, and then a blank or the end of the row.
+ * Empty rows are sentence (and paragraph) boundaries of their own. */
+pub fn for_each_sentence_start(
+    editor_rows: &editorrows::EditorRows,
+    first_row: usize,
+    mut f: impl FnMut((usize, usize)) -> bool,
+) {
+    let is_blank = |g: &str| g.chars().all(char::is_whitespace);
+    let mut expect_start = true;
+    for y in first_row..editor_rows.number_of_rows() {
+        let content = editor_rows.get_row(y);
+        let row: Vec<&str> = content.graphemes(true).collect();
+        if row.is_empty() {
+            if !f((y, 0)) {
+                return;
+            }
+            expect_start = true;
+            continue;
+        }
+        let mut x = 0;
+        while x < row.len() {
+            if expect_start {
+                if !is_blank(row[x]) {
+                    if !f((y, x)) {
+                        return;
+                    }
+                    expect_start = false;
+                }
+                x += 1;
+                continue;
+            }
+            if matches!(row[x], "." | "!" | "?") {
+                let mut end = x + 1;
+                while end < row.len() && matches!(row[end], ")" | "]" | "\"" | "'") {
+                    end += 1;
+                }
+                if end == row.len() || is_blank(row[end]) {
+                    expect_start = true;
+                    x = end;
+                    continue;
+                }
+            }
+            x += 1;
+        }
+    }
+}
+
+/* `)`: start of the next sentence. */
+pub fn sentence_forward(editor_rows: &editorrows::EditorRows, from: (usize, usize)) -> Option<(usize, usize)> {
+    let mut first_row = from.0;
+    while first_row > 0 && !is_blank_row(editor_rows, first_row - 1) {
+        first_row -= 1;
+    }
+    let mut target = None;
+    for_each_sentence_start(editor_rows, first_row, |start| {
+        if start > from {
+            target = Some(start);
+        }
+        target.is_none()
+    });
+    target.or_else(|| {
+        let last_row = editor_rows.number_of_rows().checked_sub(1)?;
+        let end = (last_row, editor_rows.row_len(last_row));
+        (end > from).then_some(end)
+    })
+}
+
+/* `(`: start of the current sentence, or of the previous one when already
+ * at a start. */
+pub fn sentence_backward(editor_rows: &editorrows::EditorRows, from: (usize, usize)) -> Option<(usize, usize)> {
+    let mut first_row = cmp::min(from.0, editor_rows.number_of_rows().checked_sub(1)?);
+    loop {
+        while first_row > 0 && !is_blank_row(editor_rows, first_row - 1) {
+            first_row -= 1;
+        }
+        let mut target = None;
+        for_each_sentence_start(editor_rows, first_row, |start| {
+            if start < from {
+                target = Some(start);
+            }
+            start < from
+        });
+        if target.is_some() || first_row == 0 {
+            return target;
+        }
+        /* the empty row above the paragraph is where the search resumes */
+        first_row -= 1;
+    }
+}
+
+/* `%`: the bracket matching the first one at or after the cursor on its row. */
+pub fn match_pair(editor_rows: &editorrows::EditorRows, from: (usize, usize)) -> Option<(usize, usize)> {
+    const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+    let buffer = &editor_rows.buffer;
+    let row_end = editor_rows.char_index(from.0, editor_rows.row_len(from.0));
+    let mut index = editor_rows.char_index(from.0, from.1);
+    let bracket = loop {
+        if index >= row_end {
+            return None;
+        }
+        let ch = buffer.char(index);
+        if PAIRS.iter().any(|&(open, close)| ch == open || ch == close) {
+            break ch;
+        }
+        index += 1;
+    };
+
+    let mut depth = 0usize;
+    if let Some(&(open, close)) = PAIRS.iter().find(|&&(open, _)| open == bracket) {
+        for (offset, ch) in buffer.chars_at(index).enumerate() {
+            if ch == open {
+                depth += 1;
+            } else if ch == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(editor_rows.position(index + offset));
+                }
+            }
+        }
+    } else {
+        let &(open, close) = PAIRS.iter().find(|&&(_, close)| close == bracket)?;
+        let mut chars = buffer.chars_at(index + 1);
+        let mut at = index + 1;
+        while let Some(ch) = chars.prev() {
+            at -= 1;
+            if ch == close {
+                depth += 1;
+            } else if ch == open {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(editor_rows.position(at));
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    fn rows(text: &str) -> editorrows::EditorRows {
+        editorrows::EditorRows::from_rope(Rope::from_str(text))
+    }
+
+    /* Every position `motion` visits from `from` until it stops moving. */
+    fn walk(
+        editor_rows: &editorrows::EditorRows,
+        mut from: (usize, usize),
+        motion: impl Fn(&editorrows::EditorRows, (usize, usize)) -> Option<(usize, usize)>,
+    ) -> Vec<(usize, usize)> {
+        let mut visited = Vec::new();
+        while let Some(to) = motion(editor_rows, from).filter(|&to| to != from) {
+            visited.push(to);
+            from = to;
+        }
+        visited
+    }
+
+    const WORDS: &str = "foo.bar baz\n\nqux\n";
+
+    #[test]
+    fn words_split_at_punctuation_and_empty_rows() {
+        let rows = rows(WORDS);
+        assert_eq!(
+            walk(&rows, (0, 0), |rows, from| word_forward(rows, from, false)),
+            [(0, 3), (0, 4), (0, 8), (1, 0), (2, 0), (2, 3)]
+        );
+        assert_eq!(
+            walk(&rows, (2, 2), |rows, from| word_backward(rows, from, false)),
+            [(2, 0), (1, 0), (0, 8), (0, 4), (0, 3), (0, 0)]
+        );
+        assert_eq!(
+            walk(&rows, (0, 0), |rows, from| word_end(rows, from, false)),
+            [(0, 2), (0, 3), (0, 6), (0, 10), (2, 2)]
+        );
+        assert_eq!(
+            walk(&rows, (2, 0), |rows, from| word_end_backward(rows, from, false)),
+            [(1, 0), (0, 10), (0, 6), (0, 3), (0, 2), (0, 0)]
+        );
+    }
+
+    #[test]
+    fn bigwords_split_only_at_blanks() {
+        let rows = rows(WORDS);
+        assert_eq!(word_forward(&rows, (0, 0), true), Some((0, 8)));
+        assert_eq!(word_backward(&rows, (0, 8), true), Some((0, 0)));
+        assert_eq!(word_end(&rows, (0, 0), true), Some((0, 6)));
+        assert_eq!(current_word_end(&rows, (0, 4), true), Some((0, 6)));
+        assert_eq!(current_word_end(&rows, (0, 7), true), None);
+    }
+
+    #[test]
+    fn paragraphs_end_at_empty_rows() {
+        let rows = rows("a\nb\n\nc\n");
+        assert_eq!(walk(&rows, (0, 0), paragraph_forward), [(2, 0), (3, 1)]);
+        assert_eq!(walk(&rows, (3, 0), paragraph_backward), [(2, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn sentences_end_at_punctuation_before_a_blank() {
+        let rows = rows("One. Two! (Three?) Four\n\nFive.\n");
+        assert_eq!(
+            walk(&rows, (0, 0), sentence_forward),
+            [(0, 5), (0, 10), (0, 19), (1, 0), (2, 0), (2, 5)]
+        );
+        assert_eq!(
+            walk(&rows, (2, 3), sentence_backward),
+            [(2, 0), (1, 0), (0, 19), (0, 10), (0, 5), (0, 0)]
+        );
+        let rows = self::rows("pi is 3.14\n");
+        assert_eq!(sentence_forward(&rows, (0, 0)), Some((0, 10)));
+    }
+
+    #[test]
+    fn brackets_match_their_pair() {
+        let rows = rows("f(a[b]{c})\nx\n");
+        assert_eq!(match_pair(&rows, (0, 0)), Some((0, 9)));
+        assert_eq!(match_pair(&rows, (0, 9)), Some((0, 1)));
+        assert_eq!(match_pair(&rows, (0, 3)), Some((0, 5)));
+        assert_eq!(match_pair(&rows, (1, 0)), None);
+    }
+}
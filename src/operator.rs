@@ -0,0 +1,31 @@
+#[derive(Copy, Clone, PartialEq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    ShiftRight,
+    ShiftLeft,
+}
+
+impl Operator {
+    pub fn from_char(ch: char) -> Option<Self> {
+        Some(match ch {
+            'd' => Operator::Delete,
+            'c' => Operator::Change,
+            'y' => Operator::Yank,
+            '>' => Operator::ShiftRight,
+            '<' => Operator::ShiftLeft,
+            _ => return None,
+        })
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            Operator::Delete => 'd',
+            Operator::Change => 'c',
+            Operator::Yank => 'y',
+            Operator::ShiftRight => '>',
+            Operator::ShiftLeft => '<',
+        }
+    }
+}
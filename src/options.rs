@@ -0,0 +1,68 @@
+/* Settings changed with `:set`. */
+pub struct Options {
+    /* `ignorecase`: patterns match either case */
+    pub ignore_case: bool,
+    /* `smartcase`: unless they have a capital letter in them */
+    pub smart_case: bool,
+    /* `wrapscan`: searches go on from the other end of the buffer */
+    pub wrap_scan: bool,
+    /* `undofile`: writing a file keeps its undo history beside it */
+    pub undo_file: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self {
+            ignore_case: false,
+            smart_case: false,
+            wrap_scan: true,
+            undo_file: false,
+        }
+    }
+
+    /* Whether a search for `pattern` should ignore case. */
+    pub fn ignore_case_for(&self, pattern: &str) -> bool {
+        self.ignore_case && !(self.smart_case && pattern.chars().any(char::is_uppercase))
+    }
+
+    /* The full name and value of option `name`, which may be abbreviated. */
+    fn flag(&mut self, name: &str) -> Option<(&'static str, &mut bool)> {
+        match name {
+            "ignorecase" | "ic" => Some(("ignorecase", &mut self.ignore_case)),
+            "smartcase" | "scs" => Some(("smartcase", &mut self.smart_case)),
+            "wrapscan" | "ws" => Some(("wrapscan", &mut self.wrap_scan)),
+            "undofile" | "udf" => Some(("undofile", &mut self.undo_file)),
+            _ => None,
+        }
+    }
+
+    /* `:set` arguments: `name` turns an option on, `noname` off, `name!`
+     * and `invname` flip it and `name?` shows it. Returns what to show. */
+    pub fn set(&mut self, args: &str) -> Result<Option<String>, String> {
+        let mut shown = Vec::new();
+        /* a bare `:set` shows them all */
+        let args = if args.trim().is_empty() { "ignorecase? smartcase? wrapscan? undofile?" } else { args };
+        for arg in args.split_whitespace() {
+            let unknown = || format!("E518: Unknown option: {}", arg);
+            if let Some(name) = arg.strip_suffix('?') {
+                let (name, value) = self.flag(name).ok_or_else(unknown)?;
+                shown.push(format!("{}{}", if *value { "  " } else { "no" }, name));
+            } else if let Some(name) = arg.strip_suffix('!').or_else(|| arg.strip_prefix("inv")) {
+                let (_, value) = self.flag(name).ok_or_else(unknown)?;
+                *value = !*value;
+            } else if let Some((_, value)) = self.flag(arg) {
+                *value = true;
+            } else {
+                let name = arg.strip_prefix("no").ok_or_else(unknown)?;
+                *self.flag(name).ok_or_else(unknown)?.1 = false;
+            }
+        }
+        Ok((!shown.is_empty()).then(|| shown.join(" ")))
+    }
+}
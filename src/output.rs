@@ -1,57 +1,111 @@
 use crossterm::event::*;
-use crossterm::style::*;
 use crossterm::terminal::ClearType;
-use crossterm::{cursor, execute, queue, terminal, style};
+use crossterm::{cursor, execute, queue, terminal};
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 use std::io::stdout;
 use std::io::Write;
 use std::io;
 use std::cmp;
+use std::time::Instant;
 
 use crate::editorrows;
+use crate::filetype;
+use crate::languages;
+use crate::motion;
+use crate::operator;
+use crate::row;
+use crate::syntax;
+use crate::syntaxfile;
+use crate::screen;
+use crate::theme;
+#[cfg(feature = "tree-sitter")]
+use crate::treesitter;
 use crate::editorcontents;
 use crate::cursorcontroller;
 use crate::status;
 use crate::output;
 use crate::reader;
+use crate::options;
+use crate::search;
 use crate::searchindex;
 use crate::searchdirection;
+use crate::register;
+use crate::selection;
 
 const VERSION: f32 = 0.1;
 
+/* The columns and rows text is drawn in on a terminal `columns` wide and
+ * `rows` high, below which come the status bar and the message line. It
+ * is never empty, so the cursor always has somewhere to scroll into, even
+ * on a terminal too small to show anything. */
+fn text_area(columns: usize, rows: usize) -> (usize, usize) {
+    (cmp::max(columns, 1), cmp::max(rows.saturating_sub(2), 1))
+}
+
+/* Longest prefix of `text` that fits in `width` terminal columns. */
+fn truncate_to_width(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (index, g) in text.grapheme_indices(true) {
+        used += g.width();
+        if used > width {
+            return &text[..index];
+        }
+    }
+    text
+}
+
 pub struct Output {
     pub win_size: (usize, usize),
     pub editor_contents: editorcontents::EditorContents,
     pub cursor_controller: cursorcontroller::CursorController,
+    pub selection: Option<selection::Selection>,
     pub editor_rows: editorrows::EditorRows,
     pub status_message: status::StatusMessage,
     pub dirty: u64,
     pub search_index: searchindex::SearchIndex,
+    pub options: options::Options,
+    pub pending_command: String,
+    /* register a macro is being recorded into */
+    pub recording: Option<char>,
+    /* the `:` line while one is being typed, shown in place of messages */
+    pub command_line: Option<String>,
+    /* where the cursor is among the matches of the last search, as `[3/17]` */
+    pub search_count: Option<String>,
+    /* the match `:s///c` is asking about, from its start up to its end */
+    pub matched: Option<((usize, usize), (usize, usize))>,
+    /* colours rows for the language of the file */
+    pub syntax: Box<dyn syntax::SyntaxHighlight>,
+    /* the colours everything is drawn in */
+    pub theme: theme::Theme,
+    /* what is on the terminal, and the frame being drawn over it */
+    pub screen: screen::Screen,
 }
 
 #[macro_export]
 macro_rules! prompt {
     /* modify */
-    ($output:expr,$args:tt) => {
-        prompt!($output, $args, callback = |&_, _, _| {})
+    ($output:expr, $reader:expr, $args:tt) => {
+        prompt!($output, $reader, $args, callback = |&_, _, _| {})
     };
-    ($output:expr,$args:tt, callback = $callback:expr) => {{
+    ($output:expr, $reader:expr, $args:tt, callback = $callback:expr) => {{
         let output: &mut output::Output = $output;
+        let reader: &mut reader::Reader = $reader;
         let mut input = String::with_capacity(32);
         loop {
             output.status_message.set_message(format!($args, input)); // modify
             output.refresh_screen()?;
-            let key_event = reader::Reader.read_key()?;
+            let key_event = reader.read_key(&mut |columns, rows| output.resize(columns, rows))?;
             match key_event {
                 KeyEvent {
                     code: KeyCode::Enter,
                     modifiers: KeyModifiers::NONE,
-                } => {
-                    if !input.is_empty() {
-                        output.status_message.set_message(String::new());
-                        $callback(output, &input, KeyCode::Enter);
-                        break;
-                    }
+                } if !input.is_empty() => {
+                    output.status_message.set_message(String::new());
+                    $callback(output, &input, KeyCode::Enter);
+                    break;
                 }
                 KeyEvent {
                     code: KeyCode::Esc, ..
@@ -89,121 +143,260 @@ macro_rules! prompt {
     }};
 }
 
+impl Default for Output {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Output {
     pub fn new() -> Self {
-        let win_size = terminal::size()
-            .map(|(x, y)| (x as usize, y as usize - 2))
-            .unwrap();
+        let (columns, rows) = terminal::size().unwrap();
+        let theme = theme::Theme::new(theme::ColorDepth::detect());
+        let mut output = Self::with_size(columns as usize, rows as usize, editorrows::EditorRows::new(), theme);
+        /* a mistake in a syntax definition is said once, at startup */
+        if let Some(report) = syntaxfile::report() {
+            output.status_message.set_message(report);
+        }
+        output
+    }
+
+    /* Output of `editor_rows` in `theme`, on a terminal `columns` wide and
+     * `rows` high. */
+    pub fn with_size(columns: usize, rows: usize, editor_rows: editorrows::EditorRows, theme: theme::Theme) -> Self {
+        let win_size = text_area(columns, rows);
+        let greeting = String::from("HELP: Ctrl-S = Save | Ctrl-W = Quit | Ctrl-F Find");
         Self { 
             win_size,
             editor_contents: editorcontents::EditorContents::new(),
             cursor_controller: cursorcontroller::CursorController::new(win_size),
-            editor_rows: editorrows::EditorRows::new(),
-            status_message: status::StatusMessage::new("HELP: Ctrl-S = Save | Ctrl-W = Quit | Ctrl-F Find".into()),
+            selection: None,
+            syntax: languages::for_file_type(editor_rows.file_type.name.as_deref()),
+            theme,
+            screen: screen::Screen::new(columns, rows),
+            editor_rows,
+            status_message: status::StatusMessage::new(greeting),
             dirty: 0,
             search_index: searchindex::SearchIndex::new(),
+            options: options::Options::new(),
+            pending_command: String::new(),
+            recording: None,
+            command_line: None,
+            matched: None,
+            search_count: None,
         }
     }
 
 
-    pub fn find(&mut self) -> io::Result<()> {
+    /* `/` and `?`: read a pattern, moving to its first match as it is
+     * typed. Returns whether a match was found; Esc puts the cursor back. */
+    pub fn find(
+        &mut self,
+        reader: &mut reader::Reader,
+        direction: searchdirection::SearchDirection,
+    ) -> io::Result<bool> {
         let cursor_controller = self.cursor_controller;
-        if prompt!(
-            self,
-            "Search: {} (Use ESC / Arrows / Enter)",
-            callback = Output::find_callback
-        ).is_none() {
-            self.cursor_controller = cursor_controller;
+        let highlight = self.search_index.highlight.clone();
+        self.search_index
+            .start(direction, (cursor_controller.cursor_y, cursor_controller.cursor_x));
+        let pattern = match direction {
+            searchdirection::SearchDirection::Forward => {
+                prompt!(self, reader, "/{}", callback = Output::find_callback)
+            }
+            searchdirection::SearchDirection::Backward => {
+                prompt!(self, reader, "?{}", callback = Output::find_callback)
+            }
+        };
+        if pattern.is_none() {
+            self.search_index.highlight = highlight;
+            self.search_count = None;
+        }
+        match pattern {
+            Some(pattern) if self.search_index.current.is_some() => {
+                let message = self
+                    .search_index
+                    .note
+                    .take()
+                    .unwrap_or_else(|| format!("{}{}", direction.to_char(), pattern));
+                self.status_message.set_message(message);
+                Ok(true)
+            }
+            _ => {
+                self.cursor_controller = cursor_controller;
+                Ok(false)
+            }
         }
-        Ok(())
     }
 
+    /* Search again from where the prompt opened each time the pattern
+     * changes. Down and Up step to the next and previous match. */
     pub fn find_callback(output: &mut Output, keyword: &str, key_code: KeyCode) {
+        let search_index = &mut output.search_index;
         match key_code {
-            KeyCode::Esc | KeyCode::Enter => {
-                output.search_index.reset();
+            KeyCode::Esc => search_index.current = None,
+            KeyCode::Enter => {
+                search_index.pattern = Some(keyword.to_string());
+                if search_index.current.is_none() {
+                    let message = search_index.note.take().unwrap_or_else(|| {
+                        search::not_found(keyword, search_index.direction, output.options.wrap_scan)
+                    });
+                    output.status_message.set_message(message);
+                }
             }
             _ => {
-                output.search_index.y_direction = None;
-                match key_code {
-                    KeyCode::Down => {
-                        output.search_index.y_direction = searchdirection::SearchDirection::Forward.into()
+                let searcher = match output.searcher(keyword) {
+                    Ok(searcher) if !keyword.is_empty() => searcher,
+                    /* half-typed patterns often fail to compile */
+                    _ => {
+                        (output.cursor_controller.cursor_y, output.cursor_controller.cursor_x) =
+                            output.search_index.origin;
+                        output.search_index.current = None;
+                        output.search_index.highlight = None;
+                        output.search_count = None;
+                        return;
                     }
-                    KeyCode::Up => {
-                        output.search_index.y_direction = searchdirection::SearchDirection::Backward.into()
+                };
+                let search_index = &output.search_index;
+                let stepping = matches!(key_code, KeyCode::Up | KeyCode::Down);
+                let (from, direction) = match (key_code, search_index.current) {
+                    (KeyCode::Down, Some(current)) => (current, searchdirection::SearchDirection::Forward),
+                    (KeyCode::Up, Some(current)) => (current, searchdirection::SearchDirection::Backward),
+                    _ => (search_index.origin, search_index.direction),
+                };
+                let deadline = Instant::now() + search::SEARCH_TIMEOUT;
+                let found = searcher.find(&output.editor_rows, from, direction, output.options.wrap_scan, deadline);
+                let search_index = &mut output.search_index;
+                let wrapped = matches!(found, search::Found::Wrapped(_));
+                search_index.note = wrapped.then(|| search::wrapped(direction).to_string());
+                match found {
+                    search::Found::At(found) | search::Found::Wrapped(found) => {
+                        (output.cursor_controller.cursor_y, output.cursor_controller.cursor_x) = found.start;
+                        search_index.current = Some(found.start);
+                        output.search_count = Some(searcher.count(&output.editor_rows, found.start, deadline));
                     }
-                    _ => {}
-                }
-                for i in 0..output.editor_rows.number_of_rows() {
-                    let row_index = match output.search_index.y_direction.as_ref() {
-                        None => {
-                            output.search_index.y_index = i;
-                            i
-                        }
-                        Some(dir) => {
-                            if matches!(dir, searchdirection::SearchDirection::Forward) {
-                                output.search_index.y_index + i + 1
-                            } else {
-                                let res = output.search_index.y_index.saturating_sub(i);
-                                if res == 0 {
-                                    break;
-                                }
-                                res - 1
-                            }
+                    /* stepping past the last match stays on it */
+                    _ if stepping => {}
+                    other => {
+                        if let search::Found::TimedOut = other {
+                            search_index.note = Some(search::timed_out(keyword));
                         }
-                    };
-                    if row_index > output.editor_rows.number_of_rows() - 1 {
-                        break;
-                    }
-                    let row = output.editor_rows.get_editor_row(row_index);
-                    let index = match output.search_index.x_direction.as_ref() {
-                        None => row.render.find(&keyword),
-                        Some(dir) => {
-                            let index = if matches!(dir, searchdirection::SearchDirection::Forward) {
-                                let start =
-                                    cmp::min(row.render.len(), output.search_index.x_index + 1);
-                                row.render[start..]
-                                    .find(&keyword)
-                                    .map(|index| index + start)
-                            } else {
-                                row.render[..output.search_index.x_index].rfind(&keyword)
-                            };
-                            if index.is_none() {
-                                break;
-                            }
-                            index
-                        }
-                    };
-                    if let Some(index) = index {
-                        output.cursor_controller.cursor_y = row_index;
-                        output.search_index.y_index = row_index;
-                        output.search_index.x_index = index;
-                        output.cursor_controller.cursor_x = row.get_row_content_x(index);
-                        output.cursor_controller.row_offset = output.editor_rows.number_of_rows();
-                        break;
+                        (output.cursor_controller.cursor_y, output.cursor_controller.cursor_x) =
+                            search_index.origin;
+                        search_index.current = None;
+                        output.search_count = None;
                     }
                 }
+                output.search_index.highlight = Some(searcher);
+            }
+        }
+    }
+
+    pub fn searcher(&self, pattern: &str) -> Result<search::Searcher, String> {
+        search::Searcher::new(pattern, self.options.ignore_case_for(pattern))
+    }
+
+    /* Move to the `count`th match of `pattern` going `direction`. */
+    pub fn search(
+        &mut self,
+        pattern: &str,
+        direction: searchdirection::SearchDirection,
+        count: Option<usize>,
+    ) -> bool {
+        let searcher = match self.searcher(pattern) {
+            Ok(searcher) => searcher,
+            Err(message) => {
+                self.status_message.set_message(message);
+                return false;
+            }
+        };
+        let mut from = (self.cursor_controller.cursor_y, self.cursor_controller.cursor_x);
+        let mut wrapped = false;
+        let mut failure = None;
+        let deadline = Instant::now() + search::SEARCH_TIMEOUT;
+        for _ in 0..count.unwrap_or(1) {
+            match searcher.find(&self.editor_rows, from, direction, self.options.wrap_scan, deadline) {
+                search::Found::At(found) => from = found.start,
+                search::Found::Wrapped(found) => {
+                    from = found.start;
+                    wrapped = true;
+                }
+                search::Found::NotFound => {
+                    failure = Some(search::not_found(pattern, direction, self.options.wrap_scan));
+                    break;
+                }
+                search::Found::TimedOut => {
+                    failure = Some(search::timed_out(pattern));
+                    break;
+                }
             }
         }
+        self.search_index.highlight = Some(searcher.clone());
+        if let Some(message) = failure {
+            self.status_message.set_message(message);
+            return false;
+        }
+        (self.cursor_controller.cursor_y, self.cursor_controller.cursor_x) = from;
+        self.status_message.set_message(if wrapped {
+            search::wrapped(direction).to_string()
+        } else {
+            format!("{}{}", direction.to_char(), pattern)
+        });
+        self.search_count = Some(searcher.count(&self.editor_rows, from, deadline));
+        true
+    }
+
+    /* `n`, or `N` when `reverse` is set: the last search again. */
+    pub fn search_next(&mut self, reverse: bool, count: Option<usize>) -> bool {
+        let pattern = match &self.search_index.pattern {
+            Some(pattern) => pattern.clone(),
+            None => {
+                self.status_message
+                    .set_message("E35: No previous regular expression".into());
+                return false;
+            }
+        };
+        let direction = if reverse {
+            self.search_index.direction.reverse()
+        } else {
+            self.search_index.direction
+        };
+        self.search(&pattern, direction, count)
     }
 
     pub fn draw_message_bar(&mut self) {
-        queue!(
-            self.editor_contents,
-            terminal::Clear(ClearType::UntilNewLine)
-        )
-        .unwrap();
-        if let Some(msg) = self.status_message.message() {
-            self.editor_contents
-                .push_str(&msg[..cmp::min(self.win_size.0, msg.len())]);
+        let y = self.win_size.1 + 1;
+        let normal = self.theme.style(theme::Face::Syntax(syntax::HighlightType::Normal));
+        let grid = &mut self.screen.grid;
+        grid.clear_row(y, normal);
+        if let Some(line) = &self.command_line {
+            let line = format!(":{}", line);
+            /* keep the end of a long line, where the typing happens */
+            let skip = line.width().saturating_sub(self.win_size.0.saturating_sub(1));
+            let mut used = 0;
+            let visible = line
+                .grapheme_indices(true)
+                .find(|(_, g)| {
+                    used += g.width();
+                    used > skip
+                })
+                .map_or("", |(index, _)| &line[index..]);
+            grid.put_str(0, y, visible, normal);
+        } else if let Some(msg) = self.status_message.message() {
+            /* `[3/17]` at the right edge after a search */
+            let count = self.search_count.as_ref().map_or(String::new(), |count| format!(" {}", count));
+            let msg = truncate_to_width(msg, self.win_size.0.saturating_sub(count.len()));
+            let x = grid.put_str(0, y, msg, normal);
+            if !count.is_empty() {
+                grid.put_str(cmp::max(x, self.win_size.0.saturating_sub(count.len())), y, &count, normal);
+            }
         }
     }
 
     pub fn draw_status_bar(&mut self) {
-        self.editor_contents
-            .push_str(&style::Attribute::Reverse.to_string());
+        let y = self.win_size.1;
+        let style = self.theme.style(theme::Face::StatusBar);
         let info = format!(
-            "{} {} -- {} lines",
+            "{} {} -- {} lines{}",
             self.editor_rows
                 .filename
                 .as_ref()
@@ -211,27 +404,42 @@ impl Output {
                 .and_then(|name| name.to_str())
                 .unwrap_or("[No Name]"),
             if self.dirty > 0 { "(modified)" } else { "" },
-            self.editor_rows.number_of_rows()
+            self.editor_rows.number_of_rows(),
+            self.recording
+                .map_or(String::new(), |name| format!(" -- recording @{}", name))
         );
-        let info_len = cmp::min(info.len(), self.win_size.0);
+        let info = truncate_to_width(&info, self.win_size.0);
+        let info_len = info.width();
         let line_info = format!(
-            "{}/{}",
+            "{:<8}{} | {}/{}",
+            self.pending_command,
+            self.editor_rows.file_type.name.as_deref().unwrap_or("no ft"),
             self.cursor_controller.cursor_y + 1,
             self.editor_rows.number_of_rows()
         );
-        self.editor_contents.push_str(&info[..info_len]);
-        for i in info_len .. self.win_size.0 {
-            if self.win_size.0 - i == line_info.len() {
-                self.editor_contents.push_str(&line_info);
-                break;
-            } else {
-                self.editor_contents.push(' ');
-            }
-        }  
+        let grid = &mut self.screen.grid;
+        grid.clear_row(y, style);
+        grid.put_str(0, y, info, style);
+        if info_len + line_info.len() <= self.win_size.0 {
+            grid.put_str(self.win_size.0 - line_info.len(), y, &line_info, style);
+        }
+    }
 
-        self.editor_contents
-            .push_str(&style::Attribute::Reset.to_string());
-        self.editor_contents.push_str("\r\n");
+    /* Colour the buffer for its file type. */
+    pub fn update_syntax(&mut self) {
+        self.syntax = languages::for_file_type(self.editor_rows.file_type.name.as_deref());
+        self.editor_rows.states = syntax::States::new();
+        #[cfg(feature = "tree-sitter")]
+        {
+            self.editor_rows.tree = treesitter::SyntaxTree::for_file_type(self.editor_rows.file_type.name.as_deref());
+        }
+    }
+
+    /* Work out the file type again, once the buffer has a new name. */
+    pub fn detect_file_type(&mut self) {
+        let editor_rows = &mut self.editor_rows;
+        editor_rows.file_type = filetype::FileType::detect(editor_rows.filename.as_deref(), &editor_rows.buffer);
+        self.update_syntax();
     }
 
     pub fn clear_screen() -> crossterm::Result<()> {
@@ -241,80 +449,414 @@ impl Output {
 
     pub fn draw_rows(&mut self) {
         let screen_rows = self.win_size.1;
+        let row_offset = self.cursor_controller.row_offset;
+        /* matches that start on screen, or far enough above it to reach it */
+        let visible_matches = self.search_index.highlight.as_ref().map_or(Vec::new(), |searcher| {
+            let first = row_offset.saturating_sub(searcher.rows - 1);
+            let last = cmp::min(row_offset + screen_rows, self.editor_rows.number_of_rows());
+            searcher.matches_in(&self.editor_rows, first..last)
+        });
         let screen_columns = self.win_size.0;
+        let normal = self.theme.style(theme::Face::Syntax(syntax::HighlightType::Normal));
         for i in 0..screen_rows {
             let file_row = i + self.cursor_controller.row_offset;
+            self.screen.grid.clear_row(i, normal);
             if file_row >= self.editor_rows.number_of_rows() {
+                let non_text = self.theme.style(theme::Face::NonText).over(normal);
+                self.screen.grid.put(0, i, "~", non_text);
                 if self.editor_rows.number_of_rows() == 0 && i == screen_rows / 3 {
                     let mut welcome = format!("Rezvan Editor --- Version {}", VERSION);
                     if welcome.len() > screen_columns {
                         welcome.truncate(screen_columns)
                     }
-                    let mut padding = (screen_columns - welcome.len()) / 2;
-                    if padding != 0 {
-                        self.editor_contents.push('~');
-                        padding -= 1
-                    }
-                    (0..padding).for_each(|_| self.editor_contents.push(' '));
-                    self.editor_contents.push_str(&welcome);
-                } else {
-                    self.editor_contents.push('~');
+                    let padding = (screen_columns - welcome.len()) / 2;
+                    self.screen.grid.put_str(padding, i, &welcome, non_text);
                 }
             } else {
-                let row = self.editor_rows.get_render(file_row);
+                let row = self.editor_rows.highlighted_row(&*self.syntax, file_row);
                 let column_offset = self.cursor_controller.column_offset;
-                let len = cmp::min(row.len().saturating_sub(column_offset), screen_columns);
-                let start = if len == 0 { 0 } else { column_offset };
-
-                row[start..start + len].chars().for_each(|c| {
-                    if c.is_digit(10) {
-                        let _ = queue!(self.editor_contents, SetForegroundColor(Color::Yellow));
-                        self.editor_contents.push(c);
-                        let _ = queue!(self.editor_contents, ResetColor);
-                    } 
-                    
-                    else if c.is_digit(16) && row.as_bytes()[0] as char == '#' {
-                        let _ = queue!(self.editor_contents, SetForegroundColor(Color::Red));
-                        self.editor_contents.push(c);
-                        let _ = queue!(self.editor_contents, ResetColor);
+                /* display columns of the selection on this row, and whether
+                 * it takes the line break too */
+                let cursor = (self.cursor_controller.cursor_y, self.cursor_controller.cursor_x);
+                let selected = self
+                    .selection
+                    .and_then(|selection| selection.row_span(cursor, file_row, &row, &self.editor_rows))
+                    .map(|(start, end)| (row.get_render_x(start), row.get_render_x(end), end > row.len()));
+                let matched = self
+                    .matched
+                    .filter(|(start, end)| (start.0..=end.0).contains(&file_row))
+                    .map(|(start, end)| {
+                        let left = if start.0 == file_row { start.1 } else { 0 };
+                        let right = if end.0 == file_row { end.1 } else { row.len() };
+                        /* an empty match still shows up as one column */
+                        (row.get_render_x(left), cmp::max(row.get_render_x(right), row.get_render_x(left) + 1))
+                    });
+                /* display columns of every search match on this row, and
+                 * whether it is the one at the cursor. A match that goes on
+                 * to the next row takes the line break too. */
+                let found: Vec<(usize, usize, bool)> = visible_matches
+                    .iter()
+                    .filter(|found| found.start != found.end)
+                    .filter(|found| found.start.0 <= file_row && (file_row, 0) < found.end)
+                    .map(|found| {
+                        let left = if found.start.0 == file_row { found.start.1 } else { 0 };
+                        let right = if found.end.0 == file_row {
+                            row.get_render_x(found.end.1)
+                        } else {
+                            row.get_render_x(row.len()) + 1
+                        };
+                        (row.get_render_x(left), right, found.start == cursor)
+                    })
+                    .collect();
+                let theme = &self.theme;
+                let search_style = |current: bool| {
+                    theme.style(if current { theme::Face::CurrentSearch } else { theme::Face::Search })
+                };
+                let overlay = |column: usize| {
+                    let search = found
+                        .iter()
+                        .find(|(left, right, _)| (*left..*right).contains(&column))
+                        .map_or(theme::Style::default(), |&(_, _, current)| search_style(current));
+                    let selected = selected.is_some_and(|(left, right, _)| (left..right).contains(&column))
+                        || matched.is_some_and(|(left, right)| (left..right).contains(&column));
+                    if selected {
+                        theme.style(theme::Face::Selection).over(search)
+                    } else {
+                        search
                     }
-
-                    else {
-                        self.editor_contents.push(c);
+                };
+                self.syntax.color_row(
+                    &row,
+                    column_offset..column_offset + screen_columns,
+                    theme,
+                    &overlay,
+                    &mut self.screen.grid,
+                    i,
+                );
+                let line_break = row.get_render_x(row.len());
+                if (column_offset..column_offset + screen_columns).contains(&line_break) {
+                    let style = if selected.is_some_and(|(_, _, line_break)| line_break) {
+                        Some(theme.style(theme::Face::Selection))
+                    } else {
+                        found
+                            .iter()
+                            .find(|(left, right, _)| (*left..*right).contains(&line_break))
+                            .map(|&(_, _, current)| search_style(current))
+                    };
+                    if let Some(style) = style {
+                        self.screen.grid.put(line_break - column_offset, i, " ", style.over(normal));
                     }
-                });
-                //self.editor_contents.push_str(&row[start..start + len])
+                }
             }
-            queue!(
-                self.editor_contents,
-                terminal::Clear(ClearType::UntilNewLine)
-            )
-            .unwrap();
-            //if i < screen_rows - 1 {
-                self.editor_contents.push_str("\r\n");
-            //}
         }
     }
 
+    /* The terminal is now `columns` wide and `rows` high: lay the text,
+     * status bar and message line out again and draw them at once, with
+     * the cursor scrolled back into view. */
+    pub fn resize(&mut self, columns: usize, rows: usize) -> crossterm::Result<()> {
+        self.set_size(columns, rows);
+        self.refresh_screen()
+    }
+
+    fn set_size(&mut self, columns: usize, rows: usize) {
+        self.win_size = text_area(columns, rows);
+        self.cursor_controller.screen_columns = self.win_size.0;
+        self.cursor_controller.screen_rows = self.win_size.1;
+        self.screen.resize(columns, rows);
+    }
+
     pub fn refresh_screen(&mut self) -> crossterm::Result<()> {
+        self.draw()?;
+        self.editor_contents.flush()
+    }
+
+    /* Queue the changes the next frame makes, without writing them out. */
+    fn draw(&mut self) -> crossterm::Result<()> {
         self.cursor_controller.scroll(&self.editor_rows);
-        queue!(self.editor_contents, cursor::Hide, cursor::MoveTo(0, 0))?;
         self.draw_rows();
         self.draw_status_bar();
         self.draw_message_bar();
-        let cursor_x = self.cursor_controller.render_x - self.cursor_controller.column_offset;
-        let cursor_y = self.cursor_controller.cursor_y - self.cursor_controller.row_offset;
+        queue!(self.editor_contents, cursor::Hide)?;
+        self.screen.render(&mut self.editor_contents);
+        let (cursor_x, cursor_y) = match &self.command_line {
+            Some(line) => (
+                cmp::min(line.width() + 1, self.win_size.0.saturating_sub(1)),
+                self.win_size.1 + 1,
+            ),
+            None => (
+                self.cursor_controller.render_x - self.cursor_controller.column_offset,
+                self.cursor_controller.cursor_y - self.cursor_controller.row_offset,
+            ),
+        };
         queue!(
             self.editor_contents,
             cursor::MoveTo(cursor_x as u16, cursor_y as u16),
             cursor::Show
-        )?;
-        self.editor_contents.flush()
+        )
     }
 
-    pub fn move_cursor(&mut self, direction:char) {
+    pub fn move_cursor(&mut self, motion: motion::Motion, count: Option<usize>) -> bool {
         self.cursor_controller
-            .move_cursor(direction, &self.editor_rows);
+            .move_cursor(motion, count, &self.editor_rows)
+    }
+
+    /* Apply `operator` to `range` as one undo step and return the text it
+     * covered. Shifting returns nothing. */
+    pub fn apply_operator(
+        &mut self,
+        operator: operator::Operator,
+        range: motion::MotionRange,
+    ) -> Option<String> {
+        let number_of_rows = self.editor_rows.number_of_rows();
+        if number_of_rows == 0 {
+            return None;
+        }
+        let (start, mut end) = if range.linewise {
+            let end_row = cmp::min(range.end.0 + 1, number_of_rows);
+            (
+                self.editor_rows.buffer.line_to_char(range.start.0),
+                self.editor_rows.buffer.line_to_char(end_row),
+            )
+        } else {
+            (
+                self.editor_rows.char_index(range.start.0, range.start.1),
+                self.editor_rows.char_index(range.end.0, range.end.1),
+            )
+        };
+        let text = self.editor_rows.buffer.slice(start..end).to_string();
+        if range.linewise && operator == operator::Operator::Change {
+            /* changing lines keeps an empty row to type into */
+            end -= 1;
+        }
+
+        self.editor_rows.history.begin_group();
+        match operator {
+            operator::Operator::Delete | operator::Operator::Change => {
+                self.editor_rows.remove_text(start..end);
+                self.dirty += 1;
+            }
+            operator::Operator::Yank => {}
+            operator::Operator::ShiftRight | operator::Operator::ShiftLeft => {
+                for y in range.start.0..=cmp::min(range.end.0, number_of_rows - 1) {
+                    self.shift_row(y, operator == operator::Operator::ShiftRight);
+                }
+                self.dirty += 1;
+            }
+        }
+        self.editor_rows.history.end_group();
+
+        self.cursor_controller.cursor_y = range.start.0;
+        self.cursor_controller.cursor_x = if range.linewise { 0 } else { range.start.1 };
+        if matches!(operator, operator::Operator::ShiftRight | operator::Operator::ShiftLeft) {
+            self.cursor_controller.cursor_x = self.editor_rows.first_non_blank(range.start.0);
+        }
+        if self.cursor_controller.cursor_y >= self.editor_rows.number_of_rows() {
+            self.cursor_controller.cursor_y = self.editor_rows.number_of_rows().saturating_sub(1);
+        }
+        self.cursor_controller.clamp(&self.editor_rows);
+        match operator {
+            operator::Operator::ShiftRight | operator::Operator::ShiftLeft => None,
+            _ => Some(text),
+        }
+    }
+
+    /* Apply a deleting, changing or yanking `operator` to a visual block as
+     * one undo step and return the text it covered on each row. */
+    pub fn apply_block(
+        &mut self,
+        operator: operator::Operator,
+        block: selection::Block,
+    ) -> Option<Vec<String>> {
+        if block.top >= self.editor_rows.number_of_rows() {
+            return None;
+        }
+        let mut texts = Vec::new();
+        self.editor_rows.history.begin_group();
+        for y in block.top..=block.bottom {
+            let row = self.editor_rows.get_editor_row(y);
+            let (start, end) = match block.span(&row) {
+                Some((start, end)) => (
+                    self.editor_rows.char_index(y, start),
+                    self.editor_rows.char_index(y, end),
+                ),
+                None => {
+                    texts.push(String::new());
+                    continue;
+                }
+            };
+            texts.push(self.editor_rows.buffer.slice(start..end).to_string());
+            if operator != operator::Operator::Yank {
+                self.editor_rows.remove_text(start..end);
+                self.dirty += 1;
+            }
+        }
+        self.editor_rows.history.end_group();
+
+        self.cursor_controller.cursor_y = block.top;
+        self.cursor_controller.cursor_x = self
+            .editor_rows
+            .get_editor_row(block.top)
+            .get_row_content_x(block.left);
+        Some(texts)
+    }
+
+    /* Insert `text` on row `y` at display column `column`, padding the row
+     * with spaces first if it ends before that column. */
+    pub fn insert_at_column(&mut self, y: usize, column: usize, text: &str) {
+        let row = self.editor_rows.get_editor_row(y);
+        let width = row.get_render_x(row.len());
+        let mut index = self.editor_rows.char_index(y, row.get_row_content_x(column));
+        if width < column {
+            let padding = " ".repeat(column - width);
+            self.editor_rows.insert_text(index, &padding);
+            index += padding.len();
+            self.dirty += 1;
+        }
+        if !text.is_empty() {
+            self.editor_rows.insert_text(index, text);
+            self.dirty += 1;
+        }
+    }
+
+    /* Put `register` after the cursor (`p`) or before it (`P`), `count`
+     * times over, as one undo step. */
+    pub fn paste(&mut self, register: &register::Register, after: bool, count: usize) {
+        let number_of_rows = self.editor_rows.number_of_rows();
+        let y = self.cursor_controller.cursor_y;
+        self.editor_rows.history.begin_group();
+        match register.kind {
+            register::RegisterKind::Linewise => {
+                let at = cmp::min(if after { y + 1 } else { y }, number_of_rows);
+                let index = self.editor_rows.buffer.line_to_char(at);
+                self.editor_rows.insert_text(index, &register.text.repeat(count));
+                self.cursor_controller.cursor_y = at;
+                self.cursor_controller.cursor_x = self.editor_rows.first_non_blank(at);
+            }
+            register::RegisterKind::Charwise => {
+                if y == number_of_rows {
+                    self.editor_rows.insert_row(number_of_rows, String::new());
+                }
+                let row_len = self.editor_rows.row_len(y);
+                let x = if after && row_len > 0 {
+                    cmp::min(self.cursor_controller.cursor_x + 1, row_len)
+                } else {
+                    self.cursor_controller.cursor_x
+                };
+                let index = self.editor_rows.char_index(y, x);
+                let text = register.text.repeat(count);
+                self.editor_rows.insert_text(index, &text);
+                /* the cursor ends on the last pasted char, or at the start
+                 * of a paste that spans rows */
+                let (cursor_y, cursor_x) = if text.contains('\n') {
+                    self.editor_rows.position(index)
+                } else {
+                    self.editor_rows.position(index + text.chars().count().saturating_sub(1))
+                };
+                self.cursor_controller.cursor_y = cursor_y;
+                self.cursor_controller.cursor_x = cursor_x;
+            }
+            register::RegisterKind::Blockwise => {
+                if y == number_of_rows {
+                    self.editor_rows.insert_row(number_of_rows, String::new());
+                }
+                let row = self.editor_rows.get_editor_row(y);
+                let x = if after && !row.is_empty() {
+                    cmp::min(self.cursor_controller.cursor_x + 1, row.len())
+                } else {
+                    self.cursor_controller.cursor_x
+                };
+                let column = row.get_render_x(x);
+                let width_of = |line: &str| {
+                    let row = row::Row::new(line.into(), String::new());
+                    row.get_render_x(row.len())
+                };
+                let width = register.text.split('\n').map(width_of).max().unwrap_or(0);
+                for (i, line) in register.text.split('\n').enumerate() {
+                    if y + i >= self.editor_rows.number_of_rows() {
+                        self.editor_rows
+                            .insert_row(self.editor_rows.number_of_rows(), String::new());
+                    }
+                    let row = self.editor_rows.get_editor_row(y + i);
+                    let mut text = line.repeat(count);
+                    /* pad short block rows so text after the block stays lined up */
+                    if row.get_render_x(row.len()) > column {
+                        text.push_str(&" ".repeat((width - width_of(line)) * count));
+                    }
+                    self.insert_at_column(y + i, column, &text);
+                }
+                self.cursor_controller.cursor_y = y;
+                self.cursor_controller.cursor_x = self
+                    .editor_rows
+                    .get_editor_row(y)
+                    .get_row_content_x(column);
+            }
+        }
+        self.editor_rows.history.end_group();
+        self.dirty += 1;
+    }
+
+    /* Show `lines` in place of the text, a screenful at a time, until a
+     * key is pressed. */
+    pub fn show_lines(
+        &mut self,
+        reader: &mut reader::Reader,
+        lines: &[String],
+    ) -> crossterm::Result<()> {
+        let page = self.win_size.1 + 1;
+        let pages = lines.chunks(page).count();
+        for (index, chunk) in lines.chunks(page).enumerate() {
+            let last = index + 1 == pages;
+            self.draw_page(chunk, last)?;
+            let key = reader.read_key(&mut |columns, rows| {
+                self.set_size(columns, rows);
+                self.draw_page(chunk, last)
+            })?;
+            if !last && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /* A page of `show_lines`, with what to press under it. */
+    fn draw_page(&mut self, chunk: &[String], last: bool) -> crossterm::Result<()> {
+        let normal = self.theme.style(theme::Face::Syntax(syntax::HighlightType::Normal));
+        let bottom = self.win_size.1 + 1;
+        let grid = &mut self.screen.grid;
+        for i in 0..bottom {
+            let line = chunk.get(i).map_or("", |line| line.as_str());
+            grid.clear_row(i, normal);
+            grid.put_str(0, i, line, normal);
+        }
+        grid.clear_row(bottom, normal);
+        grid.put_str(0, bottom, if last { "Press any key to continue" } else { "-- More -- (q to stop)" }, normal);
+        queue!(self.editor_contents, cursor::Hide)?;
+        self.screen.render(&mut self.editor_contents);
+        self.editor_contents.flush()
+    }
+
+    /* `>` adds a shift width of indent in front of a non-empty row, `<`
+     * takes away one tab or up to a shift width of spaces. */
+    fn shift_row(&mut self, y: usize, right: bool) {
+        let row = self.editor_rows.get_row(y);
+        let start = self.editor_rows.buffer.line_to_char(y);
+        if right {
+            if !row.is_empty() {
+                let indent = self.editor_rows.file_type.indent();
+                self.editor_rows.insert_text(start, &indent);
+            }
+        } else {
+            let indent = if row.starts_with('\t') {
+                1
+            } else {
+                let shift_width = self.editor_rows.file_type.shift_width;
+                row.chars().take(shift_width).take_while(|&c| c == ' ').count()
+            };
+            if indent > 0 {
+                self.editor_rows.remove_text(start..start + indent);
+            }
+        }
     }
 
     pub fn move_cursor_arrows(&mut self, direction:KeyCode) {
@@ -322,36 +864,137 @@ impl Output {
             .move_cursor_arrows(direction, &self.editor_rows);
     }
 
+    /* Run an undo-tree step and put the cursor where the change happened. */
+    pub fn history_step(
+        &mut self,
+        step: fn(&mut editorrows::EditorRows) -> Option<(usize, usize)>,
+        limit: &str,
+    ) {
+        match step(&mut self.editor_rows) {
+            Some((y, x)) => {
+                self.cursor_controller.cursor_y = y;
+                self.cursor_controller.cursor_x = x;
+                /* undoing back to what was written leaves nothing to save */
+                if self.editor_rows.history.is_saved() {
+                    self.dirty = 0;
+                } else {
+                    self.dirty += 1;
+                }
+            }
+            None => self.status_message.set_message(limit.into()),
+        }
+    }
+
     pub fn insert_char(&mut self, ch: char) {
         if self.cursor_controller.cursor_y == self.editor_rows.number_of_rows() {
             self.editor_rows.insert_row(self.editor_rows.number_of_rows(), String::new());
             self.dirty += 1;
         }
-        self.editor_rows
-            .get_editor_row_mut(self.cursor_controller.cursor_y)
-            .insert_char(self.cursor_controller.cursor_x, ch);
-        self .cursor_controller.cursor_x += 1;
+        let char_x = self
+            .editor_rows
+            .get_editor_row(self.cursor_controller.cursor_y)
+            .grapheme_to_char(self.cursor_controller.cursor_x);
+        self.editor_rows.insert_char(
+            self.cursor_controller.cursor_y,
+            self.cursor_controller.cursor_x,
+            ch,
+        );
+        /* a combining mark joins the previous grapheme instead of adding one */
+        self.cursor_controller.cursor_x = self
+            .editor_rows
+            .get_editor_row(self.cursor_controller.cursor_y)
+            .char_to_grapheme(char_x + 1);
         self.dirty += 1;
     }
 
+    /* Type `text` at the cursor as if it came from the keyboard. */
+    pub fn insert_text(&mut self, text: &str) {
+        text.chars().for_each(|ch| {
+            if ch == '\n' {
+                self.inser_newline()
+            } else {
+                self.insert_char(ch)
+            }
+        });
+    }
+
+    /* The start of a new row after row `y` when that one is a line
+     * comment: its indent and comment leader, as with Vim's 'formatoptions'
+     * `r` and `o`. Only a break after the leader carries it on. */
+    fn comment_leader(&self, y: usize, x: Option<usize>) -> String {
+        let leader = match self.syntax.line_comment() {
+            Some(leader) if y < self.editor_rows.number_of_rows() => leader,
+            _ => return String::new(),
+        };
+        let row = self.editor_rows.get_row(y);
+        let code = row.trim_start();
+        /* a `#!` line is not a comment */
+        if !code.starts_with(leader) || (y == 0 && row.starts_with("#!")) {
+            return String::new();
+        }
+        /* `///` and `//!` carry on as themselves */
+        let marks = code[leader.len()..]
+            .find(|ch: char| !leader.contains(ch) && ch != '!')
+            .map_or(code.len(), |end| leader.len() + end);
+        let space = code[marks..].chars().take_while(|ch| *ch == ' ').count().min(1);
+        let leader = &row[..row.len() - code.len() + marks + space];
+        if x.is_some_and(|x| x < leader.graphemes(true).count()) {
+            return String::new();
+        }
+        leader.to_string()
+    }
+
+    /* What Tab types: a tab, or for a file indented with spaces enough of
+     * them to reach the next shift width. */
+    pub fn tab_text(&self) -> String {
+        let file_type = &self.editor_rows.file_type;
+        if !file_type.expand_tab {
+            return String::from("\t");
+        }
+        let y = self.cursor_controller.cursor_y;
+        let column = if y < self.editor_rows.number_of_rows() {
+            self.editor_rows.get_editor_row(y).get_render_x(self.cursor_controller.cursor_x)
+        } else {
+            0
+        };
+        " ".repeat(file_type.shift_width - column % file_type.shift_width)
+    }
+
     pub fn inser_newline(&mut self) {
-        if self.cursor_controller.cursor_x == 0 {
+        let leader = self.comment_leader(self.cursor_controller.cursor_y, Some(self.cursor_controller.cursor_x));
+        if self.cursor_controller.cursor_x == 0
+            || self.cursor_controller.cursor_y == self.editor_rows.number_of_rows()
+        {
             self.editor_rows
                 .insert_row(self.cursor_controller.cursor_y, String::new())
         } else {
-            let current_row = self
-                .editor_rows
-                .get_editor_row_mut(self.cursor_controller.cursor_y);
-            let new_row_content = current_row.row_content[self.cursor_controller.cursor_x..].into();
-            current_row
-                .row_content
-                .truncate(self.cursor_controller.cursor_x);
-            editorrows::EditorRows::render_row(current_row);
             self.editor_rows
-                .insert_row(self.cursor_controller.cursor_y + 1, new_row_content);
+                .split_row(self.cursor_controller.cursor_y, self.cursor_controller.cursor_x);
+        }
+        self.cursor_controller.cursor_x = 0;
+        self.cursor_controller.cursor_y += 1;
+        self.dirty += 1;
+        if !leader.is_empty() {
+            let start = self.editor_rows.buffer.line_to_char(self.cursor_controller.cursor_y);
+            self.editor_rows.insert_text(start, &leader);
+            self.cursor_controller.cursor_x = leader.graphemes(true).count();
         }
     }
 
+    /* `o` and `O`: a new row below or above the cursor's, empty unless
+     * it goes on with a comment. */
+    pub fn open_row(&mut self, below: bool) {
+        let y = cmp::min(
+            self.cursor_controller.cursor_y + below as usize,
+            self.editor_rows.number_of_rows(),
+        );
+        let leader = self.comment_leader(self.cursor_controller.cursor_y, None);
+        self.cursor_controller.cursor_x = leader.graphemes(true).count();
+        self.editor_rows.insert_row(y, leader);
+        self.cursor_controller.cursor_y = y;
+        self.dirty += 1;
+    }
+
     pub fn delete_char(&mut self) {
         if self.cursor_controller.cursor_y == self.editor_rows.number_of_rows() {
             return;
@@ -359,22 +1002,43 @@ impl Output {
         if self.cursor_controller.cursor_y == 0 && self.cursor_controller.cursor_x == 0 {
             return;
         }
-        let row = self
-            .editor_rows
-            .get_editor_row_mut(self.cursor_controller.cursor_y);
         if self.cursor_controller.cursor_x > 0 {
-            row.delete_char(self.cursor_controller.cursor_x - 1);
+            self.editor_rows.delete_char(
+                self.cursor_controller.cursor_y,
+                self.cursor_controller.cursor_x - 1,
+            );
             self.cursor_controller.cursor_x -= 1;
         }
         else {
-            let previous_row_content = self
+            self.cursor_controller.cursor_x = self
                 .editor_rows
-                .get_row(self.cursor_controller.cursor_y - 1);
-            self.cursor_controller.cursor_x = previous_row_content.len();
+                .row_len(self.cursor_controller.cursor_y - 1);
             self.editor_rows
                 .join_adjacent_rows(self.cursor_controller.cursor_y);
             self.cursor_controller.cursor_y -= 1;
         }
         self.dirty += 1;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_down_to_a_single_cell() {
+        let editor_rows = editorrows::EditorRows::from_rope(ropey::Rope::from_str(&"some text\n".repeat(30)));
+        let theme = theme::Theme::built_in("default", theme::ColorDepth::TrueColor);
+        let mut output = Output::with_size(60, 10, editor_rows, theme);
+        output.cursor_controller.cursor_y = 20;
+        output.cursor_controller.cursor_x = 5;
+        output.draw().unwrap();
+        for (columns, rows) in [(40, 5), (3, 3), (2, 2), (1, 1), (0, 0), (60, 10)] {
+            output.set_size(columns, rows);
+            output.draw().unwrap();
+            let cursor = output.cursor_controller;
+            assert!(cursor.cursor_y - cursor.row_offset < output.win_size.1);
+            assert!(cursor.render_x - cursor.column_offset < output.win_size.0);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use regex::{Regex, RegexBuilder};
+
+/* Rows a match may reach over when a `\n` in the pattern repeats. */
+const MAX_MATCH_ROWS: usize = 50;
+
+/* Patterns are written the way Vim reads them with 'magic' set: `\(\)`
+ * group, `\|` alternates, `\+`, `\?`, `\=` and `\{n,m}` repeat, `\<` and
+ * `\>` match at word edges, while `(`, `|`, `+`, `?` and `{` on their own
+ * are plain characters. `\c` anywhere ignores case, `\C` respects it. */
+pub fn compile(pattern: &str, ignore_case: bool) -> Result<Regex, String> {
+    let mut translated = String::with_capacity(pattern.len() * 2);
+    let mut ignore_case = ignore_case;
+    let mut chars = pattern.chars().peekable();
+    let mut in_braces = false;
+    let mut lazy_braces = false;
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some('(') => translated.push('('),
+                Some(')') => translated.push(')'),
+                Some('|') => translated.push('|'),
+                Some('+') => translated.push('+'),
+                Some('?' | '=') => translated.push('?'),
+                Some('{') => {
+                    /* `\{-n,m}` repeats as few times as it can */
+                    lazy_braces = chars.next_if_eq(&'-').is_some();
+                    translated.push('{');
+                    match chars.peek() {
+                        Some('}' | '\\') => translated.push_str("0,"),
+                        Some(',') => translated.push('0'),
+                        _ => {}
+                    }
+                    in_braces = true;
+                }
+                Some('}') if in_braces => {
+                    close_braces(&mut translated, lazy_braces);
+                    in_braces = false;
+                }
+                Some('<' | '>') => translated.push_str("\\b"),
+                Some('c') => ignore_case = true,
+                Some('C') => ignore_case = false,
+                Some('n') => translated.push_str("\\n"),
+                Some('t') => translated.push_str("\\t"),
+                Some(class @ ('s' | 'S' | 'd' | 'D' | 'w' | 'W')) => {
+                    translated.push('\\');
+                    translated.push(class);
+                }
+                Some(other) => translated.push_str(&regex::escape(&other.to_string())),
+                None => translated.push_str("\\\\"),
+            },
+            '}' if in_braces => {
+                close_braces(&mut translated, lazy_braces);
+                in_braces = false;
+            }
+            '(' | ')' | '|' | '+' | '?' | '{' | '}' => {
+                translated.push('\\');
+                translated.push(ch);
+            }
+            '[' => {
+                /* a collection goes through as it is, up to its `]` */
+                let mut class = String::from("[");
+                let mut closed = false;
+                if chars.peek() == Some(&'^') {
+                    class.push(chars.next().unwrap());
+                }
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    class.push_str("\\]");
+                }
+                for ch in chars.by_ref() {
+                    match ch {
+                        ']' => {
+                            class.push(']');
+                            closed = true;
+                            break;
+                        }
+                        '[' | '&' | '~' => {
+                            class.push('\\');
+                            class.push(ch);
+                        }
+                        _ => class.push(ch),
+                    }
+                }
+                if !closed {
+                    return Err(format!("E769: Missing ] after {}[", pattern));
+                }
+                translated.push_str(&class);
+            }
+            _ => translated.push(ch),
+        }
+    }
+    RegexBuilder::new(&translated)
+        .case_insensitive(ignore_case)
+        .multi_line(true)
+        .build()
+        .map_err(|_| format!("E383: Invalid search string: {}", pattern))
+}
+
+fn close_braces(translated: &mut String, lazy: bool) {
+    translated.push('}');
+    if lazy {
+        translated.push('?');
+    }
+}
+
+/* How many rows a match of `pattern` can cover: one, plus one for each
+ * `\n` in it, or a good many when a `\n` can repeat. */
+pub fn line_span(pattern: &str) -> usize {
+    let mut rows = 1;
+    let mut chars = pattern.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            continue;
+        }
+        if chars.next() != Some('n') {
+            continue;
+        }
+        let repeats = match chars.peek() {
+            Some('*') => true,
+            Some('\\') => {
+                let mut after = chars.clone();
+                after.next();
+                matches!(after.next(), Some('+' | '{'))
+            }
+            _ => false,
+        };
+        if repeats {
+            return MAX_MATCH_ROWS;
+        }
+        rows += 1;
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found<'a>(pattern: &str, text: &'a str) -> Vec<&'a str> {
+        compile(pattern, false)
+            .unwrap()
+            .find_iter(text)
+            .map(|found| found.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn backslashed_operators_are_special() {
+        assert_eq!(found(r"\(ab\)\+", "ababx"), ["abab"]);
+        assert_eq!(found(r"a\|b", "cab"), ["a", "b"]);
+        assert_eq!(found(r"ab\?c", "ac abc"), ["ac", "abc"]);
+        assert_eq!(found(r"ab\=c", "ac"), ["ac"]);
+        assert_eq!(found(r"\<is\>", "this is"), ["is"]);
+    }
+
+    #[test]
+    fn bare_operators_are_plain_characters() {
+        assert_eq!(found("(a|b)+?", "x(a|b)+?"), ["(a|b)+?"]);
+        assert_eq!(found("a{2}", "aa a{2}"), ["a{2}"]);
+    }
+
+    #[test]
+    fn braces_count_repeats() {
+        assert_eq!(found(r"a\{2}", "aaa"), ["aa"]);
+        assert_eq!(found(r"a\{,2}b", "aaab"), ["aab"]);
+        assert_eq!(found(r"a\{2,}", "a aaa"), ["aaa"]);
+        assert_eq!(found(r"a\{-1,}", "aaa"), ["a", "a", "a"]);
+        assert_eq!(found(r"a\{-}b", "ab"), ["ab"]);
+    }
+
+    #[test]
+    fn collections_go_through_as_they_are() {
+        assert_eq!(found("[]a]", "x]a"), ["]", "a"]);
+        assert_eq!(found("[^a-c]", "abcd"), ["d"]);
+        assert_eq!(found("[[&]", "a[&"), ["[", "&"]);
+        assert_eq!(
+            compile("a[bc", false).unwrap_err(),
+            "E769: Missing ] after a[bc["
+        );
+    }
+
+    #[test]
+    fn case_follows_the_flag_unless_the_pattern_says() {
+        assert!(compile("foo", true).unwrap().is_match("FOO"));
+        assert!(!compile("foo", false).unwrap().is_match("FOO"));
+        assert!(compile(r"foo\c", false).unwrap().is_match("FOO"));
+        assert!(!compile(r"\Cfoo", true).unwrap().is_match("FOO"));
+    }
+
+    #[test]
+    fn classes_and_line_breaks_translate() {
+        assert_eq!(found(r"\d\+\s\w", "12 x"), ["12 x"]);
+        assert_eq!(found(r"a\nb", "a\nb"), ["a\nb"]);
+        assert_eq!(found(r"^b$", "a\nb\n"), ["b"]);
+        assert_eq!(found(r"a\.", "ab a."), ["a."]);
+        assert_eq!(
+            compile(r"\(a", false).unwrap_err(),
+            r"E383: Invalid search string: \(a"
+        );
+    }
+
+    #[test]
+    fn each_line_break_adds_a_row() {
+        assert_eq!(line_span("abc"), 1);
+        assert_eq!(line_span(r"a\nb\nc"), 3);
+        assert_eq!(line_span(r"a\n*b"), MAX_MATCH_ROWS);
+        assert_eq!(line_span(r"a\n\+b"), MAX_MATCH_ROWS);
+        assert_eq!(line_span(r"a\\nb"), 1);
+    }
+}
@@ -1,18 +1,119 @@
 use crossterm::event::*;
 use crossterm::{event};
 
+use std::collections::VecDeque;
 use std::time::Duration;
 
-pub struct Reader;
+/* Keys that have no character of their own are kept in macro registers as
+ * private-use characters, in this order from U+E000. */
+const SPECIAL_KEYS: [(KeyCode, &str); 9] = [
+    (KeyCode::Delete, "<Del>"),
+    (KeyCode::Up, "<Up>"),
+    (KeyCode::Down, "<Down>"),
+    (KeyCode::Left, "<Left>"),
+    (KeyCode::Right, "<Right>"),
+    (KeyCode::Home, "<Home>"),
+    (KeyCode::End, "<End>"),
+    (KeyCode::PageUp, "<PageUp>"),
+    (KeyCode::PageDown, "<PageDown>"),
+];
+const SPECIAL_KEY_BASE: u32 = 0xE000;
+
+/* Every key the editor handles comes through here. Keys queued by a macro
+ * are handed out before the terminal is read, and keys read from the
+ * terminal while recording are also kept for the macro register. */
+#[derive(Default)]
+pub struct Reader {
+    pub pending: VecDeque<KeyEvent>,
+    pub recording: Option<(char, Vec<KeyEvent>)>,
+}
 
 impl Reader {
-    pub fn read_key(&self) -> crossterm::Result<KeyEvent> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* The next key. If the terminal changes size while this waits for one,
+     * `on_resize` is told its new columns and rows right away, so that the
+     * screen can be drawn again before the key comes. */
+    pub fn read_key(
+        &mut self,
+        on_resize: &mut dyn FnMut(usize, usize) -> crossterm::Result<()>,
+    ) -> crossterm::Result<KeyEvent> {
+        if let Some(key) = self.pending.pop_front() {
+            return Ok(key);
+        }
         loop {
             if event::poll(Duration::from_millis(500))? {
-                if let Event::Key(event) = event::read()? {
-                    return Ok(event);
+                match event::read()? {
+                    Event::Key(event) => {
+                        if let Some((_, keys)) = &mut self.recording {
+                            keys.push(event);
+                        }
+                        return Ok(event);
+                    }
+                    Event::Resize(columns, rows) => on_resize(columns as usize, rows as usize)?,
+                    _ => {}
                 }
             }
         }
     }
-}
\ No newline at end of file
+
+    /* Queue `keys` ahead of anything already pending, so a macro run from
+     * inside another finishes before the outer one carries on. */
+    pub fn replay(&mut self, keys: &[KeyEvent]) {
+        keys.iter().rev().for_each(|&key| self.pending.push_front(key));
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+/* A key as it is stored in a register, or `None` for keys macros skip. */
+pub fn key_to_char(key: KeyEvent) -> Option<char> {
+    match (key.code, key.modifiers) {
+        (KeyCode::Char(ch), KeyModifiers::NONE | KeyModifiers::SHIFT) => Some(ch),
+        (KeyCode::Char(ch @ 'a'..='z'), KeyModifiers::CONTROL) => {
+            char::from_u32(ch as u32 - 'a' as u32 + 1)
+        }
+        (KeyCode::Enter, _) => Some('\r'),
+        (KeyCode::Tab, _) => Some('\t'),
+        (KeyCode::Esc, _) => Some('\x1b'),
+        (KeyCode::Backspace, _) => Some('\x7f'),
+        (code, KeyModifiers::NONE) => SPECIAL_KEYS
+            .iter()
+            .position(|(special, _)| *special == code)
+            .and_then(|index| char::from_u32(SPECIAL_KEY_BASE + index as u32)),
+        _ => None,
+    }
+}
+
+/* The inverse of `key_to_char`. */
+pub fn char_to_key(ch: char) -> KeyEvent {
+    let code = match ch {
+        '\r' | '\n' => KeyCode::Enter,
+        '\t' => KeyCode::Tab,
+        '\x1b' => KeyCode::Esc,
+        '\x7f' => KeyCode::Backspace,
+        '\x01'..='\x1a' => {
+            let letter = char::from_u32(ch as u32 - 1 + 'a' as u32).unwrap();
+            return KeyEvent::new(KeyCode::Char(letter), KeyModifiers::CONTROL);
+        }
+        _ => match special_key(ch) {
+            Some((code, _)) => code,
+            None => KeyCode::Char(ch),
+        },
+    };
+    KeyEvent::new(code, KeyModifiers::NONE)
+}
+
+fn special_key(ch: char) -> Option<(KeyCode, &'static str)> {
+    let index = (ch as u32).checked_sub(SPECIAL_KEY_BASE)?;
+    SPECIAL_KEYS.get(index as usize).copied()
+}
+
+/* How `:registers` shows a stored special key, like `<Up>`. */
+pub fn key_notation(ch: char) -> Option<&'static str> {
+    special_key(ch).map(|(_, notation)| notation)
+}
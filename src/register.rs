@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+
+use crate::reader;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum RegisterKind {
+    Charwise,
+    Linewise,
+    Blockwise,
+}
+
+impl RegisterKind {
+    pub fn from_char(ch: char) -> Option<Self> {
+        Some(match ch {
+            'c' => RegisterKind::Charwise,
+            'l' => RegisterKind::Linewise,
+            'b' => RegisterKind::Blockwise,
+            _ => return None,
+        })
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            RegisterKind::Charwise => 'c',
+            RegisterKind::Linewise => 'l',
+            RegisterKind::Blockwise => 'b',
+        }
+    }
+}
+
+/* Text stored by a yank or delete. Linewise text ends in a line break and
+ * blockwise text holds one row of the block per line. */
+#[derive(Clone)]
+pub struct Register {
+    pub text: String,
+    pub kind: RegisterKind,
+}
+
+impl Register {
+    pub fn new(text: String, kind: RegisterKind) -> Self {
+        Self { text, kind }
+    }
+
+    /* `"Ayy` after `"ayw`: text that is linewise on either side makes the
+     * whole register linewise. */
+    fn append(&mut self, other: Register) {
+        if self.kind == RegisterKind::Linewise || other.kind == RegisterKind::Linewise {
+            if !self.text.ends_with('\n') {
+                self.text.push('\n');
+            }
+            self.text.push_str(&other.text);
+            if !self.text.ends_with('\n') {
+                self.text.push('\n');
+            }
+            self.kind = RegisterKind::Linewise;
+        } else if self.kind == RegisterKind::Blockwise || other.kind == RegisterKind::Blockwise {
+            self.text.push('\n');
+            self.text.push_str(&other.text);
+            self.kind = RegisterKind::Blockwise;
+        } else {
+            self.text.push_str(&other.text);
+        }
+    }
+}
+
+/* Vim's register set. `"` is the unnamed register every yank and delete
+ * goes through, `0` holds the last yank, `1`-`9` the last deletes that
+ * spanned rows with `-` taking smaller ones, `a`-`z` are filled on request
+ * (their capitals append) and `_` throws the text away. */
+#[derive(Default)]
+pub struct Registers {
+    contents: HashMap<char, Register>,
+}
+
+/* Order `:registers` lists them in. */
+const LISTING_ORDER: &str = "\"0123456789abcdefghijklmnopqrstuvwxyz-";
+
+impl Registers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || matches!(name, '"' | '-' | '_')
+    }
+
+    pub fn get(&self, name: Option<char>) -> Option<&Register> {
+        self.contents.get(&name.unwrap_or('"').to_ascii_lowercase())
+    }
+
+    pub fn insert(&mut self, name: char, register: Register) {
+        self.contents.insert(name, register);
+    }
+
+    /* Write to a register named by the user and point `"` at it. */
+    fn store(&mut self, name: char, register: Register) {
+        let lower = name.to_ascii_lowercase();
+        let register = match self.contents.remove(&lower) {
+            Some(mut existing) if name.is_ascii_uppercase() => {
+                existing.append(register);
+                existing
+            }
+            _ => register,
+        };
+        self.contents.insert('"', register.clone());
+        self.contents.insert(lower, register);
+    }
+
+    /* A recorded macro only goes to the register it was recorded into. */
+    pub fn record(&mut self, name: char, text: String) {
+        let register = Register::new(text, RegisterKind::Charwise);
+        match self.contents.get_mut(&name.to_ascii_lowercase()) {
+            Some(existing) if name.is_ascii_uppercase() => existing.append(register),
+            _ => {
+                self.contents.insert(name.to_ascii_lowercase(), register);
+            }
+        }
+    }
+
+    pub fn yank(&mut self, name: Option<char>, register: Register) {
+        match name {
+            Some('_') => {}
+            None | Some('"') => {
+                self.contents.insert('0', register.clone());
+                self.contents.insert('"', register);
+            }
+            Some(name) => self.store(name, register),
+        }
+    }
+
+    pub fn delete(&mut self, name: Option<char>, register: Register) {
+        match name {
+            Some('_') => {}
+            None | Some('"') => {
+                if register.kind == RegisterKind::Charwise && !register.text.contains('\n') {
+                    self.contents.insert('-', register.clone());
+                } else {
+                    for number in (1..9).rev() {
+                        let digit = |n: u32| char::from_digit(n, 10).unwrap();
+                        if let Some(older) = self.contents.remove(&digit(number)) {
+                            self.contents.insert(digit(number + 1), older);
+                        }
+                    }
+                    self.contents.insert('1', register.clone());
+                }
+                self.contents.insert('"', register);
+            }
+            Some(name) => self.store(name, register),
+        }
+    }
+
+    /* Registers in listing order, limited to `names` unless it is empty. */
+    pub fn iter<'a>(&'a self, names: &'a str) -> impl Iterator<Item = (char, &'a Register)> + 'a {
+        LISTING_ORDER
+            .chars()
+            .filter(move |name| names.is_empty() || names.contains(*name))
+            .filter_map(move |name| self.contents.get(&name).map(|register| (name, register)))
+    }
+
+    /* The `:registers` table, with control characters shown as `^J` and
+     * keys recorded in macros as `<Up>`. */
+    pub fn listing(&self, names: &str) -> Vec<String> {
+        let mut lines = vec![String::from("Type Name Content")];
+        for (name, register) in self.iter(names) {
+            let content: String = register
+                .text
+                .chars()
+                .flat_map(|ch| match ch {
+                    '\x00'..='\x1f' => vec!['^', (ch as u8 + b'@') as char],
+                    '\x7f' => vec!['^', '?'],
+                    _ => match reader::key_notation(ch) {
+                        Some(notation) => notation.chars().collect(),
+                        None => vec![ch],
+                    },
+                })
+                .collect();
+            lines.push(format!("  {}  \"{}   {}", register.kind.to_char(), name, content));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn charwise(text: &str) -> Register {
+        Register::new(text.into(), RegisterKind::Charwise)
+    }
+
+    fn linewise(text: &str) -> Register {
+        Register::new(text.into(), RegisterKind::Linewise)
+    }
+
+    fn text(registers: &Registers, name: char) -> Option<&str> {
+        registers.get(Some(name)).map(|register| register.text.as_str())
+    }
+
+    #[test]
+    fn capitals_append_to_their_register() {
+        let mut registers = Registers::new();
+        registers.yank(Some('a'), charwise("foo"));
+        registers.yank(Some('A'), charwise("bar"));
+        assert_eq!(text(&registers, 'a'), Some("foobar"));
+        assert_eq!(text(&registers, '"'), Some("foobar"));
+        registers.yank(Some('A'), linewise("baz\n"));
+        assert_eq!(text(&registers, 'a'), Some("foobar\nbaz\n"));
+        assert!(registers.get(Some('A')).unwrap().kind == RegisterKind::Linewise);
+        registers.yank(Some('a'), charwise("new"));
+        assert_eq!(text(&registers, 'a'), Some("new"));
+    }
+
+    #[test]
+    fn appending_a_block_keeps_one_row_per_line() {
+        let mut registers = Registers::new();
+        registers.yank(Some('b'), Register::new("ab\ncd".into(), RegisterKind::Blockwise));
+        registers.yank(Some('B'), charwise("ef"));
+        assert_eq!(text(&registers, 'b'), Some("ab\ncd\nef"));
+        assert!(registers.get(Some('b')).unwrap().kind == RegisterKind::Blockwise);
+    }
+
+    #[test]
+    fn deletes_of_rows_shift_the_numbered_registers() {
+        let mut registers = Registers::new();
+        for n in 1..=10 {
+            registers.delete(None, linewise(&format!("{}\n", n)));
+        }
+        assert_eq!(text(&registers, '1'), Some("10\n"));
+        assert_eq!(text(&registers, '9'), Some("2\n"));
+        assert_eq!(registers.iter("123456789").count(), 9);
+
+        registers.delete(None, charwise("word"));
+        assert_eq!(text(&registers, '-'), Some("word"));
+        assert_eq!(text(&registers, '1'), Some("10\n"));
+        assert_eq!(text(&registers, '"'), Some("word"));
+    }
+
+    #[test]
+    fn yanks_fill_register_zero_and_the_black_hole_keeps_nothing() {
+        let mut registers = Registers::new();
+        registers.yank(None, charwise("kept"));
+        registers.delete(None, linewise("gone\n"));
+        assert_eq!(text(&registers, '0'), Some("kept"));
+        registers.delete(Some('_'), linewise("lost\n"));
+        registers.yank(Some('_'), charwise("lost"));
+        assert_eq!(text(&registers, '"'), Some("gone\n"));
+        assert_eq!(text(&registers, '1'), Some("gone\n"));
+    }
+
+    #[test]
+    fn macros_are_recorded_without_touching_the_unnamed_register() {
+        let mut registers = Registers::new();
+        registers.record('q', "dd".into());
+        registers.record('Q', "j".into());
+        assert_eq!(text(&registers, 'q'), Some("ddj"));
+        assert_eq!(text(&registers, '"'), None);
+    }
+
+    #[test]
+    fn the_listing_shows_control_characters() {
+        let mut registers = Registers::new();
+        registers.yank(Some('a'), linewise("x\ty\n"));
+        assert_eq!(
+            registers.listing("a"),
+            ["Type Name Content", "  l  \"a   x^Iy^J"]
+        );
+    }
+}
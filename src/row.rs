@@ -1,11 +1,21 @@
-use crate::editorrows;
+use std::cmp;
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::editorrows::TAB_STOP;
 use crate::syntax;
 
+/* A row materialized out of the `EditorRows` buffer. Edits go through
+ * `EditorRows`; a `Row` is only a view used for rendering and lookups.
+ * Cursor positions into a row are counted in grapheme clusters. */
 #[derive(Default)]
 pub struct Row {
     pub row_content : String,
     pub render      : String,
     pub highlight: Vec<syntax::HighlightType>,
+    /* what the row leaves open for the next one, once highlighted */
+    pub state: syntax::State,
 }
 
 impl Row {
@@ -14,30 +24,134 @@ impl Row {
             row_content,
             render,
             highlight:Vec::new(),
+            state: syntax::State::Normal,
+        }
+    }
+
+    /* Display width of a single grapheme cluster. Zero-width clusters still
+     * take up a cell so that every cursor position stays distinct. */
+    pub fn grapheme_width(grapheme: &str) -> usize {
+        if grapheme == "\t" {
+            return TAB_STOP;
         }
+        cmp::max(grapheme.width(), 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.row_content.graphemes(true).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.row_content.is_empty()
+    }
+
+    pub fn grapheme_to_byte(&self, x: usize) -> usize {
+        self.row_content
+            .grapheme_indices(true)
+            .nth(x)
+            .map_or(self.row_content.len(), |(index, _)| index)
+    }
+
+    pub fn grapheme_to_char(&self, x: usize) -> usize {
+        self.row_content[..self.grapheme_to_byte(x)].chars().count()
+    }
+
+    /* Number of whole graphemes that fit in the first `char_x` chars. */
+    pub fn char_to_grapheme(&self, char_x: usize) -> usize {
+        let byte = self
+            .row_content
+            .char_indices()
+            .nth(char_x)
+            .map_or(self.row_content.len(), |(index, _)| index);
+        self.row_content
+            .grapheme_indices(true)
+            .take_while(|(index, g)| index + g.len() <= byte)
+            .count()
     }
 
-    pub fn insert_char(&mut self, at: usize, ch: char) {
-        self.row_content.insert(at, ch);
-        editorrows::EditorRows::render_row(self)
+    pub fn byte_to_grapheme(&self, byte: usize) -> usize {
+        self.row_content
+            .grapheme_indices(true)
+            .take_while(|(index, _)| *index < byte)
+            .count()
     }
 
-    pub fn delete_char(&mut self, at: usize) {
-        self.row_content.remove(at);
-        editorrows::EditorRows::render_row(self)
+    pub fn get_render_x(&self, x: usize) -> usize {
+        self.row_content
+            .graphemes(true)
+            .take(x)
+            .fold(0, |render_x, g| {
+                if g == "\t" {
+                    render_x + (TAB_STOP - 1) - (render_x % TAB_STOP) + 1
+                } else {
+                    render_x + Self::grapheme_width(g)
+                }
+            })
+    }
+
+    /* Display column of a byte offset into `render`. */
+    pub fn get_render_width(&self, byte: usize) -> usize {
+        self.render[..byte].graphemes(true).map(Self::grapheme_width).sum()
     }
 
     pub fn get_row_content_x(&self, render_x: usize) -> usize {
         let mut current_render_x = 0;
-        for (cursor_x, ch) in self.row_content.chars().enumerate() {
-            if ch == '\t' {
-                current_render_x += (4 - 1) - (current_render_x % 4);
+        for (cursor_x, g) in self.row_content.graphemes(true).enumerate() {
+            if g == "\t" {
+                current_render_x += (TAB_STOP - 1) - (current_render_x % TAB_STOP) + 1;
+            } else {
+                current_render_x += Self::grapheme_width(g);
             }
-            current_render_x += 1;
             if current_render_x > render_x {
                 return cursor_x;
             }
         }
-        0
+        self.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* "e" with a combining acute, a wide CJK character and a tab. */
+    fn row() -> Row {
+        Row::new("ae\u{301}\u{4e2d}\tb".into(), String::new())
+    }
+
+    #[test]
+    fn positions_count_graphemes() {
+        let row = row();
+        assert_eq!(row.len(), 5);
+        assert_eq!(row.grapheme_to_byte(2), 4);
+        assert_eq!(row.grapheme_to_char(2), 3);
+        assert_eq!(row.grapheme_to_byte(9), row.row_content.len());
+        assert_eq!(row.byte_to_grapheme(4), 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn a_char_inside_a_cluster_is_not_a_whole_grapheme() {
+        let row = row();
+        assert_eq!(row.char_to_grapheme(2), 1);
+        assert_eq!(row.char_to_grapheme(3), 2);
+    }
+
+    #[test]
+    fn render_columns_follow_display_width() {
+        let row = row();
+        assert_eq!(row.get_render_x(2), 2);
+        assert_eq!(row.get_render_x(3), 4);
+        assert_eq!(row.get_render_x(4), 8);
+        assert_eq!(row.get_row_content_x(3), 2);
+        assert_eq!(row.get_row_content_x(6), 3);
+        assert_eq!(row.get_row_content_x(8), 4);
+        assert_eq!(row.get_row_content_x(20), 5);
+    }
+
+    #[test]
+    fn zero_width_clusters_take_a_cell() {
+        assert_eq!(Row::grapheme_width("\u{200b}"), 1);
+        assert_eq!(Row::grapheme_width("\u{4e2d}"), 2);
+        assert_eq!(Row::grapheme_width("\t"), TAB_STOP);
+    }
+}
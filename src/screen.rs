@@ -0,0 +1,290 @@
+use crossterm::cursor;
+use crossterm::queue;
+use crossterm::terminal::{self, ClearType};
+use crossterm::style::{Attribute, Color, Colored};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::editorcontents;
+use crate::row;
+use crate::theme;
+
+/* Unchanged cells it is cheaper to write again than to move the cursor
+ * past. */
+const SHORT_GAP: usize = 4;
+
+/* One cell of the terminal: what is drawn in it, and in what style. A
+ * wide grapheme is held by the first of its cells; the ones it covers
+ * after that hold nothing. */
+#[derive(Clone, PartialEq)]
+pub struct Cell {
+    pub symbol: String,
+    pub style: theme::Style,
+}
+
+impl Cell {
+    fn blank(style: theme::Style) -> Self {
+        Self {
+            symbol: String::from(" "),
+            style,
+        }
+    }
+}
+
+/* A frame: every cell of the terminal, a row at a time. */
+#[derive(Clone)]
+pub struct Grid {
+    pub width: usize,
+    pub height: usize,
+    cells: Vec<Cell>,
+}
+
+impl Grid {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::blank(theme::Style::default()); width * height],
+        }
+    }
+
+    /* Blank row `y` in `style`. */
+    pub fn clear_row(&mut self, y: usize, style: theme::Style) {
+        if y < self.height {
+            self.cells[y * self.width..(y + 1) * self.width].fill(Cell::blank(style));
+        }
+    }
+
+    /* Draw grapheme `g` at column `x` of row `y` and return how many
+     * columns it takes. One that would not fit is left out. */
+    pub fn put(&mut self, x: usize, y: usize, g: &str, style: theme::Style) -> usize {
+        let width = row::Row::grapheme_width(g);
+        if y >= self.height || x + width > self.width {
+            return width;
+        }
+        let at = y * self.width + x;
+        let symbol = match g {
+            /* a tab is drawn as the blanks it stands for */
+            "\t" => String::from(" "),
+            /* a zero-width grapheme still takes a cell */
+            g if g.width() == 0 => format!(" {}", g),
+            g => g.to_string(),
+        };
+        let covered = if g == "\t" { Cell::blank(style) } else { Cell { symbol: String::new(), style } };
+        self.cells[at] = Cell { symbol, style };
+        self.cells[at + 1..at + width].fill(covered);
+        width
+    }
+
+    /* Draw `text` from column `x` of row `y` for as much of it as fits,
+     * and return the column after it. */
+    pub fn put_str(&mut self, x: usize, y: usize, text: &str, style: theme::Style) -> usize {
+        let mut x = x;
+        for g in text.graphemes(true) {
+            if x + row::Row::grapheme_width(g) > self.width {
+                break;
+            }
+            x += self.put(x, y, g, style);
+        }
+        x
+    }
+}
+
+/* Write the escape that switches the terminal from style `from`, if it
+ * is known, to `to`, as a single sequence. Only what changed is set,
+ * unless an attribute has to be turned off, which takes a full reset. */
+fn switch_style(from: Option<theme::Style>, to: theme::Style, out: &mut editorcontents::EditorContents) {
+    let color = |color: Option<Color>| color.unwrap_or(Color::Reset);
+    let mut codes = Vec::new();
+    match from {
+        Some(from) if from.attributes == to.attributes => {
+            if from.foreground != to.foreground {
+                codes.push(Colored::ForegroundColor(color(to.foreground)).to_string());
+            }
+            if from.background != to.background {
+                codes.push(Colored::BackgroundColor(color(to.background)).to_string());
+            }
+        }
+        _ => {
+            codes.push(String::from("0"));
+            codes.extend(
+                Attribute::iterator()
+                    .filter(|attribute| *attribute != Attribute::Reset && to.attributes.has(*attribute))
+                    .map(Attribute::sgr),
+            );
+            codes.extend(to.foreground.map(|foreground| Colored::ForegroundColor(foreground).to_string()));
+            codes.extend(to.background.map(|background| Colored::BackgroundColor(background).to_string()));
+        }
+    }
+    if !codes.is_empty() {
+        out.push_str(&format!("\x1b[{}m", codes.join(";")));
+    }
+}
+
+/* The terminal, drawn a frame at a time. Each frame is drawn whole into
+ * `grid`; only the cells that differ from the frame before it are
+ * written out, so a keystroke that changes one row sends one row. */
+pub struct Screen {
+    /* the frame being drawn */
+    pub grid: Grid,
+    /* the frame the terminal shows, when that is known */
+    shown: Option<Grid>,
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            grid: Grid::new(width, height),
+            shown: None,
+        }
+    }
+
+    /* A terminal of a new size, drawn whole next time. */
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.grid = Grid::new(width, height);
+        self.shown = None;
+    }
+
+    /* Write out the cells of `grid` the terminal does not show yet. */
+    pub fn render(&mut self, out: &mut editorcontents::EditorContents) {
+        let width = self.grid.width;
+        /* where the terminal's cursor is, and the style it writes in */
+        let mut position = None;
+        let mut style = None;
+        let mut at = 0;
+        while at < self.grid.cells.len() {
+            let cell = &self.grid.cells[at];
+            let unchanged = self.shown.as_ref().is_some_and(|shown| shown.cells[at] == *cell);
+            if unchanged || cell.symbol.is_empty() {
+                at += 1;
+                continue;
+            }
+            let (x, y) = (at % width, at / width);
+            match position {
+                Some(position) if position == (x, y) => {}
+                /* a few unchanged cells in the same style are written over
+                 * again, which takes less than moving past them */
+                Some((from, row))
+                    if row == y
+                        && x - from <= SHORT_GAP
+                        && self.grid.cells[at - (x - from)..at]
+                            .iter()
+                            .all(|skipped| Some(skipped.style) == style && skipped.symbol.len() == 1) =>
+                {
+                    self.grid.cells[at - (x - from)..at].iter().for_each(|skipped| out.push_str(&skipped.symbol));
+                }
+                _ => {
+                    let _ = queue!(out, cursor::MoveTo(x as u16, y as u16));
+                }
+            }
+            if style != Some(cell.style) {
+                switch_style(style, cell.style, out);
+                style = Some(cell.style);
+            }
+            let end_of_row = (y + 1) * width;
+            /* blanks to the end of the row are cleared rather than written */
+            if self.grid.cells[at..end_of_row].iter().all(|other| *other == *cell && cell.symbol == " ") {
+                let _ = queue!(out, terminal::Clear(ClearType::UntilNewLine));
+                position = None;
+                at = end_of_row;
+                continue;
+            }
+            out.push_str(&cell.symbol);
+            let covered = self.grid.cells[at + 1..end_of_row]
+                .iter()
+                .take_while(|cell| cell.symbol.is_empty())
+                .count();
+            /* past the last column the terminal may or may not have
+             * wrapped, so the next cell is moved to */
+            position = (x + 1 + covered < width).then_some((x + 1 + covered, y));
+            at += 1 + covered;
+        }
+        if style.is_some_and(|style| style != theme::Style::default()) {
+            switch_style(None, theme::Style::default(), out);
+        }
+        self.shown = Some(self.grid.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /* What `screen` writes to the terminal for its next frame. Each frame
+     * starts from a reset, as the style the terminal was left in is not
+     * known. */
+    fn rendered(screen: &mut Screen) -> String {
+        let mut out = editorcontents::EditorContents::keeping();
+        screen.render(&mut out);
+        out.flush().unwrap();
+        out.kept.unwrap()
+    }
+
+    fn screen(rows: &[&str]) -> Screen {
+        let mut screen = Screen::new(10, rows.len());
+        for (y, text) in rows.iter().enumerate() {
+            screen.grid.put_str(0, y, text, theme::Style::default());
+        }
+        rendered(&mut screen);
+        screen
+    }
+
+    #[test]
+    fn the_first_frame_is_drawn_whole_and_blanks_are_cleared() {
+        let mut screen = Screen::new(4, 2);
+        screen.grid.put_str(0, 0, "ab", theme::Style::default());
+        assert_eq!(rendered(&mut screen), "\x1b[1;1H\x1b[0mab\x1b[K\x1b[2;1H\x1b[K");
+    }
+
+    #[test]
+    fn an_unchanged_frame_writes_nothing() {
+        let mut screen = screen(&["abc", "def"]);
+        assert_eq!(rendered(&mut screen), "");
+    }
+
+    #[test]
+    fn only_changed_cells_are_written() {
+        let mut screen = screen(&["abcdefghij", "klmnopqrst"]);
+        screen.grid.put(1, 1, "z", theme::Style::default());
+        assert_eq!(rendered(&mut screen), "\x1b[2;2H\x1b[0mz");
+    }
+
+    #[test]
+    fn short_gaps_are_written_over_and_long_ones_moved_past() {
+        let mut screen = screen(&["abcdefghij"]);
+        screen.grid.put_str(0, 0, "X", theme::Style::default());
+        screen.grid.put_str(3, 0, "Y", theme::Style::default());
+        screen.grid.put_str(9, 0, "Z", theme::Style::default());
+        assert_eq!(rendered(&mut screen), "\x1b[1;1H\x1b[0mXbcY\x1b[1;10HZ");
+    }
+
+    #[test]
+    fn a_wide_grapheme_is_written_once() {
+        let mut screen = screen(&["abcdefghij"]);
+        screen.grid.put_str(0, 0, "\u{4e2d}x", theme::Style::default());
+        assert_eq!(rendered(&mut screen), "\x1b[1;1H\x1b[0m\u{4e2d}x");
+        assert_eq!(screen.grid.put(9, 0, "\u{4e2d}", theme::Style::default()), 2);
+        assert_eq!(rendered(&mut screen), "");
+    }
+
+    #[test]
+    fn styles_are_switched_as_needed_and_reset_after() {
+        let mut screen = screen(&["abcdefghij"]);
+        let red = theme::Style {
+            foreground: Some(Color::Red),
+            ..theme::Style::default()
+        };
+        screen.grid.put_str(0, 0, "ab", red);
+        screen.grid.put_str(2, 0, "C", theme::Style::default());
+        assert_eq!(rendered(&mut screen), "\x1b[1;1H\x1b[0;38;5;9mab\x1b[39mC");
+    }
+
+    #[test]
+    fn a_resize_draws_the_next_frame_whole() {
+        let mut screen = screen(&["abc"]);
+        screen.resize(3, 1);
+        screen.grid.put_str(0, 0, "abc", theme::Style::default());
+        assert_eq!(rendered(&mut screen), "\x1b[1;1H\x1b[0mabc");
+    }
+}
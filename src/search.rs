@@ -0,0 +1,316 @@
+use std::cmp;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::editorrows;
+use crate::motion;
+use crate::pattern;
+use crate::row;
+use crate::searchdirection;
+
+/* How long a search may take before giving up, so that a pattern with no
+ * match in a huge file does not hang the editor. Counting matches for
+ * `[3/17]` afterwards has to fit in the same time. */
+pub const SEARCH_TIMEOUT: Duration = Duration::from_millis(500);
+
+/* Matches counted for `[3/17]` before giving up, like Vim's `>99`. */
+const MAX_COUNT: usize = 99;
+
+/* Where a pattern matched, as grapheme positions with the end excluded.
+ * A pattern with `\n` in it can end on a later row than it starts. */
+#[derive(Copy, Clone)]
+pub struct Match {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+pub enum Found {
+    At(Match),
+    /* found after going past one end of the buffer to the other */
+    Wrapped(Match),
+    NotFound,
+    TimedOut,
+}
+
+/* A compiled search pattern, and how many rows one match can reach over. */
+#[derive(Clone)]
+pub struct Searcher {
+    pub regex: Regex,
+    pub rows: usize,
+}
+
+impl Searcher {
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<Self, String> {
+        Ok(Self {
+            regex: pattern::compile(pattern, ignore_case)?,
+            rows: pattern::line_span(pattern),
+        })
+    }
+
+    /* Every match that starts on row `y`, from left to right. Matching
+     * happens on the row's own text, not the tab-expanded render, so
+     * positions are ones the cursor can go to. A pattern that can match
+     * line breaks sees the rows after `y` as well. */
+    pub fn row_matches(&self, editor_rows: &editorrows::EditorRows, y: usize) -> Vec<Match> {
+        if self.rows == 1 {
+            let row = row::Row::new(editor_rows.get_row(y), String::new());
+            return self
+                .regex
+                .find_iter(&row.row_content)
+                .map(|found| Match {
+                    start: (y, row.byte_to_grapheme(found.start())),
+                    end: (y, row.byte_to_grapheme(found.end())),
+                })
+                .collect();
+        }
+        let buffer = &editor_rows.buffer;
+        let end_row = cmp::min(y + self.rows, editor_rows.number_of_rows());
+        let start = buffer.line_to_char(y);
+        let text = buffer.slice(start..buffer.line_to_char(end_row)).to_string();
+        let row_bytes = buffer.line(y).len_bytes();
+        let position = |byte: usize| editor_rows.position(start + text[..byte].chars().count());
+        self.regex
+            .find_iter(&text)
+            .take_while(|found| found.start() < row_bytes)
+            .map(|found| Match {
+                start: position(found.start()),
+                end: position(found.end()),
+            })
+            .collect()
+    }
+
+    /* Every match starting on `rows`, in buffer order. */
+    pub fn matches_in(&self, editor_rows: &editorrows::EditorRows, rows: Range<usize>) -> Vec<Match> {
+        rows.flat_map(|y| self.row_matches(editor_rows, y)).collect()
+    }
+
+    /* The first match after `from` going `direction`, not counting one
+     * that starts right at `from`. With `wrap` the search goes on from
+     * the other end of the buffer back round to `from`. Gives up once
+     * `deadline` has passed. */
+    pub fn find(
+        &self,
+        editor_rows: &editorrows::EditorRows,
+        from: (usize, usize),
+        direction: searchdirection::SearchDirection,
+        wrap: bool,
+        deadline: Instant,
+    ) -> Found {
+        let number_of_rows = editor_rows.number_of_rows();
+        if number_of_rows == 0 {
+            return Found::NotFound;
+        }
+        let last_row = cmp::min(from.0, number_of_rows - 1);
+        let forward = direction == searchdirection::SearchDirection::Forward;
+        /* rows in the order they are searched, and whether the search has
+         * wrapped by the time it gets to them */
+        let rows: Box<dyn Iterator<Item = (usize, bool)>> = if forward {
+            Box::new(
+                (from.0..number_of_rows)
+                    .map(|y| (y, false))
+                    .chain((0..=last_row).filter(|_| wrap).map(|y| (y, true))),
+            )
+        } else {
+            Box::new(
+                (0..=last_row)
+                    .rev()
+                    .map(|y| (y, false))
+                    .chain((last_row..number_of_rows).rev().filter(|_| wrap).map(|y| (y, true))),
+            )
+        };
+        for (y, wrapped) in rows {
+            if Instant::now() > deadline {
+                return Found::TimedOut;
+            }
+            let matches = self.row_matches(editor_rows, y);
+            /* before wrapping only matches past `from` count, after it
+             * only the ones up to it */
+            let wanted = |found: &&Match| match (forward, wrapped) {
+                (true, false) => found.start > from,
+                (true, true) => found.start <= from,
+                (false, false) => found.start < from,
+                (false, true) => found.start >= from,
+            };
+            let found = if forward {
+                matches.iter().find(wanted)
+            } else {
+                matches.iter().rev().find(wanted)
+            };
+            match found {
+                Some(found) if wrapped => return Found::Wrapped(*found),
+                Some(found) => return Found::At(*found),
+                None => {}
+            }
+        }
+        Found::NotFound
+    }
+
+    /* Where the match at `at` comes among all matches in the buffer, as
+     * `[3/17]`, with `?` for a place past the counting limit. Counting
+     * stops at `deadline` with what it has so far, as `[?/>5]`, or as
+     * `[?/??]` before it has found any. */
+    pub fn count(&self, editor_rows: &editorrows::EditorRows, at: (usize, usize), deadline: Instant) -> String {
+        let mut total = 0;
+        let mut index = None;
+        for y in 0..editor_rows.number_of_rows() {
+            if Instant::now() > deadline {
+                let index = index.map_or(String::from("?"), |index: usize| index.to_string());
+                return match total {
+                    0 => String::from("[?/??]"),
+                    total => format!("[{}/>{}]", index, total),
+                };
+            }
+            for found in self.row_matches(editor_rows, y) {
+                total += 1;
+                if found.start == at {
+                    index = Some(total);
+                }
+                if total > MAX_COUNT {
+                    let index = index.map_or(String::from("?"), |index| index.to_string());
+                    return format!("[{}/>{}]", index, MAX_COUNT);
+                }
+            }
+        }
+        format!("[{}/{}]", index.map_or(String::from("?"), |index| index.to_string()), total)
+    }
+}
+
+pub fn not_found(pattern: &str, direction: searchdirection::SearchDirection, wrap: bool) -> String {
+    match direction {
+        _ if wrap => format!("E486: Pattern not found: {}", pattern),
+        searchdirection::SearchDirection::Forward => {
+            format!("E385: Search hit BOTTOM without match for: {}", pattern)
+        }
+        searchdirection::SearchDirection::Backward => {
+            format!("E384: Search hit TOP without match for: {}", pattern)
+        }
+    }
+}
+
+pub fn wrapped(direction: searchdirection::SearchDirection) -> &'static str {
+    match direction {
+        searchdirection::SearchDirection::Forward => "search hit BOTTOM, continuing at TOP",
+        searchdirection::SearchDirection::Backward => "search hit TOP, continuing at BOTTOM",
+    }
+}
+
+pub fn timed_out(pattern: &str) -> String {
+    format!("Search timed out: {}", pattern)
+}
+
+/* The keyword under or after the cursor on its row, for `*` and `#`. */
+pub fn word_under(editor_rows: &editorrows::EditorRows, (y, x): (usize, usize)) -> Option<String> {
+    if y >= editor_rows.number_of_rows() {
+        return None;
+    }
+    let row = editor_rows.get_row(y);
+    let graphemes: Vec<&str> = row.graphemes(true).collect();
+    let is_word = |g: &&str| motion::char_class(g, false) == motion::CharClass::Word;
+    let mut start = (x..graphemes.len()).find(|&i| is_word(&graphemes[i]))?;
+    /* from inside a word, back up to its start */
+    if start == x {
+        while start > 0 && is_word(&graphemes[start - 1]) {
+            start -= 1;
+        }
+    }
+    let end = (start..graphemes.len())
+        .find(|&i| !is_word(&graphemes[i]))
+        .unwrap_or(graphemes.len());
+    Some(graphemes[start..end].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(text: &str) -> editorrows::EditorRows {
+        editorrows::EditorRows::from_rope(ropey::Rope::from_str(text))
+    }
+
+    fn later() -> Instant {
+        Instant::now() + SEARCH_TIMEOUT
+    }
+
+    #[test]
+    fn matches_are_at_grapheme_positions() {
+        let searcher = Searcher::new("foo", false).unwrap();
+        let found = searcher.row_matches(&rows("e\u{301}\u{4e2d}foo foo\n"), 0);
+        let found: Vec<_> = found.iter().map(|found| (found.start, found.end)).collect();
+        assert_eq!(found, [((0, 2), (0, 5)), ((0, 6), (0, 9))]);
+    }
+
+    #[test]
+    fn the_count_gives_the_place_among_all_matches() {
+        let editor_rows = rows("a\na a\n");
+        let searcher = Searcher::new("a", false).unwrap();
+        assert_eq!(searcher.count(&editor_rows, (1, 2), later()), "[3/3]");
+        assert_eq!(searcher.count(&editor_rows, (1, 1), later()), "[?/3]");
+    }
+
+    #[test]
+    fn counting_stops_at_the_limit_or_the_deadline() {
+        let editor_rows = rows(&format!("{}\n", "a".repeat(150)));
+        let searcher = Searcher::new("a", false).unwrap();
+        assert_eq!(searcher.count(&editor_rows, (0, 0), later()), "[1/>99]");
+        assert_eq!(searcher.count(&editor_rows, (0, 120), later()), "[?/>99]");
+        let past = Instant::now() - Duration::from_millis(1);
+        assert_eq!(searcher.count(&editor_rows, (0, 0), past), "[?/??]");
+    }
+
+    /* Where searching `text` for `pattern` from `from` ends up. */
+    fn found(text: &str, pattern: &str, from: (usize, usize), forward: bool, wrap: bool) -> String {
+        let direction = if forward {
+            searchdirection::SearchDirection::Forward
+        } else {
+            searchdirection::SearchDirection::Backward
+        };
+        let searcher = Searcher::new(pattern, false).unwrap();
+        match searcher.find(&rows(text), from, direction, wrap, later()) {
+            Found::At(found) => format!("at {:?}", found.start),
+            Found::Wrapped(found) => format!("wrapped {:?}", found.start),
+            Found::NotFound => String::from("not found"),
+            Found::TimedOut => String::from("timed out"),
+        }
+    }
+
+    #[test]
+    fn searches_wrap_round_the_ends_with_wrapscan() {
+        let text = "x a\na\nb\n";
+        assert_eq!(found(text, "a", (1, 0), true, true), "wrapped (0, 2)");
+        assert_eq!(found(text, "a", (1, 0), true, false), "not found");
+        assert_eq!(found(text, "a", (0, 2), false, true), "wrapped (1, 0)");
+        assert_eq!(found(text, "a", (1, 0), false, false), "at (0, 2)");
+        assert_eq!(found(text, "b", (2, 0), true, true), "wrapped (2, 0)");
+    }
+
+    #[test]
+    fn a_match_can_span_rows() {
+        let text = "a\nb\na\nc\n";
+        assert_eq!(found(text, r"a\nc", (0, 0), true, false), "at (2, 0)");
+        assert_eq!(found(text, r"a\nb", (2, 0), false, false), "at (0, 0)");
+        let searcher = Searcher::new(r"a\n\+", false).unwrap();
+        let matches = searcher.matches_in(&rows("a\n\n\nb\n"), 0..4);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].end, (3, 0));
+    }
+
+    #[test]
+    fn a_search_past_its_deadline_times_out() {
+        let searcher = Searcher::new("a", false).unwrap();
+        let past = Instant::now() - Duration::from_millis(1);
+        let forward = searchdirection::SearchDirection::Forward;
+        assert!(matches!(searcher.find(&rows("a\n"), (0, 0), forward, true, past), Found::TimedOut));
+    }
+
+    #[test]
+    fn star_takes_the_keyword_under_or_after_the_cursor() {
+        let editor_rows = rows("  foo_1(bar)\n");
+        assert_eq!(word_under(&editor_rows, (0, 0)).as_deref(), Some("foo_1"));
+        assert_eq!(word_under(&editor_rows, (0, 4)).as_deref(), Some("foo_1"));
+        assert_eq!(word_under(&editor_rows, (0, 7)).as_deref(), Some("bar"));
+        assert_eq!(word_under(&editor_rows, (0, 11)), None);
+    }
+}
@@ -1,4 +1,22 @@
+#[derive(Copy, Clone, PartialEq)]
 pub enum SearchDirection {
     Forward,
     Backward,
-}
\ No newline at end of file
+}
+
+impl SearchDirection {
+    pub fn reverse(self) -> Self {
+        match self {
+            SearchDirection::Forward => SearchDirection::Backward,
+            SearchDirection::Backward => SearchDirection::Forward,
+        }
+    }
+
+    /* The character a search in this direction is typed after. */
+    pub fn to_char(self) -> char {
+        match self {
+            SearchDirection::Forward => '/',
+            SearchDirection::Backward => '?',
+        }
+    }
+}
@@ -1,26 +1,44 @@
+use crate::search;
 use crate::searchdirection;
 
 pub struct SearchIndex {
-    pub x_index     : usize,
-    pub y_index     : usize,
-    pub x_direction : Option<searchdirection::SearchDirection>,
-    pub y_direction : Option<searchdirection::SearchDirection>,
+    /* the last pattern searched for, and which way, for `n` and `N` */
+    pub pattern   : Option<String>,
+    pub direction : searchdirection::SearchDirection,
+    /* the cursor when the search prompt opened, and the match it is at */
+    pub origin    : (usize, usize),
+    pub current   : Option<(usize, usize)>,
+    /* what is highlighted on screen: the pattern being typed, or else
+     * the last one searched for until `:nohlsearch` */
+    pub highlight : Option<search::Searcher>,
+    /* what to say once the prompt closes, when a search wrapped round or
+     * gave up */
+    pub note      : Option<String>,
+}
+
+impl Default for SearchIndex {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SearchIndex {
     pub fn new() -> Self {
         Self {
-            x_index     : 0,
-            y_index     : 0,
-            x_direction : None,
-            y_direction : None,
+            pattern   : None,
+            direction : searchdirection::SearchDirection::Forward,
+            origin    : (0, 0),
+            current   : None,
+            highlight : None,
+            note      : None,
         }
     }
 
-    pub fn reset(&mut self) {
-        self.y_index     = 0;
-        self.x_index     = 0;
-        self.y_direction = None;
-        self.x_direction = None;
+    /* Start a search in `direction` from the cursor at `origin`. */
+    pub fn start(&mut self, direction: searchdirection::SearchDirection, origin: (usize, usize)) {
+        self.direction = direction;
+        self.origin = origin;
+        self.current = None;
+        self.note = None;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,155 @@
+use std::cmp;
+
+use crate::editorrows;
+use crate::motion;
+use crate::row;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum SelectionKind {
+    Charwise,
+    Linewise,
+    Blockwise,
+}
+
+/* A visual selection runs from `anchor` to the cursor, both ends included.
+ * The anchor stays put while motions move the cursor. */
+#[derive(Copy, Clone)]
+pub struct Selection {
+    pub anchor: (usize, usize),
+    pub kind: SelectionKind,
+}
+
+/* Rows `top..=bottom` between display columns `left` (included) and
+ * `right` (excluded), as selected by visual-block mode. */
+#[derive(Copy, Clone)]
+pub struct Block {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Block {
+    /* Graphemes of `row` that the block covers, at least partly. */
+    pub fn span(&self, row: &row::Row) -> Option<(usize, usize)> {
+        let start = row.get_row_content_x(self.left);
+        let end = cmp::min(row.get_row_content_x(self.right - 1) + 1, row.len());
+        (start < end).then_some((start, end))
+    }
+}
+
+impl Selection {
+    pub fn new(anchor: (usize, usize), kind: SelectionKind) -> Self {
+        Self { anchor, kind }
+    }
+
+    /* Both ends in buffer order, kept on existing rows. */
+    fn ends(
+        &self,
+        cursor: (usize, usize),
+        editor_rows: &editorrows::EditorRows,
+    ) -> ((usize, usize), (usize, usize)) {
+        let last_row = editor_rows.number_of_rows().saturating_sub(1);
+        let anchor = (cmp::min(self.anchor.0, last_row), self.anchor.1);
+        let cursor = (cmp::min(cursor.0, last_row), cursor.1);
+        if cursor < anchor { (cursor, anchor) } else { (anchor, cursor) }
+    }
+
+    /* The range an operator on a charwise or linewise selection covers. */
+    pub fn range(
+        &self,
+        cursor: (usize, usize),
+        editor_rows: &editorrows::EditorRows,
+    ) -> motion::MotionRange {
+        let (start, end) = self.ends(cursor, editor_rows);
+        if self.kind != SelectionKind::Charwise {
+            return motion::MotionRange {
+                start: (start.0, 0),
+                end: (end.0, 0),
+                linewise: true,
+            };
+        }
+        let row_len = editor_rows.row_len(end.0);
+        /* selecting past the end of a row takes its line break too, except
+         * on the last row, which always keeps one */
+        let end = if end.1 < row_len {
+            (end.0, end.1 + 1)
+        } else if end.0 + 1 < editor_rows.number_of_rows() {
+            (end.0 + 1, 0)
+        } else {
+            (end.0, row_len)
+        };
+        motion::MotionRange {
+            start: (start.0, cmp::min(start.1, editor_rows.row_len(start.0))),
+            end,
+            linewise: false,
+        }
+    }
+
+    /* The rectangle a blockwise selection covers. Each corner spans the
+     * full width of the grapheme under it. */
+    pub fn block(&self, cursor: (usize, usize), editor_rows: &editorrows::EditorRows) -> Block {
+        let (start, end) = self.ends(cursor, editor_rows);
+        let columns = |(y, x): (usize, usize)| {
+            let row = editor_rows.get_editor_row(cmp::min(y, end.0));
+            let left = row.get_render_x(x);
+            (left, cmp::max(row.get_render_x(x + 1), left + 1))
+        };
+        let (anchor_left, anchor_right) = columns(self.anchor);
+        let (cursor_left, cursor_right) = columns(cursor);
+        Block {
+            top: start.0,
+            bottom: end.0,
+            left: cmp::min(anchor_left, cursor_left),
+            right: cmp::max(anchor_right, cursor_right),
+        }
+    }
+
+    /* Graphemes of row `y` to highlight. An end past the last grapheme
+     * means the line break is selected as well. */
+    pub fn row_span(
+        &self,
+        cursor: (usize, usize),
+        y: usize,
+        row: &row::Row,
+        editor_rows: &editorrows::EditorRows,
+    ) -> Option<(usize, usize)> {
+        let (start, end) = self.ends(cursor, editor_rows);
+        if y < start.0 || y > end.0 {
+            return None;
+        }
+        match self.kind {
+            SelectionKind::Charwise => Some((
+                if y == start.0 { start.1 } else { 0 },
+                if y == end.0 { end.1 + 1 } else { row.len() + 1 },
+            )),
+            SelectionKind::Linewise => Some((0, row.len() + 1)),
+            SelectionKind::Blockwise => self.block(cursor, editor_rows).span(row),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_block_corner_spans_the_whole_grapheme_under_it() {
+        let editor_rows = editorrows::EditorRows::from_rope(ropey::Rope::from_str("a\u{4e2d}b\nxyzw\n"));
+        let selection = Selection::new((0, 1), SelectionKind::Blockwise);
+        let block = selection.block((1, 1), &editor_rows);
+        assert_eq!((block.top, block.bottom, block.left, block.right), (0, 1, 1, 3));
+        assert_eq!(block.span(&editor_rows.get_editor_row(0)), Some((1, 2)));
+        assert_eq!(block.span(&editor_rows.get_editor_row(1)), Some((1, 3)));
+    }
+
+    #[test]
+    fn a_charwise_selection_past_a_row_takes_its_line_break() {
+        let editor_rows = editorrows::EditorRows::from_rope(ropey::Rope::from_str("ab\ncd\n"));
+        let range = Selection::new((0, 2), SelectionKind::Charwise).range((0, 0), &editor_rows);
+        assert_eq!((range.start, range.end), ((0, 0), (1, 0)));
+        /* except on the last row */
+        let range = Selection::new((1, 2), SelectionKind::Charwise).range((1, 0), &editor_rows);
+        assert_eq!((range.start, range.end), ((1, 0), (1, 2)));
+    }
+}
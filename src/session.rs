@@ -0,0 +1,64 @@
+use std::{env, fs};
+use std::path::{Path, PathBuf};
+
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::history;
+use crate::register;
+
+const SESSION_MAGIC: &str = "rezvan-session 1";
+
+/* State that outlives a single run of the editor is kept in
+ * `~/.rezvan-session`, much like Vim's viminfo. */
+pub fn session_file() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".rezvan-session"))
+}
+
+/* Session file layout: a magic line, then one `register <name> <kind>
+ * <byte len>` line per register followed by its raw text. */
+pub fn save(file: &Path, registers: &register::Registers) -> io::Result<()> {
+    let mut writer = BufWriter::new(fs::File::create(file)?);
+    writeln!(writer, "{}", SESSION_MAGIC)?;
+    for (name, register) in registers.iter("") {
+        writeln!(
+            writer,
+            "register {} {} {}",
+            name,
+            register.kind.to_char(),
+            register.text.len()
+        )?;
+        writer.write_all(register.text.as_bytes())?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/* Whatever can be read back; a missing or damaged file gives an empty
+ * session. */
+pub fn load(file: &Path) -> register::Registers {
+    let mut registers = register::Registers::new();
+    if let Ok(data) = fs::read(file) {
+        read_registers(&data, &mut registers);
+    }
+    registers
+}
+
+fn read_registers(data: &[u8], registers: &mut register::Registers) -> Option<()> {
+    let mut rest = data;
+    if history::take_line(&mut rest)? != SESSION_MAGIC {
+        return None;
+    }
+    while let Some(header) = history::take_line(&mut rest) {
+        let mut fields = header.strip_prefix("register ")?.split(' ');
+        let name = fields.next()?.chars().next()?;
+        let kind = register::RegisterKind::from_char(fields.next()?.chars().next()?)?;
+        let len: usize = fields.next()?.parse().ok()?;
+        let text = String::from_utf8(rest.get(..len)?.to_vec()).ok()?;
+        rest = rest.get(len + 1..)?;
+        if register::Registers::is_valid(name) {
+            registers.insert(name, register::Register::new(text, kind));
+        }
+    }
+    Some(())
+}
@@ -25,7 +25,7 @@ impl StatusMessage {
                 self.set_time = None;
                 return None;
             } 
-            return Some(self.message.as_ref().unwrap())
+            Some(self.message.as_ref().unwrap())
         })
     }
 }
\ No newline at end of file
@@ -0,0 +1,173 @@
+use regex::{Captures, Regex};
+
+use crate::ex;
+use crate::options;
+use crate::pattern;
+
+/* `:s/pattern/replacement/flags count` */
+pub struct Substitute {
+    pub regex: Regex,
+    pub pattern: String,
+    pub replacement: String,
+    /* `g`: every match on a row rather than the first */
+    pub global: bool,
+    /* `c`: ask before each replacement */
+    pub confirm: bool,
+    /* rows from the last one of the range, as in `:s/a/b/ 3` */
+    pub count: Option<usize>,
+}
+
+impl Substitute {
+    /* Parse what follows `:s`. Any character but a letter, digit, `\`,
+     * `"` or `|` can stand in for `/`. An empty pattern or a bare `:s`
+     * reuses the pattern and replacement of `last`. Case is ignored as
+     * `options` say unless the `i` or `I` flag says otherwise. */
+    pub fn parse(
+        args: &str,
+        last: Option<(&str, &str)>,
+        options: &options::Options,
+    ) -> Result<Self, String> {
+        let (pattern, replacement, rest) = match args.chars().next() {
+            Some(delimiter) if !delimiter.is_alphanumeric() && !matches!(delimiter, '\\' | '"' | '|' | ' ') => {
+                let (pattern, rest) = ex::split_pattern(&args[delimiter.len_utf8()..], delimiter);
+                let (replacement, rest) = split_replacement(rest, delimiter);
+                (pattern, replacement, rest)
+            }
+            _ => {
+                let (pattern, replacement) =
+                    last.ok_or_else(|| String::from("E35: No previous regular expression"))?;
+                (pattern.to_string(), replacement.to_string(), args)
+            }
+        };
+        let pattern = match (pattern.is_empty(), last) {
+            (false, _) => pattern,
+            (true, Some((last, _))) => last.to_string(),
+            (true, None) => return Err(String::from("E35: No previous regular expression")),
+        };
+
+        let rest = rest.trim_start();
+        let flags_end = rest.find(|ch: char| !ch.is_ascii_alphabetic() && ch != '&').unwrap_or(rest.len());
+        let (mut global, mut confirm) = (false, false);
+        let mut ignore_case = options.ignore_case_for(&pattern);
+        for flag in rest[..flags_end].chars() {
+            match flag {
+                'g' => global = !global,
+                'c' => confirm = true,
+                'i' => ignore_case = true,
+                'I' => ignore_case = false,
+                '&' => {}
+                _ => return Err(format!("E488: Trailing characters: {}", rest)),
+            }
+        }
+        let rest = rest[flags_end..].trim();
+        let count = match rest {
+            "" => None,
+            count => match count.parse::<usize>() {
+                Ok(count) if count > 0 => Some(count),
+                Ok(_) => return Err(String::from("E939: Positive count required")),
+                Err(_) => return Err(format!("E488: Trailing characters: {}", count)),
+            },
+        };
+        Ok(Self {
+            regex: pattern::compile(&pattern, ignore_case)?,
+            pattern,
+            replacement,
+            global,
+            confirm,
+            count,
+        })
+    }
+
+    /* The replacement for one match: `&` and `\0` are the whole match,
+     * `\1`-`\9` its groups, `\r` breaks the row and `\n` is a NUL, as in
+     * Vim. `\u` and `\l` change the case of the next character and `\U`
+     * and `\L` of everything up to `\E` or `\e`. */
+    pub fn expand(&self, captures: &Captures) -> String {
+        let mut expanded = String::new();
+        let mut chars = self.replacement.chars();
+        /* `\U`/`\L` until ended, and `\u`/`\l` for one character */
+        let mut case: Option<bool> = None;
+        let mut once: Option<bool> = None;
+        while let Some(ch) = chars.next() {
+            match ch {
+                '&' => push_case(&mut expanded, &captures[0], &mut once, case),
+                '\\' => match chars.next() {
+                    Some(digit @ '0'..='9') => {
+                        let group = digit.to_digit(10).unwrap() as usize;
+                        let text = captures.get(group).map_or("", |group| group.as_str());
+                        push_case(&mut expanded, text, &mut once, case);
+                    }
+                    Some('r') => expanded.push('\n'),
+                    Some('n') => expanded.push('\0'),
+                    Some('t') => expanded.push('\t'),
+                    Some('u') => once = Some(true),
+                    Some('l') => once = Some(false),
+                    Some('U') => case = Some(true),
+                    Some('L') => case = Some(false),
+                    Some('E' | 'e') => case = None,
+                    Some(other) => push_case(&mut expanded, &other.to_string(), &mut once, case),
+                    None => expanded.push('\\'),
+                },
+                _ => push_case(&mut expanded, &ch.to_string(), &mut once, case),
+            }
+        }
+        expanded
+    }
+}
+
+/* Add `text` to `expanded`, in upper case when `once` or else `case` is
+ * `Some(true)` and lower case when it is `Some(false)`. */
+fn push_case(expanded: &mut String, text: &str, once: &mut Option<bool>, case: Option<bool>) {
+    for ch in text.chars() {
+        match once.take().or(case) {
+            Some(true) => expanded.extend(ch.to_uppercase()),
+            Some(false) => expanded.extend(ch.to_lowercase()),
+            None => expanded.push(ch),
+        }
+    }
+}
+
+/* Like `ex::split_pattern`, but keeps every escape other than the
+ * delimiter's for `expand` to read. */
+fn split_replacement(text: &str, delimiter: char) -> (String, &str) {
+    let mut replacement = String::new();
+    let mut chars = text.char_indices();
+    while let Some((index, ch)) = chars.next() {
+        if ch == delimiter {
+            return (replacement, &text[index + ch.len_utf8()..]);
+        }
+        replacement.push(ch);
+        if ch == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => {
+                    replacement.pop();
+                    replacement.push(next);
+                }
+                Some((_, next)) => replacement.push(next),
+                None => {}
+            }
+        }
+    }
+    (replacement, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(args: &str, text: &str) -> String {
+        let substitute = Substitute::parse(args, None, &options::Options::new()).unwrap();
+        let captures = substitute.regex.captures(text).unwrap();
+        substitute.expand(&captures)
+    }
+
+    #[test]
+    fn backslash_r_breaks_the_row() {
+        assert_eq!(replace(r"/, /,\r/", "a, b"), ",\n");
+    }
+
+    #[test]
+    fn backslash_n_inserts_a_nul() {
+        assert_eq!(replace(r"/, /,\n/", "a, b"), ",\0");
+    }
+}
@@ -1,64 +1,544 @@
-use crossterm::style::*;
-use crossterm::queue;
+use std::cmp;
+use std::ops::Range;
+
+use regex::Regex;
+
+use unicode_segmentation::UnicodeSegmentation;
 
 use crate::row;
-use crate::editorcontents;
+use crate::screen;
+use crate::theme;
 
+/* Characters drawn as operators in every language. */
+const OPERATORS: &str = "+-*/%=<>!&|^~?:";
 
+/* What a grapheme of a row's render is, for picking its colour. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum HighlightType {
     Normal,
     Number,
+    Keyword,
+    Type,
+    String,
+    Char,
+    Comment,
+    MultilineComment,
+    Operator,
+    /* told apart only by a parse tree, with the `tree-sitter` feature */
+    Function,
+    Macro,
+    Constant,
+    Attribute,
+    Label,
+    Escape,
+}
+
+/* What a row leaves open at its end for the next one to carry on with. */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum State {
+    #[default]
+    Normal,
+    /* inside a block comment */
+    Comment,
+    /* inside a string that goes on over line breaks, closed by this quote */
+    String(char),
+    /* inside a string closed by this delimiter, like Python's `"""` */
+    LongString(&'static str),
+    /* inside a Rust raw string, closed by `"` and this many `#` */
+    RawString(usize),
+}
+
+/* Rows between the states kept from one redraw to the next. */
+const CHECKPOINT_ROWS: usize = 32;
+
+/* How far above a row colouring it may start, like Vim's `syn sync
+ * minlines`. Further up than that, what the rows above leave open is
+ * guessed to be nothing. */
+pub const SYNC_ROWS: usize = 200;
+
+/* What rows start in, kept between redraws so that a row is coloured
+ * knowing what the rows above it left open. Only every
+ * `CHECKPOINT_ROWS`th row's is kept, so an edit costs no more than
+ * forgetting the ones below it, and the last row coloured is remembered
+ * so that a screen drawn from the top down carries on row by row. */
+pub struct States {
+    /* the state row `k * CHECKPOINT_ROWS` starts in, for as far down as
+     * it has been worked out from the top */
+    checkpoints: Vec<State>,
+    /* the row coloured last, the state it ends in and whether that was
+     * worked out from the top rather than guessed */
+    last: Option<(usize, State, bool)>,
+}
+
+impl Default for States {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl States {
+    pub fn new() -> Self {
+        Self {
+            checkpoints: vec![State::Normal],
+            last: None,
+        }
+    }
+
+    /* Rows from `at` on changed, so what they start in is forgotten. */
+    pub fn edited(&mut self, at: usize) {
+        self.checkpoints.truncate(at / CHECKPOINT_ROWS + 1);
+        if self.last.is_some_and(|(row, _, _)| row >= at) {
+            self.last = None;
+        }
+    }
+
+    /* Where colouring has to start for row `at` to be coloured: a row, the
+     * state it starts in and whether that is known rather than guessed.
+     * It is never more than `SYNC_ROWS` above `at`. */
+    pub fn start(&self, at: usize) -> (usize, State, bool) {
+        let checkpoint = cmp::min(at / CHECKPOINT_ROWS, self.checkpoints.len() - 1);
+        let mut start = (checkpoint * CHECKPOINT_ROWS, self.checkpoints[checkpoint], true);
+        if let Some((row, end, exact)) = self.last.filter(|(row, _, _)| *row < at && *row + 1 > start.0) {
+            start = (row + 1, end, exact);
+        }
+        if at - start.0 > SYNC_ROWS {
+            start = (at - SYNC_ROWS, State::Normal, false);
+        }
+        start
+    }
+
+    /* Row `at` was coloured and ends in `end`, which is known when the
+     * state it started in was. */
+    pub fn store(&mut self, at: usize, end: State, exact: bool) {
+        self.last = Some((at, end, exact));
+        if exact && at + 1 == self.checkpoints.len() * CHECKPOINT_ROWS {
+            self.checkpoints.push(end);
+        }
+    }
 }
 
+/* Separates words, so that keywords and numbers are only found whole. */
+fn is_separator(g: &str) -> bool {
+    g.chars().all(|ch| ch.is_whitespace() || (ch.is_ascii_punctuation() && ch != '_'))
+}
+
+/* A language the editor can colour. Most are described by a handful of
+ * lists through `syntax_struct!` and coloured by the `update_syntax` given
+ * here; a language that reads differently writes its own. */
 pub trait SyntaxHighlight {
-    fn syntax_color(&self, highlight_type: &HighlightType) -> Color;
+    fn file_type(&self) -> &'static str;
+
+    fn extensions(&self) -> &'static [&'static str];
 
-    fn update_syntax(&self, at: usize, editor_rows: &mut Vec<row::Row>);
+    fn line_comment(&self) -> Option<&'static str> {
+        None
+    }
 
-    fn color_row(&self, render: &str, highlight: &[HighlightType], out: &mut editorcontents::EditorContents) {
-        render.chars().enumerate().for_each(|(i, c)| {
-            let _ = queue!(out, SetForegroundColor(self.syntax_color(&highlight[i])));
-            out.push(c);
-            let _ = queue!(out, ResetColor);
-        });
+    fn block_comment(&self) -> Option<(&'static str, &'static str)> {
+        None
+    }
+
+    /* characters that open and close a string */
+    fn quotes(&self) -> &'static [char] {
+        &[]
+    }
+
+    /* quotes whose strings go on over line breaks */
+    fn multiline_quotes(&self) -> &'static [char] {
+        &[]
+    }
+
+    /* delimiters of strings that may span rows, like Python's `"""` */
+    fn long_quotes(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /* whether `r"..."` and `r#"..."#` are raw strings, as in Rust */
+    fn raw_strings(&self) -> bool {
+        false
+    }
+
+    /* whether `'x'` is a character, as in C, rather than a string */
+    fn char_literals(&self) -> bool {
+        false
+    }
+
+    fn keywords(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn types(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /* where given, what a number looks like in place of the usual digits,
+     * suffixes and hex */
+    fn numbers(&self) -> Option<&Regex> {
+        None
+    }
+
+    /* patterns tried before anything else, each matching only at the
+     * start of what it is given, with the colour of what they match */
+    fn rules(&self) -> &[(Regex, HighlightType)] {
+        &[]
+    }
+
+    /* Whether a row can leave something open for the rows below it, so
+     * that colouring it needs the rows above. */
+    fn carries_state(&self) -> bool {
+        self.block_comment().is_some()
+            || !self.multiline_quotes().is_empty()
+            || !self.long_quotes().is_empty()
+            || self.raw_strings()
+    }
+
+    /* Fill `row.highlight` with one entry for each grapheme of its render,
+     * starting in the state `start` the row above left open, and set
+     * `row.state` to what this row leaves open. */
+    fn update_syntax(&self, row: &mut row::Row, start: State) {
+        let render = &row.render;
+        let graphemes: Vec<(usize, &str)> = render.grapheme_indices(true).collect();
+        let highlight = &mut row.highlight;
+        highlight.clear();
+        highlight.reserve(graphemes.len());
+
+        let is_quote = |quotes: &[char], g: &str| quotes.iter().any(|quote| g.len() == quote.len_utf8() && g.starts_with(*quote));
+        let mut previous_separator = true;
+        let mut state = start;
+        let mut i = 0;
+        while i < graphemes.len() {
+            let (index, g) = graphemes[i];
+            let rest = &render[index..];
+            let previous = highlight.last().copied().unwrap_or(HighlightType::Normal);
+            /* how many graphemes `text` at the start of `rest` takes */
+            let span = |text: &str| text.graphemes(true).count();
+
+            /* carry on with whatever is open until it closes */
+            if state != State::Normal {
+                /* how many graphemes close it here, if it closes */
+                let closing = match state {
+                    State::Normal => None,
+                    State::Comment => self.block_comment().filter(|(_, end)| rest.starts_with(end)).map(|(_, end)| span(end)),
+                    State::String(quote) => is_quote(&[quote], g).then_some(1),
+                    State::LongString(end) => rest.starts_with(end).then(|| span(end)),
+                    State::RawString(hashes) => (g == "\"" && rest[1..].bytes().take_while(|byte| *byte == b'#').count() >= hashes)
+                        .then_some(1 + hashes),
+                };
+                /* an escaped character never ends a string */
+                let escaped = g == "\\" && matches!(state, State::String(_) | State::LongString(_)) && i + 1 < graphemes.len();
+                let length = match closing {
+                    Some(length) => length,
+                    None if escaped => 2,
+                    None => 1,
+                };
+                let highlight_type = if state == State::Comment { HighlightType::MultilineComment } else { HighlightType::String };
+                highlight.extend((0..length).map(|_| highlight_type));
+                i += length;
+                if closing.is_some() {
+                    state = State::Normal;
+                    previous_separator = true;
+                }
+                continue;
+            }
+
+            if self.line_comment().is_some_and(|start| rest.starts_with(start)) {
+                highlight.resize(graphemes.len(), HighlightType::Comment);
+                break;
+            }
+
+            /* whatever opens here, and how many graphemes its opening takes */
+            let opening = if let Some((start, _)) = self.block_comment().filter(|(start, _)| rest.starts_with(start)) {
+                Some((State::Comment, span(start), HighlightType::MultilineComment))
+            } else if let Some(quote) = self.long_quotes().iter().find(|quote| rest.starts_with(*quote)) {
+                Some((State::LongString(quote), span(quote), HighlightType::String))
+            } else if self.raw_strings() && previous_separator && g == "r" {
+                /* `r"`, `r#"`, `r##"` and so on */
+                let hashes = rest[1..].bytes().take_while(|byte| *byte == b'#').count();
+                rest[1 + hashes..]
+                    .starts_with('"')
+                    .then_some((State::RawString(hashes), 2 + hashes, HighlightType::String))
+            } else if is_quote(self.quotes(), g) || is_quote(self.multiline_quotes(), g) {
+                g.chars().next().map(|quote| (State::String(quote), 1, HighlightType::String))
+            } else {
+                None
+            };
+            if let Some((opened, length, highlight_type)) = opening {
+                highlight.extend((0..length).map(|_| highlight_type));
+                i += length;
+                state = opened;
+                continue;
+            }
+
+            let rule = self
+                .rules()
+                .iter()
+                .find_map(|(regex, highlight_type)| regex.find(rest).filter(|found| found.end() > 0).map(|found| (found, *highlight_type)));
+            if let Some((found, highlight_type)) = rule {
+                let matched = &rest[..found.end()];
+                highlight.extend((0..span(matched)).map(|_| highlight_type));
+                i += span(matched);
+                previous_separator = matched.graphemes(true).next_back().is_some_and(is_separator);
+                continue;
+            }
+
+            if self.char_literals() && g == "'" {
+                /* `'a'` or `'\n'`; anything else, like a Rust lifetime, is
+                 * left alone */
+                let length = match graphemes.get(i + 1) {
+                    Some((_, "\\")) => graphemes[i + 2..]
+                        .iter()
+                        .take(10)
+                        .position(|(_, g)| *g == "'")
+                        .map(|position| position + 3),
+                    Some(_) if graphemes.get(i + 2).is_some_and(|(_, g)| *g == "'") => Some(3),
+                    _ => None,
+                };
+                if let Some(length) = length {
+                    highlight.extend((0..length).map(|_| HighlightType::Char));
+                    i += length;
+                    previous_separator = true;
+                    continue;
+                }
+            }
+
+            if let Some(numbers) = self.numbers() {
+                let number = numbers
+                    .find(rest)
+                    .filter(|found| previous_separator && found.end() > 0)
+                    .map(|found| &rest[..found.end()])
+                    .filter(|number| rest[number.len()..].graphemes(true).next().is_none_or(is_separator));
+                if let Some(number) = number {
+                    highlight.extend((0..span(number)).map(|_| HighlightType::Number));
+                    i += span(number);
+                    previous_separator = false;
+                    continue;
+                }
+            }
+
+            /* a number starts with a digit and runs on through suffixes,
+             * hex digits and a decimal point followed by a digit */
+            let starts_number = g.chars().all(|ch| ch.is_ascii_digit()) && previous_separator && self.numbers().is_none();
+            let continues_number = previous == HighlightType::Number
+                && (g.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+                    || (g == "." && graphemes.get(i + 1).is_some_and(|(_, g)| g.chars().all(|ch| ch.is_ascii_digit()))));
+            if starts_number || continues_number {
+                highlight.push(HighlightType::Number);
+                i += 1;
+                previous_separator = false;
+                continue;
+            }
+
+            if previous_separator {
+                let word = [(self.keywords(), HighlightType::Keyword), (self.types(), HighlightType::Type)]
+                    .into_iter()
+                    .flat_map(|(words, highlight_type)| words.iter().map(move |word| (*word, highlight_type)))
+                    .find(|(word, _)| {
+                        rest.starts_with(word)
+                            && rest[word.len()..].graphemes(true).next().is_none_or(is_separator)
+                    });
+                if let Some((word, highlight_type)) = word {
+                    highlight.extend((0..span(word)).map(|_| highlight_type));
+                    i += span(word);
+                    previous_separator = false;
+                    continue;
+                }
+            }
+
+            highlight.push(if g.len() == 1 && OPERATORS.contains(g) {
+                HighlightType::Operator
+            } else {
+                HighlightType::Normal
+            });
+            previous_separator = is_separator(g);
+            i += 1;
+        }
+        /* a string that cannot span rows ends with its row */
+        row.state = match state {
+            State::String(quote) if !self.multiline_quotes().contains(&quote) => State::Normal,
+            state => state,
+        };
+    }
+
+    /* Draw the part of `row` between display columns `columns` on row `y`
+     * of `grid`, each grapheme in its style from `theme` with the style
+     * `overlay` gives its column on top. */
+    fn color_row(
+        &self,
+        row: &row::Row,
+        columns: Range<usize>,
+        theme: &theme::Theme,
+        overlay: &dyn Fn(usize) -> theme::Style,
+        grid: &mut screen::Grid,
+        y: usize,
+    ) {
+        let normal = theme.style(theme::Face::Syntax(HighlightType::Normal));
+        let mut column = 0;
+        for (i, g) in row.render.graphemes(true).enumerate() {
+            let width = row::Row::grapheme_width(g);
+            if column < columns.start {
+                /* a wide grapheme cut by the left edge shows as blanks,
+                 * which the row already is */
+                column += width;
+                continue;
+            }
+            if column + width > columns.end {
+                break;
+            }
+            let highlight_type = row.highlight.get(i).copied().unwrap_or(HighlightType::Normal);
+            let style = overlay(column).over(theme.style(theme::Face::Syntax(highlight_type)).over(normal));
+            column += grid.put(column - columns.start, y, g, style);
+        }
     }
 }
 
+/* A language described by its comments, quotes and words:
+ *
+ *     syntax_struct! {
+ *         struct RustHighlight {
+ *             file_type: "rust",
+ *             extensions: ["rs"],
+ *             line_comment: Some("//"),
+ *             block_comment: None,
+ *             quotes: [],
+ *             multiline_quotes: ['"'],
+ *             long_quotes: [],
+ *             raw_strings: true,
+ *             char_literals: true,
+ *             keywords: ["fn", "let"],
+ *             types: ["i32", "String"],
+ *         }
+ *     }
+ */
 #[macro_export]
 macro_rules! syntax_struct {
     (
-        struct $Name:ident;
+        struct $Name:ident {
+            file_type: $file_type:expr,
+            extensions: [$($extension:expr),* $(,)?],
+            line_comment: $line_comment:expr,
+            block_comment: $block_comment:expr,
+            quotes: [$($quote:expr),* $(,)?],
+            multiline_quotes: [$($multiline_quote:expr),* $(,)?],
+            long_quotes: [$($long_quote:expr),* $(,)?],
+            raw_strings: $raw_strings:expr,
+            char_literals: $char_literals:expr,
+            keywords: [$($keyword:expr),* $(,)?],
+            types: [$($type:expr),* $(,)?] $(,)?
+        }
     ) => {
-        struct $Name;
-        
-        impl SyntaxHighlight for $Name {
-
-            fn syntax_color(&self, highlight_type: &HighlightType) -> Color {
-                match highlight_type {
-                    HightlightType::Normal => Color::Reset,
-                    HightlightType::Number => Color::Cyan,
-                }
+        pub struct $Name;
+
+        impl $crate::syntax::SyntaxHighlight for $Name {
+            fn file_type(&self) -> &'static str {
+                $file_type
             }
 
-            fn update_syntax(&self, at: usize, editor_rows: &mut Vec<row::Row>) {
-                let current_row = &mut editor_rows[at];
-                macro_rules! add {
-                    ($h:expr) => {
-                        current_row.highlight.push($h)
-                    };
-                }
+            fn extensions(&self) -> &'static [&'static str] {
+                &[$($extension),*]
+            }
 
-                current_row.highlight = Vec::with_capacity(current_row.render.len());
-                let chars = current_row.render.chars();
-                for c in chars {
-                    if c.is_digit(10) {
-                        add!(HighlightType::Number);
-                    } 
-                    add!(HighlightType::Normal)
-                    
-                }
-                assert_eq!(current_row.render.len(), current_row.highlight.len())
+            fn line_comment(&self) -> Option<&'static str> {
+                $line_comment
+            }
+
+            fn block_comment(&self) -> Option<(&'static str, &'static str)> {
+                $block_comment
+            }
+
+            fn quotes(&self) -> &'static [char] {
+                &[$($quote),*]
+            }
+
+            fn multiline_quotes(&self) -> &'static [char] {
+                &[$($multiline_quote),*]
+            }
+
+            fn long_quotes(&self) -> &'static [&'static str] {
+                &[$($long_quote),*]
+            }
+
+            fn raw_strings(&self) -> bool {
+                $raw_strings
+            }
+
+            fn char_literals(&self) -> bool {
+                $char_literals
+            }
+
+            fn keywords(&self) -> &'static [&'static str] {
+                &[$($keyword),*]
+            }
+
+            fn types(&self) -> &'static [&'static str] {
+                &[$($type),*]
             }
         }
     };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editorrows;
+    use crate::languages;
+
+    fn rows(text: &str) -> editorrows::EditorRows {
+        editorrows::EditorRows::from_rope(ropey::Rope::from_str(text))
+    }
+
+    #[test]
+    fn a_comment_opened_above_carries_on() {
+        let mut editor_rows = rows(&format!("/*\n{}*/ x\n", "a\n".repeat(100)));
+        let row = editor_rows.highlighted_row(&languages::RustHighlight, 70);
+        assert_eq!(row.highlight, [HighlightType::MultilineComment]);
+        let row = editor_rows.highlighted_row(&languages::RustHighlight, 101);
+        assert_eq!(row.highlight[..2], [HighlightType::MultilineComment; 2]);
+        assert_eq!(row.highlight[3], HighlightType::Normal);
+    }
+
+    #[test]
+    fn an_edit_forgets_what_rows_below_it_start_in() {
+        let mut editor_rows = rows(&"a\n".repeat(100));
+        editor_rows.highlighted_row(&languages::RustHighlight, 90);
+        editor_rows.insert_text(editor_rows.buffer.line_to_char(40), "/*");
+        let row = editor_rows.highlighted_row(&languages::RustHighlight, 90);
+        assert_eq!(row.highlight, [HighlightType::MultilineComment]);
+        editor_rows.remove_text(editor_rows.buffer.line_to_char(40)..editor_rows.buffer.line_to_char(40) + 2);
+        let row = editor_rows.highlighted_row(&languages::RustHighlight, 90);
+        assert_eq!(row.highlight, [HighlightType::Normal]);
+    }
+
+    #[test]
+    fn colouring_starts_at_most_sync_rows_above() {
+        let mut states = States::new();
+        assert_eq!(states.start(10).0, 0);
+        let (start, state, exact) = states.start(100_000);
+        assert_eq!(start, 100_000 - SYNC_ROWS);
+        assert!(state == State::Normal && !exact);
+        /* rows worked out from the top are kept */
+        for at in 0..CHECKPOINT_ROWS * 3 {
+            states.store(at, State::Comment, true);
+        }
+        let (start, state, exact) = states.start(CHECKPOINT_ROWS * 2 + 1);
+        assert_eq!(start, CHECKPOINT_ROWS * 2);
+        assert!(state == State::Comment && exact);
+        states.edited(CHECKPOINT_ROWS + 5);
+        assert_eq!(states.start(CHECKPOINT_ROWS * 2 + 1).0, CHECKPOINT_ROWS);
+        /* guesses are not */
+        let mut states = States::new();
+        for at in 100_000 - SYNC_ROWS..100_000 {
+            states.store(at, State::Comment, false);
+        }
+        assert_eq!(states.checkpoints.len(), 1);
+    }
+
+    #[test]
+    fn rows_that_carry_nothing_are_coloured_alone() {
+        assert!(!languages::PlainText.carries_state());
+        assert!(languages::RustHighlight.carries_state());
+        assert!(languages::MarkdownHighlight.carries_state());
+        let mut editor_rows = rows(&"a\n".repeat(1000));
+        editor_rows.highlighted_row(&languages::PlainText, 999);
+        assert!(editor_rows.states.last.is_none());
+    }
+}
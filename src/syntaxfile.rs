@@ -0,0 +1,409 @@
+use std::{env, fs};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::syntax::{HighlightType, SyntaxHighlight};
+
+/* Files in the syntax directory that hold definitions. */
+const EXTENSION: &str = "syntax";
+
+/* A language described in a data file rather than in Rust, one directive
+ * to a line:
+ *
+ *     # the deploy scripts
+ *     filetype deploy
+ *     extensions deploy dpl
+ *     line_comment --
+ *     block_comment {- -}
+ *     quotes " '
+ *     multiline_quotes `
+ *     long_quotes """
+ *     raw_strings no
+ *     char_literals no
+ *     keywords stage step when
+ *     types host port
+ *     numbers 0x[0-9a-f]+|[0-9]+
+ *     rule keyword @[a-z]+
+ *
+ * `keywords`, `types` and `rule` may be given more than once. `numbers`
+ * and `rule` take a regular expression in the syntax of the `regex`
+ * crate, matched where a word could start; a `rule` is tried before
+ * anything else at each place and colours what it matches as one of
+ * normal, number, keyword, type, string, char, comment, operator,
+ * function, macro, constant, attribute, label or escape. */
+#[derive(Clone)]
+pub struct Definition {
+    file_type: &'static str,
+    extensions: &'static [&'static str],
+    line_comment: Option<&'static str>,
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    multiline_quotes: &'static [char],
+    long_quotes: &'static [&'static str],
+    raw_strings: bool,
+    char_literals: bool,
+    keywords: &'static [&'static str],
+    types: &'static [&'static str],
+    numbers: Option<Regex>,
+    rules: Vec<(Regex, HighlightType)>,
+}
+
+impl SyntaxHighlight for Definition {
+    fn file_type(&self) -> &'static str {
+        self.file_type
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        self.extensions
+    }
+
+    fn line_comment(&self) -> Option<&'static str> {
+        self.line_comment
+    }
+
+    fn block_comment(&self) -> Option<(&'static str, &'static str)> {
+        self.block_comment
+    }
+
+    fn quotes(&self) -> &'static [char] {
+        self.quotes
+    }
+
+    fn multiline_quotes(&self) -> &'static [char] {
+        self.multiline_quotes
+    }
+
+    fn long_quotes(&self) -> &'static [&'static str] {
+        self.long_quotes
+    }
+
+    fn raw_strings(&self) -> bool {
+        self.raw_strings
+    }
+
+    fn char_literals(&self) -> bool {
+        self.char_literals
+    }
+
+    fn keywords(&self) -> &'static [&'static str] {
+        self.keywords
+    }
+
+    fn types(&self) -> &'static [&'static str] {
+        self.types
+    }
+
+    fn numbers(&self) -> Option<&Regex> {
+        self.numbers.as_ref()
+    }
+
+    fn rules(&self) -> &[(Regex, HighlightType)] {
+        &self.rules
+    }
+}
+
+/* Definitions are read once, on first use, and kept for the whole run,
+ * so their words can be handed out as `'static` like the built-in ones. */
+static LOADED: OnceLock<(Vec<Definition>, Vec<String>)> = OnceLock::new();
+
+/* `$XDG_CONFIG_HOME/rezvan`, or `~/.config/rezvan`. */
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|config| config.join("rezvan"))
+}
+
+/* `$XDG_CONFIG_HOME/rezvan/syntax`, or `~/.config/rezvan/syntax`. */
+pub fn syntax_dir() -> Option<PathBuf> {
+    config_dir().map(|config| config.join("syntax"))
+}
+
+fn loaded() -> &'static (Vec<Definition>, Vec<String>) {
+    LOADED.get_or_init(|| syntax_dir().map_or((Vec::new(), Vec::new()), |dir| load(&dir)))
+}
+
+pub fn definitions() -> &'static [Definition] {
+    &loaded().0
+}
+
+/* What went wrong reading the definitions, for the message bar: the
+ * first problem as `file:line: what`, and how many more there were. */
+pub fn report() -> Option<String> {
+    let errors = &loaded().1;
+    let first = errors.first()?;
+    Some(match errors.len() {
+        1 => first.clone(),
+        count => format!("{} (and {} more)", first, count - 1),
+    })
+}
+
+/* Every `*.syntax` file in `dir`, in name order. A file with mistakes in
+ * it is left out altogether. */
+fn load(dir: &Path) -> (Vec<Definition>, Vec<String>) {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|extension| extension == EXTENSION))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    let mut definitions = Vec::new();
+    let mut errors = Vec::new();
+    for file in files {
+        let parsed = fs::read_to_string(&file)
+            .map_err(|error| vec![(0, error.to_string())])
+            .and_then(|text| parse(&text));
+        match parsed {
+            Ok(definition) => definitions.push(definition),
+            Err(problems) => errors.extend(
+                problems
+                    .into_iter()
+                    .map(|(line, problem)| format!("{}:{}: {}", file.display(), line, problem)),
+            ),
+        }
+    }
+    (definitions, errors)
+}
+
+fn leak(words: Vec<&str>) -> &'static [&'static str] {
+    words.into_iter().map(|word| &*String::from(word).leak()).collect::<Vec<_>>().leak()
+}
+
+fn highlight_type(name: &str) -> Option<HighlightType> {
+    Some(match name {
+        "normal" => HighlightType::Normal,
+        "number" => HighlightType::Number,
+        "keyword" => HighlightType::Keyword,
+        "type" => HighlightType::Type,
+        "string" => HighlightType::String,
+        "char" => HighlightType::Char,
+        "comment" => HighlightType::Comment,
+        "operator" => HighlightType::Operator,
+        "function" => HighlightType::Function,
+        "macro" => HighlightType::Macro,
+        "constant" => HighlightType::Constant,
+        "attribute" => HighlightType::Attribute,
+        "label" => HighlightType::Label,
+        "escape" => HighlightType::Escape,
+        _ => return None,
+    })
+}
+
+/* A regular expression that only matches where it is tried. */
+fn anchored(pattern: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})", pattern)).map_err(|_| format!("invalid regular expression: {}", pattern))
+}
+
+/* Parse a definition, or say what is wrong with it, line by line with
+ * 1-based line numbers. */
+fn parse(text: &str) -> Result<Definition, Vec<(usize, String)>> {
+    let mut errors = Vec::new();
+    let mut file_type = None;
+    let mut extensions = Vec::new();
+    let mut line_comment = None;
+    let mut block_comment = None;
+    let mut quotes = Vec::new();
+    let mut multiline_quotes = Vec::new();
+    let mut long_quotes = Vec::new();
+    let mut raw_strings = false;
+    let mut char_literals = false;
+    let mut keywords = Vec::new();
+    let mut types = Vec::new();
+    let mut numbers = None;
+    let mut rules = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (directive, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+        let words: Vec<&str> = value.split_whitespace().collect();
+        let chars = |words: &[&str]| -> Result<Vec<char>, String> {
+            words
+                .iter()
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(quote), None) => Ok(quote),
+                        _ => Err(format!("a quote is a single character, not {}", word)),
+                    }
+                })
+                .collect()
+        };
+        let yes_no = |value: &str| match value {
+            "yes" => Ok(true),
+            "no" => Ok(false),
+            _ => Err(format!("expected yes or no, not {}", value)),
+        };
+        let result = match directive {
+            _ if value.is_empty() => Err(format!("{} needs a value", directive)),
+            "filetype" if words.len() == 1 => {
+                file_type = Some(value);
+                Ok(())
+            }
+            "filetype" => Err(String::from("a file type is a single word")),
+            "extensions" => {
+                extensions.extend(&words);
+                Ok(())
+            }
+            "line_comment" if words.len() == 1 => {
+                line_comment = Some(value);
+                Ok(())
+            }
+            "block_comment" if words.len() == 2 => {
+                block_comment = Some((words[0], words[1]));
+                Ok(())
+            }
+            "line_comment" => Err(String::from("line_comment takes a single delimiter")),
+            "block_comment" => Err(String::from("block_comment takes a start and an end")),
+            "quotes" => chars(&words).map(|found| quotes.extend(found)),
+            "multiline_quotes" => chars(&words).map(|found| multiline_quotes.extend(found)),
+            "long_quotes" => {
+                long_quotes.extend(&words);
+                Ok(())
+            }
+            "raw_strings" => yes_no(value).map(|yes| raw_strings = yes),
+            "char_literals" => yes_no(value).map(|yes| char_literals = yes),
+            "keywords" => {
+                keywords.extend(&words);
+                Ok(())
+            }
+            "types" => {
+                types.extend(&words);
+                Ok(())
+            }
+            "numbers" => anchored(value).map(|regex| numbers = Some(regex)),
+            "rule" => {
+                let (name, pattern) = value.split_once(char::is_whitespace).unwrap_or((value, ""));
+                match (highlight_type(name), pattern.trim()) {
+                    (None, _) => Err(format!("unknown highlight type {}", name)),
+                    (Some(_), "") => Err(String::from("rule needs a regular expression")),
+                    (Some(highlight_type), pattern) => {
+                        anchored(pattern).map(|regex| rules.push((regex, highlight_type)))
+                    }
+                }
+            }
+            _ => Err(format!("unknown directive {}", directive)),
+        };
+        if let Err(problem) = result {
+            errors.push((index + 1, problem));
+        }
+    }
+
+    let file_type = match file_type {
+        Some(file_type) => file_type,
+        None => {
+            errors.push((1, String::from("no filetype given")));
+            return Err(errors);
+        }
+    };
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Definition {
+        file_type: String::from(file_type).leak(),
+        extensions: leak(extensions),
+        line_comment: line_comment.map(|start| &*String::from(start).leak()),
+        block_comment: block_comment
+            .map(|(start, end)| (&*String::from(start).leak(), &*String::from(end).leak())),
+        quotes: quotes.leak(),
+        multiline_quotes: multiline_quotes.leak(),
+        long_quotes: leak(long_quotes),
+        raw_strings,
+        char_literals,
+        keywords: leak(keywords),
+        types: leak(types),
+        numbers,
+        rules,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editorrows;
+
+    const DEPLOY: &str = "# the deploy scripts
+filetype deploy
+extensions deploy dpl
+line_comment --
+block_comment {- -}
+quotes \" '
+keywords stage step
+keywords when
+types host
+numbers 0x[0-9a-f]+|[0-9]+
+rule keyword @[a-z]+
+";
+
+    #[test]
+    fn a_definition_is_read_directive_by_directive() {
+        let definition = parse(DEPLOY).ok().unwrap();
+        assert_eq!(definition.file_type(), "deploy");
+        assert_eq!(definition.extensions(), ["deploy", "dpl"]);
+        assert_eq!(definition.block_comment(), Some(("{-", "-}")));
+        assert_eq!(definition.quotes(), ['"', '\'']);
+        assert_eq!(definition.keywords(), ["stage", "step", "when"]);
+        assert!(!definition.raw_strings());
+    }
+
+    #[test]
+    fn rules_and_numbers_colour_what_they_match() {
+        let definition = parse(DEPLOY).ok().unwrap();
+        let mut editor_rows = editorrows::EditorRows::from_rope(ropey::Rope::from_str("@up 0x1f -- x\n"));
+        let row = editor_rows.highlighted_row(&definition, 0);
+        assert_eq!(row.highlight[..3], [HighlightType::Keyword; 3]);
+        assert_eq!(row.highlight[4..8], [HighlightType::Number; 4]);
+        assert_eq!(row.highlight[9..], [HighlightType::Comment; 4]);
+    }
+
+    #[test]
+    fn every_mistake_is_reported_with_its_line() {
+        let text = "filetype a b
+quotes ab
+
+raw_strings maybe
+rule shiny x
+rule keyword (
+foo bar
+keywords
+";
+        let errors = parse(text).err().unwrap();
+        assert_eq!(
+            errors,
+            [
+                (1, String::from("a file type is a single word")),
+                (2, String::from("a quote is a single character, not ab")),
+                (4, String::from("expected yes or no, not maybe")),
+                (5, String::from("unknown highlight type shiny")),
+                (6, String::from("invalid regular expression: (")),
+                (7, String::from("unknown directive foo")),
+                (8, String::from("keywords needs a value")),
+                (1, String::from("no filetype given")),
+            ]
+        );
+    }
+
+    #[test]
+    fn files_with_mistakes_are_left_out() {
+        let dir = env::temp_dir().join(format!("rezvan-syntax-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.syntax"), "filetype b\n").unwrap();
+        fs::write(dir.join("a.syntax"), "filetype a\nextensions\n").unwrap();
+        fs::write(dir.join("c.txt"), "not a definition\n").unwrap();
+        let (definitions, errors) = load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].file_type(), "b");
+        assert_eq!(errors, [format!("{}:2: extensions needs a value", dir.join("a.syntax").display())]);
+    }
+}
@@ -0,0 +1,519 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::editorrows;
+use crate::motion;
+
+/* Rows of context searched on either side of the cursor for tags. */
+const TAG_SEARCH_ROWS: usize = 500;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TextObject {
+    /* the flag selects WORDs over words */
+    Word(bool),
+    Sentence,
+    Paragraph,
+    Quote(char),
+    Bracket(char, char),
+    Tag,
+}
+
+impl TextObject {
+    pub fn from_char(ch: char) -> Option<Self> {
+        Some(match ch {
+            'w' => TextObject::Word(false),
+            'W' => TextObject::Word(true),
+            's' => TextObject::Sentence,
+            'p' => TextObject::Paragraph,
+            '"' | '\'' | '`' => TextObject::Quote(ch),
+            '(' | ')' | 'b' => TextObject::Bracket('(', ')'),
+            '[' | ']' => TextObject::Bracket('[', ']'),
+            '{' | '}' | 'B' => TextObject::Bracket('{', '}'),
+            '<' | '>' => TextObject::Bracket('<', '>'),
+            't' => TextObject::Tag,
+            _ => return None,
+        })
+    }
+
+    /* The range `i{object}` (`inner`) or `a{object}` selects around `from`. */
+    pub fn range(
+        &self,
+        editor_rows: &editorrows::EditorRows,
+        from: (usize, usize),
+        inner: bool,
+        count: Option<usize>,
+    ) -> Option<motion::MotionRange> {
+        if from.0 >= editor_rows.number_of_rows() {
+            return None;
+        }
+        /* a cursor parked past the end of a row acts on its last grapheme */
+        let row_len = editor_rows.row_len(from.0);
+        let from = (from.0, std::cmp::min(from.1, row_len.saturating_sub(1)));
+        let count = count.unwrap_or(1);
+        match *self {
+            TextObject::Word(bigword) => word(editor_rows, from, inner, bigword, count),
+            TextObject::Sentence => sentence(editor_rows, from, inner),
+            TextObject::Paragraph => paragraph(editor_rows, from, inner, count),
+            TextObject::Quote(quote) => quoted(editor_rows, from, inner, quote),
+            TextObject::Bracket(open, close) => bracket(editor_rows, from, inner, open, close, count),
+            TextObject::Tag => tag(editor_rows, from, inner, count),
+        }
+    }
+}
+
+fn charwise(
+    editor_rows: &editorrows::EditorRows,
+    start: usize,
+    end: usize,
+) -> Option<motion::MotionRange> {
+    (start < end).then(|| motion::MotionRange {
+        start: editor_rows.position(start),
+        end: editor_rows.position(end),
+        linewise: false,
+    })
+}
+
+/* `iw` is a run of one class (blanks included), `aw` adds the blanks after
+ * the word, or before it when there are none after. */
+fn word(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    inner: bool,
+    bigword: bool,
+    count: usize,
+) -> Option<motion::MotionRange> {
+    let content = editor_rows.get_row(from.0);
+    let classes: Vec<motion::CharClass> = content
+        .graphemes(true)
+        .map(|g| motion::char_class(g, bigword))
+        .collect();
+    let x = from.1;
+    if x >= classes.len() {
+        return None;
+    }
+    let run_end = |start: usize| {
+        let mut end = start;
+        while end < classes.len() && classes[end] == classes[start] {
+            end += 1;
+        }
+        end
+    };
+    let mut start = x;
+    while start > 0 && classes[start - 1] == classes[x] {
+        start -= 1;
+    }
+    let mut end = run_end(start);
+    let on_blank = classes[x] == motion::CharClass::Blank;
+
+    if inner {
+        for _ in 1..count {
+            if end >= classes.len() {
+                break;
+            }
+            end = run_end(end);
+        }
+    } else {
+        let mut trailing = false;
+        for i in 0..count {
+            if end >= classes.len() {
+                break;
+            }
+            if on_blank && i == 0 {
+                /* `aw` on blanks takes the blanks and the word after them */
+                end = run_end(end);
+            } else if classes[end] == motion::CharClass::Blank {
+                end = run_end(end);
+                trailing = true;
+            } else {
+                end = run_end(end);
+                if end < classes.len() && classes[end] == motion::CharClass::Blank {
+                    end = run_end(end);
+                    trailing = true;
+                }
+            }
+        }
+        if !on_blank && !trailing {
+            while start > 0 && classes[start - 1] == motion::CharClass::Blank {
+                start -= 1;
+            }
+        }
+    }
+    Some(motion::MotionRange {
+        start: (from.0, start),
+        end: (from.0, end),
+        linewise: false,
+    })
+}
+
+/* `is` runs from the start of the sentence under the cursor to its last
+ * non-blank, `as` on to the start of the next one. */
+fn sentence(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    inner: bool,
+) -> Option<motion::MotionRange> {
+    let mut first_row = from.0;
+    while first_row > 0 && !motion::is_blank_row(editor_rows, first_row - 1) {
+        first_row -= 1;
+    }
+    let mut start = None;
+    let mut next = None;
+    motion::for_each_sentence_start(editor_rows, first_row, |position| {
+        if position <= from {
+            start = Some(position);
+            true
+        } else {
+            next = Some(position);
+            false
+        }
+    });
+    let start = editor_rows.char_index(start?.0, start?.1);
+    let mut end = match next {
+        Some((y, x)) => editor_rows.char_index(y, x),
+        None => editor_rows.buffer.len_chars().saturating_sub(1),
+    };
+    if inner || next.is_none() {
+        while end > start && editor_rows.buffer.char(end - 1).is_whitespace() {
+            end -= 1;
+        }
+    }
+    charwise(editor_rows, start, end)
+}
+
+/* `ip` is a run of rows that are all blank or all non-blank, `ap` adds the
+ * blank rows after the paragraph (or before it at the end of the buffer). */
+fn paragraph(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    inner: bool,
+    count: usize,
+) -> Option<motion::MotionRange> {
+    let number_of_rows = editor_rows.number_of_rows();
+    let blank = |y: usize| motion::is_blank_row(editor_rows, y);
+    let run_end = |y: usize| {
+        let mut end = y;
+        while end + 1 < number_of_rows && blank(end + 1) == blank(y) {
+            end += 1;
+        }
+        end
+    };
+    let mut start = from.0;
+    while start > 0 && blank(start - 1) == blank(from.0) {
+        start -= 1;
+    }
+    let mut end = run_end(start);
+    let runs = if inner { count } else { count * 2 };
+    for _ in 1..runs {
+        if end + 1 >= number_of_rows {
+            break;
+        }
+        end = run_end(end + 1);
+    }
+    if !inner && !blank(from.0) && blank(end) == blank(from.0) {
+        /* no blank rows after the last paragraph, take the ones before */
+        while start > 0 && blank(start - 1) {
+            start -= 1;
+        }
+    }
+    Some(motion::MotionRange {
+        start: (start, 0),
+        end: (end, 0),
+        linewise: true,
+    })
+}
+
+/* Quotes pair up left to right on the cursor's row, skipping escaped ones.
+ * Like Vim, a cursor before the first pair selects that pair. */
+fn quoted(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    inner: bool,
+    quote: char,
+) -> Option<motion::MotionRange> {
+    let content = editor_rows.get_row(from.0);
+    let graphemes: Vec<&str> = content.graphemes(true).collect();
+    let quote = quote.to_string();
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (x, g) in graphemes.iter().enumerate() {
+        if !escaped && *g == quote {
+            quotes.push(x);
+        }
+        escaped = !escaped && *g == "\\";
+    }
+    let (open, close) = quotes
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| close >= from.1)?;
+
+    let (mut start, mut end) = if inner { (open + 1, close) } else { (open, close + 1) };
+    if !inner {
+        let is_blank = |x: usize| graphemes[x].chars().all(char::is_whitespace);
+        if end < graphemes.len() && is_blank(end) {
+            while end < graphemes.len() && is_blank(end) {
+                end += 1;
+            }
+        } else {
+            while start > 0 && is_blank(start - 1) {
+                start -= 1;
+            }
+        }
+    }
+    (start < end || inner).then_some(motion::MotionRange {
+        start: (from.0, start),
+        end: (from.0, end),
+        linewise: false,
+    })
+}
+
+/* The `count`-th pair of brackets around the cursor. An inner block whose
+ * brackets sit on their own rows is taken linewise, as in Vim. */
+fn bracket(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    inner: bool,
+    open: char,
+    close: char,
+    count: usize,
+) -> Option<motion::MotionRange> {
+    let buffer = &editor_rows.buffer;
+    let cursor = editor_rows.char_index(from.0, from.1);
+    let mut open_at = None;
+
+    /* a cursor on the closing bracket belongs to that pair */
+    let mut search_from = if cursor < buffer.len_chars() && buffer.char(cursor) == close {
+        cursor
+    } else {
+        cursor + 1
+    };
+    for _ in 0..count {
+        let mut depth = 0usize;
+        let mut chars = buffer.chars_at(search_from);
+        let mut at = search_from;
+        open_at = loop {
+            let ch = chars.prev()?;
+            at -= 1;
+            if ch == close {
+                depth += 1;
+            } else if ch == open {
+                if depth == 0 {
+                    break Some(at);
+                }
+                depth -= 1;
+            }
+        };
+        search_from = open_at?;
+    }
+    let open_at = open_at?;
+
+    let mut depth = 0usize;
+    let mut close_at = None;
+    for (offset, ch) in buffer.chars_at(open_at).enumerate() {
+        if ch == open {
+            depth += 1;
+        } else if ch == close {
+            depth -= 1;
+            if depth == 0 {
+                close_at = Some(open_at + offset);
+                break;
+            }
+        }
+    }
+    let close_at = close_at?;
+
+    if !inner {
+        return charwise(editor_rows, open_at, close_at + 1);
+    }
+    let (open_row, _) = editor_rows.position(open_at);
+    let (close_row, close_x) = editor_rows.position(close_at);
+    if buffer.char(open_at + 1) == '\n'
+        && close_row > open_row + 1
+        && close_x <= editor_rows.first_non_blank(close_row)
+    {
+        return Some(motion::MotionRange {
+            start: (open_row + 1, 0),
+            end: (close_row - 1, 0),
+            linewise: true,
+        });
+    }
+    Some(motion::MotionRange {
+        start: editor_rows.position(open_at + 1),
+        end: editor_rows.position(close_at),
+        linewise: false,
+    })
+}
+
+/* An element as found in the text: char offsets of its opening tag's
+ * start and end and of its closing tag's start and end. */
+struct Element {
+    open_start  : usize,
+    open_end    : usize,
+    close_start : usize,
+    close_end   : usize,
+}
+
+/* Pair up tags in `text`. Comments, declarations and self-closing tags are
+ * skipped; a closing tag matches the innermost open tag of the same name. */
+fn elements(text: &[char]) -> Vec<Element> {
+    let mut elements = Vec::new();
+    let mut open: Vec<(String, usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] != '<' {
+            i += 1;
+            continue;
+        }
+        let end = match text[i..].iter().position(|&c| c == '>') {
+            Some(offset) => i + offset + 1,
+            None => break,
+        };
+        let closing = text.get(i + 1) == Some(&'/');
+        let name: String = text[i + if closing { 2 } else { 1 }..end - 1]
+            .iter()
+            .take_while(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+            .collect();
+        if name.is_empty() {
+            /* a stray `<`, a comment or a declaration */
+            i += 1;
+            continue;
+        }
+        if text[end - 2] == '/' {
+            i = end;
+            continue;
+        }
+        if closing {
+            if let Some(depth) = open.iter().rposition(|(open_name, _, _)| *open_name == name) {
+                let (_, open_start, open_end) = open[depth].clone();
+                open.truncate(depth);
+                elements.push(Element {
+                    open_start,
+                    open_end,
+                    close_start: i,
+                    close_end: end,
+                });
+            }
+        } else {
+            open.push((name, i, end));
+        }
+        i = end;
+    }
+    elements
+}
+
+/* `it` is the contents of the `count`-th element around the cursor, `at`
+ * includes its tags. Only rows near the cursor are searched. */
+fn tag(
+    editor_rows: &editorrows::EditorRows,
+    from: (usize, usize),
+    inner: bool,
+    count: usize,
+) -> Option<motion::MotionRange> {
+    let buffer = &editor_rows.buffer;
+    let first_row = from.0.saturating_sub(TAG_SEARCH_ROWS);
+    let last_row = std::cmp::min(from.0 + TAG_SEARCH_ROWS, editor_rows.number_of_rows());
+    let offset = buffer.line_to_char(first_row);
+    let text: Vec<char> = buffer
+        .slice(offset..buffer.line_to_char(last_row))
+        .chars()
+        .collect();
+    let cursor = editor_rows.char_index(from.0, from.1) - offset;
+
+    let mut around: Vec<Element> = elements(&text)
+        .into_iter()
+        .filter(|element| element.open_start <= cursor && cursor < element.close_end)
+        .collect();
+    around.sort_by_key(|element| element.close_end - element.open_start);
+    let element = around.get(count - 1)?;
+    if inner {
+        Some(motion::MotionRange {
+            start: editor_rows.position(offset + element.open_end),
+            end: editor_rows.position(offset + element.close_start),
+            linewise: false,
+        })
+    } else {
+        charwise(editor_rows, offset + element.open_start, offset + element.close_end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    /* The text `{count}{object}` selects around `from`, the object given
+     * as typed after an operator, e.g. "iw" or "a(". */
+    fn selected(text: &str, from: (usize, usize), count: usize, object: &str) -> Option<String> {
+        let editor_rows = editorrows::EditorRows::from_rope(Rope::from_str(text));
+        let mut keys = object.chars();
+        let inner = keys.next() == Some('i');
+        let object = TextObject::from_char(keys.next()?)?;
+        let range = object.range(&editor_rows, from, inner, Some(count))?;
+        let buffer = &editor_rows.buffer;
+        let (start, end) = if range.linewise {
+            (buffer.line_to_char(range.start.0), buffer.line_to_char(range.end.0 + 1))
+        } else {
+            (
+                editor_rows.char_index(range.start.0, range.start.1),
+                editor_rows.char_index(range.end.0, range.end.1),
+            )
+        };
+        Some(buffer.slice(start..end).to_string())
+    }
+
+    #[test]
+    fn words_take_the_blanks_after_or_else_before() {
+        let text = "foo bar  baz\n";
+        assert_eq!(selected(text, (0, 5), 1, "iw").as_deref(), Some("bar"));
+        assert_eq!(selected(text, (0, 5), 1, "aw").as_deref(), Some("bar  "));
+        assert_eq!(selected(text, (0, 5), 2, "iw").as_deref(), Some("bar  "));
+        assert_eq!(selected(text, (0, 10), 1, "aw").as_deref(), Some("  baz"));
+        assert_eq!(selected(text, (0, 7), 1, "aw").as_deref(), Some("  baz"));
+        assert_eq!(selected("a.b c\n", (0, 0), 1, "iW").as_deref(), Some("a.b"));
+    }
+
+    #[test]
+    fn quotes_skip_escaped_ones() {
+        let text = "say \"hi \\\"there\\\"\" now\n";
+        assert_eq!(selected(text, (0, 0), 1, "i\"").as_deref(), Some("hi \\\"there\\\""));
+        assert_eq!(selected(text, (0, 6), 1, "a\"").as_deref(), Some("\"hi \\\"there\\\"\" "));
+        assert_eq!(selected(text, (0, 20), 1, "i\""), None);
+    }
+
+    #[test]
+    fn brackets_nest_by_count() {
+        let text = "f(a, (b), c)\n";
+        assert_eq!(selected(text, (0, 6), 1, "i(").as_deref(), Some("b"));
+        assert_eq!(selected(text, (0, 6), 1, "a)").as_deref(), Some("(b)"));
+        assert_eq!(selected(text, (0, 6), 2, "ib").as_deref(), Some("a, (b), c"));
+        assert_eq!(selected(text, (0, 0), 1, "i("), None);
+    }
+
+    #[test]
+    fn an_inner_block_on_its_own_rows_is_linewise() {
+        let text = "if {\n    x\n}\n";
+        assert_eq!(selected(text, (1, 4), 1, "iB").as_deref(), Some("    x\n"));
+        assert_eq!(selected(text, (1, 4), 1, "aB").as_deref(), Some("{\n    x\n}"));
+    }
+
+    #[test]
+    fn paragraphs_take_the_blank_rows_after_or_else_before() {
+        let text = "a\nb\n\nc\n";
+        assert_eq!(selected(text, (0, 0), 1, "ip").as_deref(), Some("a\nb\n"));
+        assert_eq!(selected(text, (0, 0), 1, "ap").as_deref(), Some("a\nb\n\n"));
+        assert_eq!(selected(text, (3, 0), 1, "ap").as_deref(), Some("\nc\n"));
+    }
+
+    #[test]
+    fn sentences_take_the_blanks_after_them() {
+        let text = "One. Two.  Three\n";
+        assert_eq!(selected(text, (0, 6), 1, "is").as_deref(), Some("Two."));
+        assert_eq!(selected(text, (0, 6), 1, "as").as_deref(), Some("Two.  "));
+    }
+
+    #[test]
+    fn tags_pair_up_by_name() {
+        let text = "<a><b>x</b> y<br/></a>\n";
+        assert_eq!(selected(text, (0, 6), 1, "it").as_deref(), Some("x"));
+        assert_eq!(selected(text, (0, 6), 1, "at").as_deref(), Some("<b>x</b>"));
+        assert_eq!(selected(text, (0, 6), 2, "it").as_deref(), Some("<b>x</b> y<br/>"));
+    }
+}
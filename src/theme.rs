@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::{env, fs};
+use std::path::PathBuf;
+
+use crossterm::style::{Attribute, Attributes, Color};
+
+use crate::syntax::HighlightType;
+use crate::syntaxfile;
+
+/* Files in the theme directory that hold themes. */
+const EXTENSION: &str = "theme";
+
+/* Themes that come with the editor. A theme file of the same name in the
+ * theme directory stands in for one of these. */
+const BUILT_IN: [(&str, &str); 2] = [
+    (
+        "default",
+        "
+        number         fg=cyan
+        keyword        fg=yellow
+        type           fg=green
+        string         fg=magenta
+        char           fg=dark_magenta
+        comment        fg=blue
+        operator       fg=red
+        function       fg=dark_cyan
+        macro          fg=dark_yellow
+        constant       fg=cyan
+        attribute      fg=dark_green
+        label          fg=dark_red
+        escape         fg=dark_magenta
+        status_bar     reverse
+        selection      reverse
+        search         fg=black bg=yellow
+        current_search fg=black bg=magenta
+        ",
+    ),
+    (
+        "gruvbox",
+        "
+        normal         fg=#ebdbb2 bg=#282828
+        number         fg=#d3869b
+        keyword        fg=#fb4934
+        type           fg=#fabd2f
+        string         fg=#b8bb26
+        char           fg=#b8bb26
+        comment        fg=#928374 italic
+        operator       fg=#fe8019
+        function       fg=#8ec07c
+        macro          fg=#8ec07c bold
+        constant       fg=#d3869b
+        attribute      fg=#83a598
+        label          fg=#83a598
+        escape         fg=#fe8019
+        status_bar     fg=#ebdbb2 bg=#504945
+        selection      bg=#504945
+        search         fg=#282828 bg=#fabd2f
+        current_search fg=#282828 bg=#fe8019
+        non_text       fg=#7c6f64
+        ",
+    ),
+];
+
+/* Something on screen a theme gives a style to. */
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Face {
+    Syntax(HighlightType),
+    StatusBar,
+    Selection,
+    /* a search match, and the one at the cursor */
+    Search,
+    CurrentSearch,
+    /* the `~` of rows past the end of the buffer */
+    NonText,
+}
+
+/* Faces by the names theme files give them. Multiline comments are
+ * styled as comments. */
+const FACES: [(&str, Face); 19] = [
+    ("normal", Face::Syntax(HighlightType::Normal)),
+    ("number", Face::Syntax(HighlightType::Number)),
+    ("keyword", Face::Syntax(HighlightType::Keyword)),
+    ("type", Face::Syntax(HighlightType::Type)),
+    ("string", Face::Syntax(HighlightType::String)),
+    ("char", Face::Syntax(HighlightType::Char)),
+    ("comment", Face::Syntax(HighlightType::Comment)),
+    ("operator", Face::Syntax(HighlightType::Operator)),
+    ("function", Face::Syntax(HighlightType::Function)),
+    ("macro", Face::Syntax(HighlightType::Macro)),
+    ("constant", Face::Syntax(HighlightType::Constant)),
+    ("attribute", Face::Syntax(HighlightType::Attribute)),
+    ("label", Face::Syntax(HighlightType::Label)),
+    ("escape", Face::Syntax(HighlightType::Escape)),
+    ("status_bar", Face::StatusBar),
+    ("selection", Face::Selection),
+    ("search", Face::Search),
+    ("current_search", Face::CurrentSearch),
+    ("non_text", Face::NonText),
+];
+
+/* Colours and attributes to draw with. A colour left out is whatever is
+ * underneath, in the end the terminal's own. */
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Style {
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+    pub attributes: Attributes,
+}
+
+impl Style {
+    /* This style drawn on top of `under`. */
+    pub fn over(self, under: Style) -> Style {
+        Style {
+            foreground: self.foreground.or(under.foreground),
+            background: self.background.or(under.background),
+            attributes: under.attributes | self.attributes,
+        }
+    }
+}
+
+/* How many colours the terminal can show. */
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorDepth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+/* The 16 colours as xterm shows them by default. */
+const ANSI_16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::DarkRed, (205, 0, 0)),
+    (Color::DarkGreen, (0, 205, 0)),
+    (Color::DarkYellow, (205, 205, 0)),
+    (Color::DarkBlue, (0, 0, 238)),
+    (Color::DarkMagenta, (205, 0, 205)),
+    (Color::DarkCyan, (0, 205, 205)),
+    (Color::Grey, (229, 229, 229)),
+    (Color::DarkGrey, (127, 127, 127)),
+    (Color::Red, (255, 0, 0)),
+    (Color::Green, (0, 255, 0)),
+    (Color::Yellow, (255, 255, 0)),
+    (Color::Blue, (92, 92, 255)),
+    (Color::Magenta, (255, 0, 255)),
+    (Color::Cyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/* The levels of each channel in the 6x6x6 cube of the 256 colours. */
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let squared = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    /* the eye tells greens apart best and blues worst */
+    3 * squared(r1, r2) + 4 * squared(g1, g2) + 2 * squared(b1, b2)
+}
+
+/* The red, green and blue of colour `index` of the 256. */
+fn ansi_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI_16[index as usize].1,
+        16..=231 => {
+            let cube = index - 16;
+            let level = |value: u8| CUBE_LEVELS[value as usize];
+            (level(cube / 36), level(cube / 6 % 6), level(cube % 6))
+        }
+        _ => {
+            let grey = 8 + 10 * (index - 232);
+            (grey, grey, grey)
+        }
+    }
+}
+
+/* The nearest of the 256 colours, leaving out the first 16, which
+ * terminals each show their own way. */
+fn nearest_256(rgb: (u8, u8, u8)) -> u8 {
+    let nearest_level = |value: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&level| (CUBE_LEVELS[level] as i32 - value as i32).abs())
+            .unwrap_or(0) as u8
+    };
+    let cube = 16 + 36 * nearest_level(rgb.0) + 6 * nearest_level(rgb.1) + nearest_level(rgb.2);
+    let average = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+    let grey = 232 + (average.saturating_sub(3) / 10).min(23) as u8;
+    [cube, grey]
+        .into_iter()
+        .min_by_key(|&index| distance(rgb, ansi_rgb(index)))
+        .unwrap_or(cube)
+}
+
+fn nearest_16(rgb: (u8, u8, u8)) -> Color {
+    ANSI_16
+        .iter()
+        .min_by_key(|(_, other)| distance(rgb, *other))
+        .map_or(Color::Reset, |(color, _)| *color)
+}
+
+impl ColorDepth {
+    /* What the terminal says it can do: `COLORTERM=truecolor` for 24-bit
+     * colour, a `TERM` like `xterm-256color` for 256. */
+    pub fn detect() -> Self {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        if matches!(colorterm.as_str(), "truecolor" | "24bit") || term.ends_with("-direct") {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Ansi256
+        } else {
+            ColorDepth::Ansi16
+        }
+    }
+
+    /* `color`, or the nearest this terminal can show. */
+    pub fn reduce(self, color: Color) -> Color {
+        match (self, color) {
+            (ColorDepth::TrueColor, _) => color,
+            (ColorDepth::Ansi256, Color::Rgb { r, g, b }) => Color::AnsiValue(nearest_256((r, g, b))),
+            (ColorDepth::Ansi16, Color::Rgb { r, g, b }) => nearest_16((r, g, b)),
+            (ColorDepth::Ansi16, Color::AnsiValue(index)) if index < 16 => ANSI_16[index as usize].0,
+            (ColorDepth::Ansi16, Color::AnsiValue(index)) => nearest_16(ansi_rgb(index)),
+            _ => color,
+        }
+    }
+}
+
+/* `$XDG_CONFIG_HOME/rezvan/themes`, or `~/.config/rezvan/themes`. */
+pub fn theme_dir() -> Option<PathBuf> {
+    syntaxfile::config_dir().map(|config| config.join("themes"))
+}
+
+/* Which colours and attributes everything is drawn in. */
+pub struct Theme {
+    pub name: String,
+    /* what the terminal can show, which colours are brought down to */
+    pub depth: ColorDepth,
+    styles: HashMap<Face, Style>,
+}
+
+impl Theme {
+    /* The theme the editor starts with. */
+    pub fn new(depth: ColorDepth) -> Self {
+        Self::load("default", depth).expect("the built-in themes are well formed")
+    }
+
+    /* Theme `name` from the theme directory, or a built-in one. Faces the
+     * theme leaves out look as they do in the default theme. */
+    pub fn load(name: &str, depth: ColorDepth) -> Result<Self, String> {
+        let file = theme_dir()
+            .map(|dir| dir.join(format!("{}.{}", name, EXTENSION)))
+            .filter(|file| file.is_file());
+        match file {
+            Some(file) => {
+                let text = fs::read_to_string(&file).map_err(|error| format!("{}: {}", file.display(), error))?;
+                Self::from_text(name, &file.display().to_string(), &text, depth)
+            }
+            None => BUILT_IN
+                .iter()
+                .find(|(built_in, _)| *built_in == name)
+                .ok_or_else(|| format!("E185: Cannot find color scheme '{}'", name))
+                .and_then(|(built_in, text)| Self::from_text(name, built_in, text, depth)),
+        }
+    }
+
+    /* Built-in theme `name`, whatever the theme directory holds. */
+    pub fn built_in(name: &str, depth: ColorDepth) -> Self {
+        let (_, text) = BUILT_IN
+            .iter()
+            .find(|(built_in, _)| *built_in == name)
+            .expect("there is a built-in theme of that name");
+        Self::from_text(name, name, text, depth).expect("the built-in themes are well formed")
+    }
+
+    /* Theme `name` from `text`, with mistakes in it reported as being in
+     * `source`. */
+    fn from_text(name: &str, source: &str, text: &str, depth: ColorDepth) -> Result<Self, String> {
+        let report = |errors: Vec<(usize, String)>| {
+            let (line, problem) = &errors[0];
+            match errors.len() {
+                1 => format!("{}:{}: {}", source, line, problem),
+                count => format!("{}:{}: {} (and {} more)", source, line, problem, count - 1),
+            }
+        };
+        let mut styles = parse(BUILT_IN[0].1).map_err(report)?;
+        styles.extend(parse(text).map_err(report)?);
+        for style in styles.values_mut() {
+            style.foreground = style.foreground.map(|color| depth.reduce(color));
+            style.background = style.background.map(|color| depth.reduce(color));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            depth,
+            styles,
+        })
+    }
+
+    pub fn style(&self, face: Face) -> Style {
+        let face = match face {
+            Face::Syntax(HighlightType::MultilineComment) => Face::Syntax(HighlightType::Comment),
+            face => face,
+        };
+        self.styles.get(&face).copied().unwrap_or_default()
+    }
+}
+
+/* `#rrggbb`, one of the 256 colours by number, a name like `dark_cyan`,
+ * or `default` for the terminal's own. */
+fn color(text: &str) -> Result<Color, String> {
+    if let Some(hex) = text.strip_prefix('#') {
+        let channel = |at: usize| hex.get(at..at + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok());
+        return match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Color::Rgb { r, g, b }),
+            _ => Err(format!("invalid colour {}", text)),
+        };
+    }
+    if let Ok(index) = text.parse::<u8>() {
+        return Ok(Color::AnsiValue(index));
+    }
+    Ok(match text {
+        "default" => Color::Reset,
+        "black" => Color::Black,
+        "dark_grey" | "dark_gray" => Color::DarkGrey,
+        "red" => Color::Red,
+        "dark_red" => Color::DarkRed,
+        "green" => Color::Green,
+        "dark_green" => Color::DarkGreen,
+        "yellow" => Color::Yellow,
+        "dark_yellow" => Color::DarkYellow,
+        "blue" => Color::Blue,
+        "dark_blue" => Color::DarkBlue,
+        "magenta" => Color::Magenta,
+        "dark_magenta" => Color::DarkMagenta,
+        "cyan" => Color::Cyan,
+        "dark_cyan" => Color::DarkCyan,
+        "white" => Color::White,
+        "grey" | "gray" => Color::Grey,
+        _ => return Err(format!("invalid colour {}", text)),
+    })
+}
+
+/* A theme, one face to a line:
+ *
+ *     # a face, then its colours and attributes
+ *     keyword    fg=#fb4934 bold
+ *     status_bar fg=235 bg=dark_yellow
+ *     selection  reverse
+ *
+ * or what is wrong with it, with 1-based line numbers. */
+fn parse(text: &str) -> Result<HashMap<Face, Style>, Vec<(usize, String)>> {
+    let mut styles = HashMap::new();
+    let mut errors = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let Some((_, face)) = FACES.iter().find(|(face, _)| *face == name) else {
+            errors.push((index + 1, format!("unknown face {}", name)));
+            continue;
+        };
+        let mut style = Style::default();
+        for word in words {
+            let result = match word.split_once('=') {
+                Some(("fg", value)) => color(value).map(|color| style.foreground = Some(color)),
+                Some(("bg", value)) => color(value).map(|color| style.background = Some(color)),
+                Some(_) => Err(format!("unknown setting {}", word)),
+                None => match word {
+                    "bold" => Ok(Attribute::Bold),
+                    "dim" => Ok(Attribute::Dim),
+                    "italic" => Ok(Attribute::Italic),
+                    "underline" => Ok(Attribute::Underlined),
+                    "reverse" => Ok(Attribute::Reverse),
+                    _ => Err(format!("unknown attribute {}", word)),
+                }
+                .map(|attribute| style.attributes.set(attribute)),
+            };
+            if let Err(problem) = result {
+                errors.push((index + 1, problem));
+            }
+        }
+        styles.insert(*face, style);
+    }
+    if errors.is_empty() {
+        Ok(styles)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyword(theme: &Theme) -> Option<Color> {
+        theme.style(Face::Syntax(HighlightType::Keyword)).foreground
+    }
+
+    #[test]
+    fn the_built_in_themes_are_well_formed() {
+        for (name, _) in BUILT_IN {
+            for depth in [ColorDepth::TrueColor, ColorDepth::Ansi256, ColorDepth::Ansi16] {
+                assert_eq!(Theme::built_in(name, depth).name, name);
+            }
+        }
+    }
+
+    #[test]
+    fn faces_left_out_look_as_in_the_default_theme() {
+        let theme = Theme::from_text("t", "t.theme", "keyword fg=#102030 bold\n", ColorDepth::TrueColor).ok().unwrap();
+        assert_eq!(keyword(&theme), Some(Color::Rgb { r: 0x10, g: 0x20, b: 0x30 }));
+        assert!(theme.style(Face::Syntax(HighlightType::Keyword)).attributes.has(Attribute::Bold));
+        assert_eq!(theme.style(Face::Syntax(HighlightType::Number)).foreground, Some(Color::Cyan));
+        assert!(
+            theme.style(Face::Syntax(HighlightType::MultilineComment))
+                == theme.style(Face::Syntax(HighlightType::Comment))
+        );
+    }
+
+    #[test]
+    fn colours_come_down_to_what_the_terminal_shows() {
+        let theme = |depth| keyword(&Theme::built_in("gruvbox", depth));
+        assert_eq!(theme(ColorDepth::TrueColor), Some(Color::Rgb { r: 0xfb, g: 0x49, b: 0x34 }));
+        assert_eq!(theme(ColorDepth::Ansi256), Some(Color::AnsiValue(203)));
+        assert_eq!(theme(ColorDepth::Ansi16), Some(Color::Red));
+        assert_eq!(ColorDepth::Ansi256.reduce(Color::Rgb { r: 128, g: 128, b: 128 }), Color::AnsiValue(244));
+        assert_eq!(ColorDepth::Ansi16.reduce(Color::AnsiValue(9)), Color::Red);
+        assert_eq!(ColorDepth::Ansi16.reduce(Color::AnsiValue(196)), Color::Red);
+        assert_eq!(ColorDepth::Ansi16.reduce(Color::DarkCyan), Color::DarkCyan);
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_their_line() {
+        let text = "keyword fg=#12345\n\nbogus fg=red\nsearch bold under fg=pink\n";
+        let error = Theme::from_text("t", "t.theme", text, ColorDepth::TrueColor).err().unwrap();
+        assert_eq!(error, "t.theme:1: invalid colour #12345 (and 3 more)");
+        let error = Theme::from_text("t", "t.theme", "\nlabel fg\n", ColorDepth::TrueColor).err().unwrap();
+        assert_eq!(error, "t.theme:2: unknown attribute fg");
+        assert_eq!(
+            Theme::load("no-such-theme", ColorDepth::TrueColor).err().unwrap(),
+            "E185: Cannot find color scheme 'no-such-theme'"
+        );
+    }
+
+    #[test]
+    fn a_style_shows_through_where_the_one_on_top_has_nothing() {
+        let under = Style {
+            foreground: Some(Color::Red),
+            background: Some(Color::Blue),
+            attributes: Attribute::Bold.into(),
+        };
+        let over = Style {
+            foreground: Some(Color::Green),
+            background: None,
+            attributes: Attribute::Reverse.into(),
+        };
+        let style = over.over(under);
+        assert_eq!(style.foreground, Some(Color::Green));
+        assert_eq!(style.background, Some(Color::Blue));
+        assert!(style.attributes.has(Attribute::Bold) && style.attributes.has(Attribute::Reverse));
+    }
+}
@@ -0,0 +1,255 @@
+use std::cmp;
+
+use ropey::Rope;
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, Query, QueryCursor, Tree};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::editorrows::TAB_STOP;
+use crate::row;
+use crate::syntax::HighlightType;
+
+/* The grammar and highlight query of a file type, where there is one. */
+fn grammar(name: &str) -> Option<(Language, &'static str)> {
+    Some(match name {
+        "rust" => (tree_sitter_rust::LANGUAGE.into(), tree_sitter_rust::HIGHLIGHTS_QUERY),
+        "c" => (tree_sitter_c::LANGUAGE.into(), tree_sitter_c::HIGHLIGHT_QUERY),
+        "python" => (tree_sitter_python::LANGUAGE.into(), tree_sitter_python::HIGHLIGHTS_QUERY),
+        "go" => (tree_sitter_go::LANGUAGE.into(), tree_sitter_go::HIGHLIGHTS_QUERY),
+        _ => return None,
+    })
+}
+
+/* The colour of a capture like `@function.macro`: its whole name if it
+ * has one, otherwise that of the name with its last part taken off, so
+ * `@function.method` is coloured as `@function`. */
+fn capture_type(name: &str) -> Option<HighlightType> {
+    let mut name = name;
+    loop {
+        let highlight_type = match name {
+            "keyword" | "variable.builtin" => Some(HighlightType::Keyword),
+            "type" | "constructor" => Some(HighlightType::Type),
+            "number" => Some(HighlightType::Number),
+            "string" => Some(HighlightType::String),
+            "escape" => Some(HighlightType::Escape),
+            "comment" => Some(HighlightType::Comment),
+            "operator" => Some(HighlightType::Operator),
+            "function" => Some(HighlightType::Function),
+            "function.macro" => Some(HighlightType::Macro),
+            "constant" => Some(HighlightType::Constant),
+            "attribute" => Some(HighlightType::Attribute),
+            "label" => Some(HighlightType::Label),
+            _ => None,
+        };
+        if highlight_type.is_some() {
+            return highlight_type;
+        }
+        name = &name[..name.rfind('.')?];
+    }
+}
+
+/* Char offset `index` into `buffer` as tree-sitter counts it: in bytes,
+ * and as a row and a byte column. */
+fn point(buffer: &Rope, index: usize) -> (usize, Point) {
+    let byte = buffer.char_to_byte(index);
+    let row = buffer.byte_to_line(byte);
+    (byte, Point::new(row, byte - buffer.line_to_byte(row)))
+}
+
+/* A parse tree of the whole buffer, kept up to date edit by edit, so
+ * that rows are coloured by what their text is rather than what it looks
+ * like: a `<` that opens generics, a macro call, a lifetime. Edits only
+ * mark the tree; it is parsed again, reusing what the edits left alone,
+ * when a row is next coloured. */
+pub struct SyntaxTree {
+    parser: Parser,
+    query: Query,
+    /* the colour of each of the query's captures, if it has one */
+    captures: Vec<Option<HighlightType>>,
+    tree: Option<Tree>,
+    /* whether `tree` has been edited since it was parsed */
+    edited: bool,
+}
+
+impl SyntaxTree {
+    /* A tree for files of type `name`, when there is a grammar for it. */
+    pub fn for_file_type(name: Option<&str>) -> Option<Self> {
+        let (language, highlights) = grammar(name?)?;
+        let mut parser = Parser::new();
+        parser.set_language(&language).ok()?;
+        let query = Query::new(&language, highlights).ok()?;
+        let captures = query.capture_names().iter().map(|name| capture_type(name)).collect();
+        Some(Self {
+            parser,
+            query,
+            captures,
+            tree: None,
+            edited: false,
+        })
+    }
+
+    /* `text` went into `buffer` at char offset `index`. */
+    pub fn inserted(&mut self, buffer: &Rope, index: usize, text: &str) {
+        let (start_byte, start_position) = point(buffer, index);
+        let (new_end_byte, new_end_position) = point(buffer, index + text.chars().count());
+        self.edit(InputEdit {
+            start_byte,
+            old_end_byte: start_byte,
+            new_end_byte,
+            start_position,
+            old_end_position: start_position,
+            new_end_position,
+        });
+    }
+
+    /* Chars `start..end` of `buffer` are about to be removed. */
+    pub fn removing(&mut self, buffer: &Rope, start: usize, end: usize) {
+        let (start_byte, start_position) = point(buffer, start);
+        let (old_end_byte, old_end_position) = point(buffer, end);
+        self.edit(InputEdit {
+            start_byte,
+            old_end_byte,
+            new_end_byte: start_byte,
+            start_position,
+            old_end_position,
+            new_end_position: start_position,
+        });
+    }
+
+    fn edit(&mut self, edit: InputEdit) {
+        if let Some(tree) = &mut self.tree {
+            tree.edit(&edit);
+            self.edited = true;
+        }
+    }
+
+    /* The buffer changed in ways not told edit by edit, like an undo, so
+     * it is parsed again from scratch. */
+    pub fn forget(&mut self) {
+        self.tree = None;
+    }
+
+    fn parse(&mut self, buffer: &Rope) {
+        if self.tree.is_some() && !self.edited {
+            return;
+        }
+        let length = buffer.len_bytes();
+        let mut read = |byte: usize, _: Point| -> &[u8] {
+            if byte >= length {
+                return &[];
+            }
+            let (chunk, chunk_byte, _, _) = buffer.chunk_at_byte(byte);
+            &chunk.as_bytes()[byte - chunk_byte..]
+        };
+        self.tree = self.parser.parse_with(&mut read, self.tree.as_ref());
+        self.edited = false;
+    }
+
+    /* Fill `row.highlight`, for row `at` of `buffer`, from the captures
+     * of the highlight query on it. */
+    pub fn highlight(&mut self, buffer: &Rope, at: usize, row: &mut row::Row) {
+        self.parse(buffer);
+        let start = buffer.line_to_byte(at);
+        let end = start + row.row_content.len();
+        let mut colours = vec![HighlightType::Normal; row.row_content.len()];
+
+        if let Some(tree) = &self.tree {
+            let mut cursor = QueryCursor::new();
+            cursor.set_byte_range(start..cmp::max(end, start + 1));
+            let text = |node: Node| buffer.byte_slice(node.byte_range()).chunks().map(str::as_bytes);
+            let mut found = Vec::new();
+            let mut captures = cursor.captures(&self.query, tree.root_node(), text);
+            while let Some((found_match, index)) = captures.next() {
+                let capture = found_match.captures[*index];
+                if let Some(highlight_type) = self.captures[capture.index as usize] {
+                    found.push((capture.node.byte_range(), found_match.pattern_index, highlight_type));
+                }
+            }
+            /* inner nodes are coloured over the ones holding them, and of
+             * the patterns capturing the same node the first one wins */
+            found.sort_by_key(|(range, pattern, _)| (range.start, cmp::Reverse(range.end), cmp::Reverse(*pattern)));
+            for (range, _, highlight_type) in found {
+                let from = range.start.clamp(start, end) - start;
+                let to = range.end.clamp(start, end) - start;
+                colours[from..to].fill(highlight_type);
+            }
+        }
+
+        /* one colour for each grapheme of the render, a tab taking as
+         * many as the blanks it is drawn as */
+        row.highlight.clear();
+        let mut column = 0;
+        for (index, g) in row.row_content.grapheme_indices(true) {
+            let highlight_type = colours[index];
+            if g == "\t" {
+                let blanks = TAB_STOP - column % TAB_STOP;
+                row.highlight.extend((0..blanks).map(|_| highlight_type));
+                column += blanks;
+            } else {
+                row.highlight.push(highlight_type);
+                column += row::Row::grapheme_width(g);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* The colour of each grapheme of row `at` of `buffer`, as Rust. */
+    fn colours(tree: &mut SyntaxTree, buffer: &Rope, at: usize) -> Vec<HighlightType> {
+        let mut row = row::Row::new(buffer.line(at).to_string().trim_end_matches('\n').into(), String::new());
+        tree.highlight(buffer, at, &mut row);
+        row.highlight
+    }
+
+    #[test]
+    fn captures_are_coloured_by_their_longest_known_name() {
+        assert_eq!(capture_type("function.macro"), Some(HighlightType::Macro));
+        assert_eq!(capture_type("function.method.call"), Some(HighlightType::Function));
+        assert_eq!(capture_type("punctuation.bracket"), None);
+    }
+
+    #[test]
+    fn only_file_types_with_a_grammar_get_a_tree() {
+        assert!(SyntaxTree::for_file_type(Some("rust")).is_some());
+        assert!(SyntaxTree::for_file_type(Some("toml")).is_none());
+        assert!(SyntaxTree::for_file_type(None).is_none());
+    }
+
+    #[test]
+    fn rows_are_coloured_by_what_the_parse_tree_says() {
+        let buffer = Rope::from_str("fn main() {\n    println!(\"x\");\n}\n");
+        let mut tree = SyntaxTree::for_file_type(Some("rust")).unwrap();
+        let row = colours(&mut tree, &buffer, 0);
+        assert_eq!(row[..2], [HighlightType::Keyword; 2]);
+        assert_eq!(row[3..7], [HighlightType::Function; 4]);
+        let row = colours(&mut tree, &buffer, 1);
+        assert_eq!(row[4..12], [HighlightType::Macro; 8]);
+        assert_eq!(row[13..16], [HighlightType::String; 3]);
+    }
+
+    #[test]
+    fn an_edit_is_parsed_again_where_it_was_made() {
+        let mut buffer = Rope::from_str("fn f() {}\n");
+        let mut tree = SyntaxTree::for_file_type(Some("rust")).unwrap();
+        assert_eq!(colours(&mut tree, &buffer, 0)[0], HighlightType::Keyword);
+        buffer.insert(0, "// ");
+        tree.inserted(&buffer, 0, "// ");
+        assert_eq!(colours(&mut tree, &buffer, 0), [HighlightType::Comment; 12]);
+        tree.removing(&buffer, 0, 3);
+        buffer.remove(0..3);
+        assert_eq!(colours(&mut tree, &buffer, 0)[0], HighlightType::Keyword);
+    }
+
+    #[test]
+    fn a_tab_takes_a_colour_for_each_blank_it_is_drawn_as() {
+        let buffer = Rope::from_str("\t// c\n");
+        let mut tree = SyntaxTree::for_file_type(Some("rust")).unwrap();
+        let row = colours(&mut tree, &buffer, 0);
+        assert_eq!(row.len(), TAB_STOP + 4);
+        assert_eq!(row[..TAB_STOP], [HighlightType::Normal; TAB_STOP]);
+        assert_eq!(row[TAB_STOP..], [HighlightType::Comment; 4]);
+    }
+}